@@ -0,0 +1,250 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::Local;
+use hyper::header::{CONTENT_LENGTH, REFERER, USER_AGENT};
+use hyper::{Body, Request, Response};
+use serde_derive::{Deserialize, Serialize};
+
+/// The format of access log lines.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessLogFormat {
+    /// Apache/nginx combined log format.
+    Combined,
+    /// One JSON object per line.
+    Json,
+    /// No access logs.
+    Off,
+}
+
+/// The parts of a request that go into the access log, taken before the request is handled.
+pub struct RequestInfo {
+    remote_addr: Option<SocketAddr>,
+    method: String,
+    uri: String,
+    version: String,
+    referer: String,
+    user_agent: String,
+}
+
+impl RequestInfo {
+    pub fn new(req: &Request<Body>, remote_addr: Option<SocketAddr>) -> RequestInfo {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("")
+                .to_string()
+        };
+        RequestInfo {
+            remote_addr,
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            version: format!("{:?}", req.version()),
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    time: String,
+    remote_addr: Option<String>,
+    user: Option<&'a str>,
+    method: &'a str,
+    uri: &'a str,
+    version: &'a str,
+    status: u16,
+    bytes: Option<u64>,
+    referer: &'a str,
+    user_agent: &'a str,
+    duration_ms: u128,
+}
+
+/// Writes a line per request, to stdout or a file.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    file: Option<Mutex<File>>,
+}
+
+impl AccessLog {
+    pub fn new(format: AccessLogFormat, path: Option<&Path>) -> io::Result<AccessLog> {
+        let file = match path {
+            Some(path) if format != AccessLogFormat::Off => Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            _ => None,
+        };
+        Ok(AccessLog { format, file })
+    }
+
+    pub fn log(
+        &self,
+        info: &RequestInfo,
+        user: Option<&str>,
+        res: &Response<Body>,
+        duration: Duration,
+    ) {
+        let line = match self.format_line(info, user, res, duration) {
+            Some(line) => line,
+            None => return,
+        };
+
+        match &self.file {
+            Some(file) => {
+                if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+                    eprintln!("Could not write access log: {}", e);
+                }
+            }
+            None => println!("{}", line),
+        }
+    }
+
+    /// Format a request's log line, or `None` if logs are off.
+    fn format_line(
+        &self,
+        info: &RequestInfo,
+        user: Option<&str>,
+        res: &Response<Body>,
+        duration: Duration,
+    ) -> Option<String> {
+        let bytes = res
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| l.parse::<u64>().ok());
+        let remote_addr = info.remote_addr.map(|a| a.ip().to_string());
+
+        match self.format {
+            AccessLogFormat::Off => None,
+            AccessLogFormat::Combined => Some(format!(
+                "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+                remote_addr.as_deref().unwrap_or("-"),
+                user.unwrap_or("-"),
+                Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+                info.method,
+                info.uri,
+                info.version,
+                res.status().as_u16(),
+                bytes.map_or("-".to_string(), |b| b.to_string()),
+                info.referer.replace('"', "\\\""),
+                info.user_agent.replace('"', "\\\""),
+            )),
+            AccessLogFormat::Json => {
+                let entry = JsonEntry {
+                    time: Local::now().to_rfc3339(),
+                    remote_addr,
+                    user,
+                    method: &info.method,
+                    uri: &info.uri,
+                    version: &info.version,
+                    status: res.status().as_u16(),
+                    bytes,
+                    referer: &info.referer,
+                    user_agent: &info.user_agent,
+                    duration_ms: duration.as_millis(),
+                };
+                serde_json::to_string(&entry).ok()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> RequestInfo {
+        let req = Request::get("/crates/serde/1.0.0/download")
+            .header(REFERER, "http://example.com/")
+            .header(USER_AGENT, "cargo \"1.60\"")
+            .body(Body::empty())
+            .unwrap();
+        RequestInfo::new(&req, Some(([10, 0, 0, 1], 1234).into()))
+    }
+
+    fn response() -> Response<Body> {
+        Response::builder()
+            .status(404)
+            .header(CONTENT_LENGTH, "9")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn access_log(format: AccessLogFormat) -> AccessLog {
+        AccessLog::new(format, None).unwrap()
+    }
+
+    #[test]
+    fn formats_combined_lines() {
+        let line = access_log(AccessLogFormat::Combined)
+            .format_line(
+                &info(),
+                Some("alice"),
+                &response(),
+                Duration::from_millis(5),
+            )
+            .unwrap();
+        assert!(line.starts_with("10.0.0.1 - alice ["), "{}", line);
+        assert!(
+            line.ends_with(
+                "] \"GET /crates/serde/1.0.0/download HTTP/1.1\" 404 9 \"http://example.com/\" \"cargo \\\"1.60\\\"\""
+            ),
+            "{}",
+            line
+        );
+
+        // Missing values are written as `-`.
+        let res = Response::new(Body::empty());
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        let line = access_log(AccessLogFormat::Combined)
+            .format_line(&RequestInfo::new(&req, None), None, &res, Duration::ZERO)
+            .unwrap();
+        assert!(line.starts_with("- - - ["), "{}", line);
+        assert!(
+            line.ends_with("] \"GET / HTTP/1.1\" 200 - \"\" \"\""),
+            "{}",
+            line
+        );
+    }
+
+    #[test]
+    fn formats_json_lines() {
+        let line = access_log(AccessLogFormat::Json)
+            .format_line(
+                &info(),
+                Some("alice"),
+                &response(),
+                Duration::from_millis(5),
+            )
+            .unwrap();
+        let entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(entry["remote_addr"], "10.0.0.1");
+        assert_eq!(entry["user"], "alice");
+        assert_eq!(entry["method"], "GET");
+        assert_eq!(entry["uri"], "/crates/serde/1.0.0/download");
+        assert_eq!(entry["version"], "HTTP/1.1");
+        assert_eq!(entry["status"], 404);
+        assert_eq!(entry["bytes"], 9);
+        assert_eq!(entry["referer"], "http://example.com/");
+        assert_eq!(entry["user_agent"], "cargo \"1.60\"");
+        assert_eq!(entry["duration_ms"], 5);
+    }
+
+    #[test]
+    fn writes_nothing_when_off() {
+        let line = access_log(AccessLogFormat::Off).format_line(
+            &info(),
+            None,
+            &response(),
+            Duration::ZERO,
+        );
+        assert_eq!(line, None);
+    }
+}
@@ -0,0 +1,535 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use git2::{Commit, ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+use semver::Version;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+
+use crate::crates::index_file_path;
+use crate::safe_path::is_valid_crate_name;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ApiError {
+        Git(err: git2::Error) {
+            from()
+            display("Could not read the crates.io-index: {}", err)
+        }
+        Join(err: tokio::task::JoinError) {
+            from()
+            display("Index task failed: {}", err)
+        }
+    }
+}
+
+/// Default and maximum number of results per page, as on crates.io.
+static DEFAULT_PER_PAGE: usize = 10;
+static MAX_PER_PAGE: usize = 100;
+
+/// One line of a crate's file in the index.
+#[derive(Deserialize, Debug, Clone)]
+struct IndexEntry {
+    name: String,
+    vers: String,
+    deps: Vec<IndexDep>,
+    cksum: String,
+    #[serde(default)]
+    features: BTreeMap<String, Vec<String>>,
+    yanked: bool,
+    #[serde(default)]
+    links: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct IndexDep {
+    name: String,
+    req: String,
+    features: Vec<String>,
+    optional: bool,
+    default_features: bool,
+    target: Option<String>,
+    kind: Option<String>,
+    /// The real crate name, if the dependency is renamed.
+    package: Option<String>,
+}
+
+impl IndexDep {
+    fn crate_name(&self) -> &str {
+        self.package.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// What the crate list and search need to know about a crate.
+#[derive(Debug)]
+struct CrateSummary {
+    name: String,
+    max_version: String,
+    newest_version: String,
+}
+
+/// A crate depending on another, from the dependent's latest version.
+#[derive(Debug)]
+struct ReverseDep {
+    name: String,
+    vers: String,
+    dep: IndexDep,
+}
+
+/// An in-memory summary of one commit of the index, for searches and reverse dependencies.
+///
+/// Everything else is read straight from the index on demand.
+struct CrateIndex {
+    commit: Oid,
+    /// Sorted by lowercase name.
+    crates: Vec<CrateSummary>,
+    /// Keyed by lowercase name of the crate depended on.
+    reverse_deps: HashMap<String, Vec<ReverseDep>>,
+}
+
+/// The read-only parts of the crates.io web API, backed by the mirror's crates.io-index.
+pub struct Api {
+    repo_path: PathBuf,
+    index: Mutex<Option<Arc<CrateIndex>>>,
+    /// Held while the in-memory index is rebuilt, so only one rebuild runs at a time.
+    rebuilding: tokio::sync::Mutex<()>,
+}
+
+impl Api {
+    pub fn new(mirror_path: &Path) -> Api {
+        Api {
+            repo_path: mirror_path.join("crates.io-index"),
+            index: Mutex::new(None),
+            rebuilding: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Build the in-memory index, so the first request doesn't have to wait for it.
+    pub async fn warm_up(&self) {
+        if let Err(e) = self.index().await {
+            eprintln!("Could not build the crates API index: {}", e);
+        }
+    }
+
+    /// Get the in-memory index, rebuilding it if the served index has moved on.
+    ///
+    /// While another request rebuilds it, the previous index is used.
+    async fn index(&self) -> Result<Arc<CrateIndex>, ApiError> {
+        let repo_path = self.repo_path.clone();
+        let commit = tokio::task::spawn_blocking(move || {
+            let repo = Repository::open(&repo_path)?;
+            let commit = served_commit(&repo)?.id();
+            Ok::<_, ApiError>(commit)
+        })
+        .await??;
+
+        let current = self.index.lock().unwrap().clone();
+        let _rebuilding = match current {
+            Some(current) if current.commit == commit => return Ok(current),
+            Some(current) => match self.rebuilding.try_lock() {
+                Ok(rebuilding) => rebuilding,
+                Err(_) => return Ok(current),
+            },
+            None => self.rebuilding.lock().await,
+        };
+
+        // Another request may have finished a rebuild while this one waited.
+        if let Some(current) = &*self.index.lock().unwrap() {
+            if current.commit == commit {
+                return Ok(current.clone());
+            }
+        }
+
+        let repo_path = self.repo_path.clone();
+        let built = tokio::task::spawn_blocking(move || build_index(&repo_path, commit)).await??;
+        let built = Arc::new(built);
+        *self.index.lock().unwrap() = Some(built.clone());
+        Ok(built)
+    }
+
+    /// Answer an API request. `segments` is the path after `/api/v1/crates`.
+    pub async fn handle(&self, segments: &[&str], query: Option<&str>) -> Response<Body> {
+        let query: HashMap<String, String> =
+            url::form_urlencoded::parse(query.unwrap_or("").as_bytes())
+                .into_owned()
+                .collect();
+
+        let result = match segments {
+            [] | [""] => self.search(&query).await,
+            [name, ..] if !is_valid_crate_name(name) => Ok(None),
+            [name] => self.crate_info(name).await,
+            [name, "versions"] => self.versions(name).await,
+            [name, "reverse_dependencies"] => self.reverse_dependencies(name, &query).await,
+            [name, version] => self.version(name, version).await,
+            [name, version, "dependencies"] => self.dependencies(name, version).await,
+            _ => Ok(None),
+        };
+
+        match result {
+            Ok(Some(json)) => json_response(StatusCode::OK, &json),
+            Ok(None) => json_response(
+                StatusCode::NOT_FOUND,
+                &json!({ "errors": [{ "detail": "Not Found" }] }),
+            ),
+            Err(e) => {
+                eprintln!("Crates API request failed: {}", e);
+                json_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &json!({ "errors": [{ "detail": e.to_string() }] }),
+                )
+            }
+        }
+    }
+
+    /// `GET /api/v1/crates?q=...`, as used by `cargo search`.
+    async fn search(&self, query: &HashMap<String, String>) -> Result<Option<Value>, ApiError> {
+        let index = self.index().await?;
+        let q = query
+            .get("q")
+            .map(|q| q.trim().to_lowercase())
+            .unwrap_or_default();
+        let (offset, per_page) = pagination(query);
+
+        // Exact matches first, then prefix matches, then everything else containing the query.
+        let mut matches: Vec<(u8, &CrateSummary)> = index
+            .crates
+            .iter()
+            .filter_map(|c| {
+                let name = c.name.to_lowercase();
+                if name == q {
+                    Some((0, c))
+                } else if name.starts_with(&q) {
+                    Some((1, c))
+                } else if name.contains(&q) {
+                    Some((2, c))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        matches.sort_by_key(|(rank, _)| *rank);
+
+        let crates: Vec<Value> = matches
+            .iter()
+            .skip(offset)
+            .take(per_page)
+            .map(|(_, c)| crate_json(c))
+            .collect();
+        Ok(Some(json!({
+            "crates": crates,
+            "meta": { "total": matches.len() },
+        })))
+    }
+
+    /// `GET /api/v1/crates/<name>`
+    async fn crate_info(&self, name: &str) -> Result<Option<Value>, ApiError> {
+        let index = self.index().await?;
+        let summary = match find_summary(&index, name) {
+            Some(summary) => summary,
+            None => return Ok(None),
+        };
+        let entries = self.entries(&index, name).await?;
+        let versions: Vec<Value> = entries.iter().rev().map(version_json).collect();
+        Ok(Some(json!({
+            "crate": crate_json(summary),
+            "versions": versions,
+        })))
+    }
+
+    /// `GET /api/v1/crates/<name>/versions`
+    async fn versions(&self, name: &str) -> Result<Option<Value>, ApiError> {
+        let index = self.index().await?;
+        if find_summary(&index, name).is_none() {
+            return Ok(None);
+        }
+        let entries = self.entries(&index, name).await?;
+        let versions: Vec<Value> = entries.iter().rev().map(version_json).collect();
+        Ok(Some(json!({ "versions": versions })))
+    }
+
+    /// `GET /api/v1/crates/<name>/<version>`
+    async fn version(&self, name: &str, version: &str) -> Result<Option<Value>, ApiError> {
+        let index = self.index().await?;
+        let entries = self.entries(&index, name).await?;
+        Ok(entries
+            .iter()
+            .find(|e| e.vers == version)
+            .map(|e| json!({ "version": version_json(e) })))
+    }
+
+    /// `GET /api/v1/crates/<name>/<version>/dependencies`
+    async fn dependencies(&self, name: &str, version: &str) -> Result<Option<Value>, ApiError> {
+        let index = self.index().await?;
+        let entries = self.entries(&index, name).await?;
+        Ok(entries.iter().find(|e| e.vers == version).map(|e| {
+            let deps: Vec<Value> = e.deps.iter().map(dependency_json).collect();
+            json!({ "dependencies": deps })
+        }))
+    }
+
+    /// `GET /api/v1/crates/<name>/reverse_dependencies`
+    ///
+    /// Only the latest version of each dependent crate is considered.
+    async fn reverse_dependencies(
+        &self,
+        name: &str,
+        query: &HashMap<String, String>,
+    ) -> Result<Option<Value>, ApiError> {
+        let index = self.index().await?;
+        if find_summary(&index, name).is_none() {
+            return Ok(None);
+        }
+        let (offset, per_page) = pagination(query);
+
+        let reverse_deps = index
+            .reverse_deps
+            .get(&name.to_lowercase())
+            .map(|deps| deps.as_slice())
+            .unwrap_or(&[]);
+        let page_deps: Vec<&ReverseDep> = reverse_deps.iter().skip(offset).take(per_page).collect();
+        Ok(Some(json!({
+            "dependencies": page_deps
+                .iter()
+                .map(|r| dependency_json(&r.dep))
+                .collect::<Vec<_>>(),
+            "versions": page_deps
+                .iter()
+                .map(|r| json!({ "crate": r.name, "num": r.vers }))
+                .collect::<Vec<_>>(),
+            "meta": { "total": reverse_deps.len() },
+        })))
+    }
+
+    /// Read every version of a crate, from the index commit the summary was built from.
+    async fn entries(&self, index: &CrateIndex, name: &str) -> Result<Vec<IndexEntry>, ApiError> {
+        let repo_path = self.repo_path.clone();
+        let commit = index.commit;
+        let name = name.to_string();
+        tokio::task::spawn_blocking(move || read_entries(&repo_path, commit, &name)).await?
+    }
+}
+
+fn read_entries(repo_path: &Path, commit: Oid, name: &str) -> Result<Vec<IndexEntry>, ApiError> {
+    let repo = Repository::open(repo_path)?;
+    let tree = repo.find_commit(commit)?.tree()?;
+    let entry = match tree.get_path(Path::new(&index_file_path(name))) {
+        Ok(entry) => entry,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let blob = entry.to_object(&repo)?.peel_to_blob()?;
+    Ok(parse_entries(blob.content()))
+}
+
+/// The commit the mirror serves: master, or origin/master before the first merge.
+fn served_commit(repo: &Repository) -> Result<Commit<'_>, git2::Error> {
+    match repo.find_reference("refs/heads/master") {
+        Ok(master) => master.peel_to_commit(),
+        Err(_) => repo
+            .find_reference("refs/remotes/origin/master")?
+            .peel_to_commit(),
+    }
+}
+
+fn parse_entries(content: &[u8]) -> Vec<IndexEntry> {
+    content
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| serde_json::from_slice(line).ok())
+        .collect()
+}
+
+fn build_index(repo_path: &Path, commit: Oid) -> Result<CrateIndex, ApiError> {
+    eprintln!("Building the crates API index...");
+    let repo = Repository::open(repo_path)?;
+    let tree = repo.find_commit(commit)?.tree()?;
+
+    let mut crates = vec![];
+    let mut reverse_deps: HashMap<String, Vec<ReverseDep>> = HashMap::new();
+    tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+        let name = entry.name().unwrap_or("");
+        if name.starts_with('.') {
+            return TreeWalkResult::Skip;
+        }
+        // Top-level files, like config.json, aren't crates.
+        if dir.is_empty() || entry.kind() != Some(ObjectType::Blob) {
+            return TreeWalkResult::Ok;
+        }
+        let blob = match entry.to_object(&repo).and_then(|o| o.peel_to_blob()) {
+            Ok(blob) => blob,
+            Err(_) => return TreeWalkResult::Ok,
+        };
+
+        let entries = parse_entries(blob.content());
+        if let Some(summary) = summarize(&entries) {
+            if let Some(latest) = entries.iter().find(|e| e.vers == summary.max_version) {
+                for dep in &latest.deps {
+                    reverse_deps
+                        .entry(dep.crate_name().to_lowercase())
+                        .or_default()
+                        .push(ReverseDep {
+                            name: latest.name.clone(),
+                            vers: latest.vers.clone(),
+                            dep: dep.clone(),
+                        });
+                }
+            }
+            crates.push(summary);
+        }
+        TreeWalkResult::Ok
+    })?;
+
+    crates.sort_by_key(|c| c.name.to_lowercase());
+    for deps in reverse_deps.values_mut() {
+        deps.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+    eprintln!("Crates API index built, with {} crates.", crates.len());
+
+    Ok(CrateIndex {
+        commit,
+        crates,
+        reverse_deps,
+    })
+}
+
+/// Find a crate's highest version, preferring versions that aren't yanked or pre-releases.
+fn summarize(entries: &[IndexEntry]) -> Option<CrateSummary> {
+    let newest = entries.last()?;
+    let versions: Vec<(Version, &IndexEntry)> = entries
+        .iter()
+        .filter_map(|e| Version::parse(&e.vers).ok().map(|v| (v, e)))
+        .collect();
+
+    let max = |filter: &dyn Fn(&(Version, &IndexEntry)) -> bool| {
+        versions
+            .iter()
+            .filter(|v| filter(v))
+            .max_by(|a, b| a.0.cmp(&b.0))
+            .map(|(_, e)| e.vers.clone())
+    };
+    let max_version = max(&|(v, e)| !e.yanked && v.pre.is_empty())
+        .or_else(|| max(&|(_, e)| !e.yanked))
+        .or_else(|| max(&|_| true))
+        .unwrap_or_else(|| newest.vers.clone());
+
+    Some(CrateSummary {
+        name: newest.name.clone(),
+        max_version,
+        newest_version: newest.vers.clone(),
+    })
+}
+
+fn find_summary<'a>(index: &'a CrateIndex, name: &str) -> Option<&'a CrateSummary> {
+    let lowercase = name.to_lowercase();
+    index
+        .crates
+        .binary_search_by(|c| c.name.to_lowercase().cmp(&lowercase))
+        .ok()
+        .map(|i| &index.crates[i])
+}
+
+/// Get how many results to skip, and how many to return, from the `page` and `per_page` query
+/// parameters, as on crates.io.
+fn pagination(query: &HashMap<String, String>) -> (usize, usize) {
+    let page: usize = query
+        .get("page")
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(1)
+        .max(1);
+    let per_page = query
+        .get("per_page")
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .max(1)
+        .min(MAX_PER_PAGE);
+    ((page - 1).saturating_mul(per_page), per_page)
+}
+
+/// The index doesn't know descriptions, download counts or dates, so those are left empty.
+fn crate_json(summary: &CrateSummary) -> Value {
+    json!({
+        "id": summary.name,
+        "name": summary.name,
+        "max_version": summary.max_version,
+        "newest_version": summary.newest_version,
+        "description": null,
+        "downloads": 0,
+    })
+}
+
+fn version_json(entry: &IndexEntry) -> Value {
+    json!({
+        "crate": entry.name,
+        "num": entry.vers,
+        "dl_path": format!("/api/v1/crates/{}/{}/download", entry.name, entry.vers),
+        "checksum": entry.cksum,
+        "yanked": entry.yanked,
+        "features": entry.features,
+        "links": entry.links,
+        "downloads": 0,
+    })
+}
+
+fn dependency_json(dep: &IndexDep) -> Value {
+    json!({
+        "crate_id": dep.crate_name(),
+        "req": dep.req,
+        "optional": dep.optional,
+        "default_features": dep.default_features,
+        "features": dep.features,
+        "target": dep.target,
+        "kind": dep.kind.as_deref().unwrap_or("normal"),
+        "downloads": 0,
+    })
+}
+
+pub fn json_response(status: StatusCode, json: &Value) -> Response<Body> {
+    let mut res = Response::new(Body::from(json.to_string()));
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/json; charset=utf-8"),
+    );
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn paginates() {
+        assert_eq!(pagination(&query(&[])), (0, DEFAULT_PER_PAGE));
+        assert_eq!(
+            pagination(&query(&[("page", "3"), ("per_page", "20")])),
+            (40, 20)
+        );
+        assert_eq!(
+            pagination(&query(&[("page", "0"), ("per_page", "0")])),
+            (0, 1)
+        );
+        assert_eq!(
+            pagination(&query(&[("page", "x"), ("per_page", "1000")])),
+            (0, MAX_PER_PAGE)
+        );
+    }
+
+    #[test]
+    fn paginates_without_overflow() {
+        let page = usize::MAX.to_string();
+        assert_eq!(
+            pagination(&query(&[("page", &page), ("per_page", "100")])),
+            (usize::MAX, 100)
+        );
+    }
+}
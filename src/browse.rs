@@ -0,0 +1,315 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use similar::TextDiff;
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use crate::landing::escape;
+
+/// Files larger than this are listed, but not shown or diffed.
+static MAX_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Crates larger than this when decompressed aren't read, so a small `.crate` can't make the
+/// server decompress gigabytes of zeros.
+static MAX_EXTRACTED_SIZE: u64 = 512 * 1024 * 1024;
+
+/// One file inside a `.crate` tarball.
+pub struct CrateFile {
+    pub size: u64,
+    /// None if the file is too large to show.
+    pub contents: Option<Vec<u8>>,
+}
+
+/// Read every file in a `.crate` tarball, keyed by path inside the crate.
+///
+/// The `<name>-<version>/` directory every crate is packaged in is left out of the paths.
+pub fn read_crate(crate_path: &Path) -> io::Result<BTreeMap<String, CrateFile>> {
+    read_crate_limited(crate_path, MAX_EXTRACTED_SIZE)
+}
+
+fn read_crate_limited(
+    crate_path: &Path,
+    max_extracted_size: u64,
+) -> io::Result<BTreeMap<String, CrateFile>> {
+    let decoder = GzDecoder::new(File::open(crate_path)?);
+    // Counting the decompressed bytes, rather than trusting the sizes in the tar headers,
+    // includes the files that are skipped.
+    let mut archive = tar::Archive::new(LimitedReader {
+        inner: decoder,
+        remaining: max_extracted_size,
+    });
+    let mut files = BTreeMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let path = match path.split_once('/') {
+            Some((_, path)) if !path.is_empty() => path.to_string(),
+            _ => continue,
+        };
+        let size = entry.header().size()?;
+        let contents = if size <= MAX_FILE_SIZE {
+            let mut contents = Vec::with_capacity(size as usize);
+            (&mut entry)
+                .take(MAX_FILE_SIZE)
+                .read_to_end(&mut contents)?;
+            Some(contents)
+        } else {
+            None
+        };
+        files.insert(path, CrateFile { size, contents });
+    }
+    Ok(files)
+}
+
+/// A reader that fails once more than `remaining` bytes have been read from it.
+struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.remaining = self
+            .remaining
+            .checked_sub(n as u64)
+            .ok_or_else(|| io::Error::other("The crate is too large when decompressed"))?;
+        Ok(n)
+    }
+}
+
+/// An HTML listing of a crate's files, linking to each of them.
+pub fn listing_html(name: &str, vers: &str, files: &BTreeMap<String, CrateFile>) -> String {
+    let mut html = String::new();
+    html.push_str(&format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{} {}</title>\n</head>\n<body>\n",
+        escape(name),
+        escape(vers)
+    ));
+    html.push_str(&format!(
+        "<h1>{} {}</h1>\n<p><a href=\"/crates/{}/{}/download\">Download</a></p>\n<ul>\n",
+        escape(name),
+        escape(vers),
+        escape(name),
+        escape(vers)
+    ));
+    for (path, file) in files {
+        html.push_str(&format!(
+            "<li><a href=\"/browse/{}/{}/{}\">{}</a> ({} bytes)</li>\n",
+            escape(name),
+            escape(vers),
+            escape(&percent_encode_path(path)),
+            escape(path),
+            file.size
+        ));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    html
+}
+
+/// A file's contents as plain text, or a note if it can't be shown.
+pub fn file_text(file: &CrateFile) -> String {
+    match &file.contents {
+        Some(contents) => match std::str::from_utf8(contents) {
+            Ok(text) => text.to_string(),
+            Err(_) => format!("Binary file, {} bytes.\n", file.size),
+        },
+        None => format!("File too large to show, {} bytes.\n", file.size),
+    }
+}
+
+/// A unified diff of every file that changed between two versions of a crate.
+pub fn diff_text(
+    old_label: &str,
+    old_files: &BTreeMap<String, CrateFile>,
+    new_label: &str,
+    new_files: &BTreeMap<String, CrateFile>,
+) -> String {
+    let mut out = format!("Changes from {} to {}\n\n", old_label, new_label);
+    let mut paths: Vec<&String> = old_files.keys().chain(new_files.keys()).collect();
+    paths.sort();
+    paths.dedup();
+
+    for path in paths {
+        let old = old_files.get(path);
+        let new = new_files.get(path);
+        let old_header = match old {
+            Some(_) => format!("a/{}", path),
+            None => "/dev/null".to_string(),
+        };
+        let new_header = match new {
+            Some(_) => format!("b/{}", path),
+            None => "/dev/null".to_string(),
+        };
+
+        let old_contents = old.map(|f| f.contents.as_deref());
+        let new_contents = new.map(|f| f.contents.as_deref());
+        // Files too large to read are only compared by size.
+        if old_contents == new_contents && old.map(|f| f.size) == new.map(|f| f.size) {
+            continue;
+        }
+
+        match (diffable_text(old_contents), diffable_text(new_contents)) {
+            (Some(old_text), Some(new_text)) => {
+                out.push_str(
+                    &TextDiff::from_lines(old_text, new_text)
+                        .unified_diff()
+                        .header(&old_header, &new_header)
+                        .to_string(),
+                );
+            }
+            _ => out.push_str(&format!(
+                "Binary or large files {} and {} differ\n",
+                old_header, new_header
+            )),
+        }
+    }
+    out
+}
+
+/// The text to diff for a file: empty if it's missing, None if it's binary or too large.
+fn diffable_text(contents: Option<Option<&[u8]>>) -> Option<&str> {
+    match contents {
+        None => Some(""),
+        Some(Some(contents)) => std::str::from_utf8(contents).ok(),
+        Some(None) => None,
+    }
+}
+
+/// Percent-encode each segment of a path inside a crate, for a link to it.
+fn percent_encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT_ENCODE_SET).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+
+    /// Write a `.crate` tarball with the given files, paths including the crate directory.
+    fn write_crate(path: &Path, files: &[(&str, &[u8])]) {
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            File::create(path).unwrap(),
+            Compression::default(),
+        ));
+        for (file_path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, file_path, *contents)
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    fn file(contents: &[u8]) -> CrateFile {
+        CrateFile {
+            size: contents.len() as u64,
+            contents: Some(contents.to_vec()),
+        }
+    }
+
+    #[test]
+    fn strips_crate_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let crate_path = dir.path().join("foo-1.0.0.crate");
+        write_crate(
+            &crate_path,
+            &[
+                ("foo-1.0.0/Cargo.toml", b"[package]\n"),
+                ("foo-1.0.0/src/lib.rs", b"fn main() {}\n"),
+                ("README", b"outside the crate directory"),
+            ],
+        );
+        let files = read_crate(&crate_path).unwrap();
+        let paths: Vec<&str> = files.keys().map(|p| p.as_str()).collect();
+        assert_eq!(paths, ["Cargo.toml", "src/lib.rs"]);
+        assert_eq!(
+            files["src/lib.rs"].contents.as_deref(),
+            Some(&b"fn main() {}\n"[..])
+        );
+        assert_eq!(files["src/lib.rs"].size, 13);
+    }
+
+    #[test]
+    fn skips_contents_of_large_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let crate_path = dir.path().join("foo-1.0.0.crate");
+        let large = vec![b'a'; MAX_FILE_SIZE as usize + 1];
+        write_crate(
+            &crate_path,
+            &[
+                ("foo-1.0.0/large.txt", &large),
+                ("foo-1.0.0/small.txt", b"small"),
+            ],
+        );
+        let files = read_crate(&crate_path).unwrap();
+        assert_eq!(files["large.txt"].size, MAX_FILE_SIZE + 1);
+        assert!(files["large.txt"].contents.is_none());
+        assert_eq!(files["small.txt"].contents.as_deref(), Some(&b"small"[..]));
+        assert_eq!(
+            file_text(&files["large.txt"]),
+            format!("File too large to show, {} bytes.\n", MAX_FILE_SIZE + 1)
+        );
+    }
+
+    #[test]
+    fn limits_decompressed_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let crate_path = dir.path().join("foo-1.0.0.crate");
+        let large = vec![0; 64 * 1024];
+        write_crate(
+            &crate_path,
+            &[("foo-1.0.0/a", &large), ("foo-1.0.0/b", &large)],
+        );
+        assert!(read_crate_limited(&crate_path, 256 * 1024).is_ok());
+        // Too much in total, even though each file is well under the per-file limit.
+        assert!(read_crate_limited(&crate_path, 100 * 1024).is_err());
+    }
+
+    #[test]
+    fn diffs_changed_files() {
+        let mut old = BTreeMap::new();
+        old.insert("same.rs".to_string(), file(b"same\n"));
+        old.insert("changed.rs".to_string(), file(b"one\ntwo\n"));
+        old.insert("removed.rs".to_string(), file(b"gone\n"));
+        old.insert("image.png".to_string(), file(&[0xff, 0xfe, 0x00]));
+        let mut new = BTreeMap::new();
+        new.insert("same.rs".to_string(), file(b"same\n"));
+        new.insert("changed.rs".to_string(), file(b"one\nthree\n"));
+        new.insert("added.rs".to_string(), file(b"new\n"));
+        new.insert("image.png".to_string(), file(&[0xff, 0xfe, 0x01]));
+
+        let diff = diff_text("foo 1.0.0", &old, "foo 1.0.1", &new);
+        assert!(diff.starts_with("Changes from foo 1.0.0 to foo 1.0.1\n\n"));
+        assert!(!diff.contains("same.rs"));
+        assert!(diff.contains("--- a/changed.rs\n+++ b/changed.rs\n"));
+        assert!(diff.contains("-two\n+three\n"));
+        assert!(diff.contains("--- /dev/null\n+++ b/added.rs\n"));
+        assert!(diff.contains("+new\n"));
+        assert!(diff.contains("--- a/removed.rs\n+++ /dev/null\n"));
+        assert!(diff.contains("-gone\n"));
+        assert!(diff.contains("Binary or large files a/image.png and b/image.png differ\n"));
+    }
+
+    #[test]
+    fn encodes_link_paths() {
+        assert_eq!(percent_encode_path("src/lib.rs"), "src/lib.rs");
+        assert_eq!(
+            percent_encode_path("docs/a b/#1?.md"),
+            "docs/a%20b/%231%3F.md"
+        );
+        assert_eq!(percent_encode_path("ü.rs"), "%C3%BC.rs");
+    }
+}
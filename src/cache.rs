@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use hyper::header::{HeaderMap, IF_MODIFIED_SINCE, IF_NONE_MATCH};
+
+use crate::download::{append_to_path, sha256_file};
+
+/// For files that never change once they're in the mirror: crates, and dated releases.
+pub static CACHE_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// For files that are replaced by syncs, like channel manifests and the latest rustup-init.
+pub static CACHE_REVALIDATE: &str = "public, max-age=60, must-revalidate";
+
+/// Pick the Cache-Control header for a file under `dist/` or `rustup/`.
+pub fn cache_control(url_path: &str) -> &'static str {
+    let segments: Vec<&str> = url_path.trim_start_matches('/').split('/').collect();
+    match segments.as_slice() {
+        // dist/2020-01-30/rust-1.41.0-x86_64-unknown-linux-gnu.tar.xz
+        ["dist", date, _] if is_date(date) => CACHE_IMMUTABLE,
+        // rustup/archive/1.21.1/x86_64-unknown-linux-gnu/rustup-init
+        ["rustup", "archive", ..] => CACHE_IMMUTABLE,
+        _ => CACHE_REVALIDATE,
+    }
+}
+
+fn is_date(s: &str) -> bool {
+    s.len() == 10
+        && s.chars().enumerate().all(|(i, c)| {
+            if i == 4 || i == 7 {
+                c == '-'
+            } else {
+                c.is_ascii_digit()
+            }
+        })
+}
+
+/// Strong ETags for mirror files, based on their SHA-256 hashes.
+///
+/// Hashes are kept in memory as long as the file's size and modification time stay the same,
+/// so each file is only hashed once.
+#[derive(Default)]
+pub struct EtagCache {
+    etags: Mutex<HashMap<PathBuf, CachedEtag>>,
+}
+
+/// A file's ETag, with the size and modification time it was hashed at.
+struct CachedEtag {
+    len: u64,
+    modified: Option<SystemTime>,
+    etag: String,
+}
+
+impl EtagCache {
+    /// Get a file's ETag, hashing it if needed. This may block on reading the file.
+    pub fn etag(&self, path: &Path, metadata: &Metadata) -> Option<String> {
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+        if let Some(cached) = self.etags.lock().unwrap().get(path) {
+            if cached.len == len && cached.modified == modified {
+                return Some(cached.etag.clone());
+            }
+        }
+
+        let hash = match read_sha256_file(path) {
+            Some(hash) => hash,
+            None => sha256_file(path).ok()?,
+        };
+        let etag = format!("\"{}\"", hash);
+        self.etags.lock().unwrap().insert(
+            path.to_owned(),
+            CachedEtag {
+                len,
+                modified,
+                etag: etag.clone(),
+            },
+        );
+        Some(etag)
+    }
+}
+
+/// Read the hash from a file's `.sha256` companion, as mirrored from rustup, if it has one.
+fn read_sha256_file(path: &Path) -> Option<String> {
+    let content = std::fs::read_to_string(append_to_path(path, ".sha256")).ok()?;
+    let hash = content.split_whitespace().next()?;
+    if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(hash.to_lowercase())
+    } else {
+        None
+    }
+}
+
+/// Check the request's conditional headers, to see if the client's copy is still current.
+///
+/// If-None-Match takes precedence over If-Modified-Since, as per RFC 7232.
+pub fn is_not_modified(
+    headers: &HeaderMap,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> bool {
+    if let Some(if_none_match) = headers.get(IF_NONE_MATCH).and_then(|h| h.to_str().ok()) {
+        let etag = match etag {
+            Some(etag) => etag,
+            None => return false,
+        };
+        return if_none_match.split(',').map(|tag| tag.trim()).any(|tag| {
+            // Weak comparison: W/"abc" matches "abc".
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        });
+    }
+
+    if let Some(if_modified_since) = headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| httpdate::parse_http_date(h).ok())
+    {
+        if let Some(last_modified) = last_modified {
+            // HTTP dates only have a precision of seconds.
+            return truncate_to_secs(last_modified) <= if_modified_since;
+        }
+    }
+    false
+}
+
+/// Drop the sub-second part of a time, to match the precision of HTTP dates.
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => SystemTime::UNIX_EPOCH + Duration::from_secs(d.as_secs()),
+        Err(_) => time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn headers(name: hyper::header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn matches_etags() {
+        let etag = Some("\"abc\"");
+        let matches = |value| is_not_modified(&headers(IF_NONE_MATCH, value), etag, None);
+        assert!(matches("\"abc\""));
+        assert!(matches("\"xyz\", \"abc\""));
+        assert!(matches("W/\"abc\""));
+        assert!(matches("*"));
+        assert!(!matches("\"xyz\""));
+        assert!(!matches("abc"));
+
+        // Without an ETag of our own, nothing matches.
+        assert!(!is_not_modified(&headers(IF_NONE_MATCH, "*"), None, None));
+    }
+
+    #[test]
+    fn checks_modification_time() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_millis(1_000_000_500);
+        let at = |secs| httpdate::fmt_http_date(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        let not_modified =
+            |value: &str| is_not_modified(&headers(IF_MODIFIED_SINCE, value), None, Some(modified));
+        // The sub-second part of the modification time is ignored.
+        assert!(not_modified(&at(1_000_000)));
+        assert!(not_modified(&at(1_000_001)));
+        assert!(!not_modified(&at(999_999)));
+        assert!(!not_modified("not a date"));
+        assert!(!is_not_modified(
+            &headers(IF_MODIFIED_SINCE, &at(1_000_000)),
+            None,
+            None
+        ));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut headers = headers(IF_NONE_MATCH, "\"xyz\"");
+        headers.insert(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_str(&httpdate::fmt_http_date(modified)).unwrap(),
+        );
+        assert!(!is_not_modified(&headers, Some("\"abc\""), Some(modified)));
+    }
+
+    #[test]
+    fn picks_cache_control() {
+        assert_eq!(
+            cache_control("/dist/2020-01-30/rust-1.41.0-x86_64-unknown-linux-gnu.tar.xz"),
+            CACHE_IMMUTABLE
+        );
+        assert_eq!(
+            cache_control("/rustup/archive/1.21.1/x86_64-unknown-linux-gnu/rustup-init"),
+            CACHE_IMMUTABLE
+        );
+        assert_eq!(
+            cache_control("/dist/channel-rust-stable.toml"),
+            CACHE_REVALIDATE
+        );
+        assert_eq!(
+            cache_control("/dist/2020-01-30/channel-rust-stable.toml/extra"),
+            CACHE_REVALIDATE
+        );
+        assert_eq!(
+            cache_control("/dist/2020-1-30/rust.tar.xz"),
+            CACHE_REVALIDATE
+        );
+        assert_eq!(
+            cache_control("/rustup/dist/x86_64-unknown-linux-gnu/rustup-init"),
+            CACHE_REVALIDATE
+        );
+    }
+
+    #[test]
+    fn caches_etags_until_the_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, "hello").unwrap();
+        let cache = EtagCache::default();
+
+        let etag = cache.etag(&path, &path.metadata().unwrap()).unwrap();
+        assert_eq!(
+            etag,
+            "\"2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824\""
+        );
+
+        std::fs::write(&path, "hello!").unwrap();
+        assert_ne!(cache.etag(&path, &path.metadata().unwrap()).unwrap(), etag);
+
+        // A `.sha256` companion is used instead of hashing the file.
+        std::fs::write(
+            append_to_path(&path, ".sha256"),
+            format!("{}  file\n", "AB".repeat(32)),
+        )
+        .unwrap();
+        std::fs::write(&path, "changed").unwrap();
+        assert_eq!(
+            cache.etag(&path, &path.metadata().unwrap()).unwrap(),
+            format!("\"{}\"", "ab".repeat(32))
+        );
+    }
+}
@@ -1,17 +1,22 @@
-use crate::download::{download, DownloadError};
-use crate::mirror::{CratesSection, MirrorError, MirrorSection, ServeSection};
+use crate::download::{download, sha256_file, DownloadError, FailedDownload};
+use crate::mirror::{CratesSection, MirrorError, MirrorSection, SyncReport};
+use crate::overlay::Overlay;
 use crate::progress_bar::{progress_bar, ProgressBarMessage};
 use console::style;
 use git2::{
-    FetchOptions, IndexEntry, IndexTime, Oid, Reference, RemoteCallbacks, Repository,
-    RepositoryInitOptions, Signature, Tree,
+    Commit, Delta, ErrorCode, FetchOptions, FileFavor, IndexEntry, IndexTime, MergeOptions, Oid,
+    Reference, RemoteCallbacks, Repository, RepositoryInitOptions, Signature, Tree,
 };
 use reqwest::header::HeaderValue;
 use scoped_threadpool::Pool;
+use semver::Version;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::Path;
+use std::process::Command;
+use std::sync::Mutex;
 use std::{
-    fs::File,
+    fs::{self, File},
     io::{self, BufRead, Cursor},
 };
 
@@ -38,6 +43,12 @@ quick_error! {
             from()
         }
         GitTargetNotFound {}
+        Parse(err: toml::de::Error) {
+            from()
+        }
+        Serialize(err: toml::ser::Error) {
+            from()
+        }
     }
 }
 
@@ -49,48 +60,164 @@ pub struct CrateEntry {
     yanked: bool,
 }
 
-/// Download one single crate file.
-pub fn sync_one_crate_entry(
+/// Get the crates.io URL, or None if default.
+pub fn crates_source(crates: &CratesSection) -> Option<&str> {
+    if crates.source == "https://crates.io/api/v1/crates" {
+        None
+    } else {
+        Some(crates.source.as_ref())
+    }
+}
+
+/// Get the path of a crate's file in the index, e.g. `se/rd/serde`.
+pub fn index_file_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", &name[..1], name),
+        _ => format!("{}/{}/{}", &name[..2], &name[2..4], name),
+    }
+}
+
+/// The directory of a crate's index file, keeping the name's case, like `Se/rd` for `Serde`.
+fn crate_prefix(name: &str) -> String {
+    match name.len() {
+        1 => "1".to_string(),
+        2 => "2".to_string(),
+        3 => format!("3/{}", &name[..1]),
+        _ => format!("{}/{}", &name[..2], &name[2..4]),
+    }
+}
+
+/// Read every version of a crate from the mirror's crates.io-index.
+fn crate_entries(path: &Path, name: &str) -> Result<Vec<CrateEntry>, SyncError> {
+    let repo = Repository::open(path.join("crates.io-index"))?;
+    let reference = match repo.find_reference("refs/heads/master") {
+        Ok(master) => master,
+        Err(_) => repo.find_reference("refs/remotes/origin/master")?,
+    };
+    let tree = reference.peel_to_tree()?;
+    let entry = match tree.get_path(Path::new(&index_file_path(name))) {
+        Ok(entry) => entry,
+        Err(e) if e.code() == ErrorCode::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let blob = entry.to_object(&repo)?.peel_to_blob()?;
+
+    let mut entries = vec![];
+    for line in Cursor::new(blob.content()).lines() {
+        entries.push(serde_json::from_str(&line?)?);
+    }
+    Ok(entries)
+}
+
+/// Check whether a crate is in the mirror's crates.io-index.
+///
+/// Like crates.io, `-` and `_` are treated as the same character.
+pub fn crate_exists(path: &Path, name: &str) -> Result<bool, SyncError> {
+    for variant in &[name.replace('_', "-"), name.replace('-', "_")] {
+        if !crate_entries(path, variant)?.is_empty() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Find one crate version's entry in the mirror's crates.io-index.
+pub fn find_crate_entry(
     path: &Path,
-    source: Option<&str>,
-    retries: usize,
-    crate_entry: &CrateEntry,
-    user_agent: &HeaderValue,
-) -> Result<(), DownloadError> {
-    // What's the URL, what's the download path
+    name: &str,
+    vers: &str,
+) -> Result<Option<CrateEntry>, SyncError> {
+    Ok(crate_entries(path, name)?
+        .into_iter()
+        .find(|e| e.name == name && e.vers == vers))
+}
+
+/// Find the latest version of a crate in the mirror's crates.io-index.
+///
+/// Yanked versions are ignored, and pre-releases are only picked if there's nothing else.
+pub fn latest_crate_version(path: &Path, name: &str) -> Result<Option<String>, SyncError> {
+    let versions: Vec<Version> = crate_entries(path, name)?
+        .iter()
+        .filter(|e| !e.yanked)
+        .filter_map(|e| Version::parse(&e.vers).ok())
+        .collect();
+    let latest = versions
+        .iter()
+        .filter(|v| v.pre.is_empty())
+        .max()
+        .or_else(|| versions.iter().max());
+    Ok(latest.map(|v| v.to_string()))
+}
 
+/// Get the URL to download one crate file from.
+pub fn crate_entry_url(source: Option<&str>, crate_entry: &CrateEntry) -> String {
     // If source is "https://crates.io/api/v1/crates" (the default, and thus a None here)
     // download straight from the static.crates.io CDN, to avoid bogging down crates.io itself
     // or affecting its statistics, and avoiding an extra redirect for each crate.
-    let url = if let Some(source) = source {
-        format!(
-            "{}/{}/{}/download",
-            source, crate_entry.name, crate_entry.vers
-        )
+    if let Some(source) = source {
+        if source.contains('{') {
+            // Alternate registries can give a download URL template, like cargo does.
+            let prefix = crate_prefix(&crate_entry.name);
+            source
+                .replace("{crate}", &crate_entry.name)
+                .replace("{version}", &crate_entry.vers)
+                .replace("{lowerprefix}", &prefix.to_lowercase())
+                .replace("{prefix}", &prefix)
+                .replace("{sha256-checksum}", &crate_entry.cksum)
+        } else {
+            format!(
+                "{}/{}/{}/download",
+                source, crate_entry.name, crate_entry.vers
+            )
+        }
     } else {
         format!(
             "https://static.crates.io/crates/{}/{}-{}.crate",
             crate_entry.name, crate_entry.name, crate_entry.vers
         )
-    };
+    }
+}
+
+/// Download one single crate file.
+pub fn sync_one_crate_entry(
+    path: &Path,
+    source: Option<&str>,
+    retries: usize,
+    crate_entry: &CrateEntry,
+    verify: bool,
+    user_agent: &HeaderValue,
+) -> Result<(), DownloadError> {
+    // What's the URL, what's the download path
+    let url = crate_entry_url(source, crate_entry);
 
     let file_path = path
         .join("crates")
         .join(&crate_entry.name)
         .join(&crate_entry.vers)
         .join("download");
+
+    // If verifying, re-download any existing file that doesn't match the index's checksum
+    let force_download =
+        verify && file_path.exists() && sha256_file(&file_path)? != crate_entry.cksum;
+
     download(
         &url[..],
         &file_path,
         Some(&crate_entry.cksum),
         retries,
-        false,
+        force_download,
         user_agent,
     )
 }
 
 /// Sync the crates.io-index repository.
-pub fn sync_crates_repo(path: &Path, crates: &CratesSection) -> Result<(), SyncError> {
+///
+/// Returns true if the upstream history was rewritten (e.g. crates.io squashed the index),
+/// meaning the new origin/master is not a descendant of the previous one.
+pub fn sync_crates_repo(path: &Path, crates: &CratesSection) -> Result<bool, SyncError> {
     let repo_path = path.join("crates.io-index");
 
     let prefix = format!("{} Syncing crates.io-index...  ", style("[1/3]").bold());
@@ -115,6 +242,7 @@ pub fn sync_crates_repo(path: &Path, crates: &CratesSection) -> Result<(), SyncE
     } else {
         Repository::open(repo_path)?
     };
+    let old_origin = repo.refname_to_id("refs/remotes/origin/master").ok();
     repo.find_remote("origin")?
         .fetch(&["master"], Some(&mut fetch_options), None)?;
     sender
@@ -122,16 +250,82 @@ pub fn sync_crates_repo(path: &Path, crates: &CratesSection) -> Result<(), SyncE
         .expect("Channel send should not fail");
     pb_thread.join().expect("Thread join should not fail");
 
+    let new_origin = repo.refname_to_id("refs/remotes/origin/master")?;
+    let history_rewritten = match old_origin {
+        Some(old_origin) if old_origin != new_origin => {
+            !repo.graph_descendant_of(new_origin, old_origin)?
+        }
+        _ => false,
+    };
+
+    if history_rewritten {
+        eprintln!(
+            "The upstream crates.io-index history has been rewritten (squashed or force-pushed)."
+        );
+    }
+
+    Ok(history_rewritten)
+}
+
+/// A crate file that failed to download, kept in the mirror so it can be retried next sync.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CrateFailure {
+    pub name: String,
+    pub vers: String,
+    pub cksum: String,
+    /// The error from the most recent attempt.
+    pub error: String,
+    /// Number of syncs this crate has failed in.
+    pub attempts: usize,
+    /// Set this to true to stop retrying this crate.
+    #[serde(default)]
+    pub ignore: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CrateFailuresFile {
+    #[serde(default)]
+    pub failures: Vec<CrateFailure>,
+}
+
+/// Load the list of crates that failed to download in previous syncs.
+pub fn get_crate_failures(path: &Path) -> Result<CrateFailuresFile, SyncError> {
+    let failures_path = path.join("mirror-crates-failures.toml");
+    if failures_path.exists() {
+        let data = fs::read_to_string(failures_path)?;
+        Ok(toml::from_str(&data)?)
+    } else {
+        Ok(CrateFailuresFile::default())
+    }
+}
+
+/// Write the list of crates that failed to download.
+pub fn write_crate_failures(path: &Path, failures: &CrateFailuresFile) -> Result<(), SyncError> {
+    let data = toml::to_string(failures)?;
+    fs::write(path.join("mirror-crates-failures.toml"), data)?;
     Ok(())
 }
 
 /// Synchronize the crate files themselves, using the index for a list of files.
-// TODO: There are still many unwraps in the foreach sections. This needs to be fixed.
+///
+/// The list of files is found by comparing the trees of master and origin/master,
+/// not their commit history, so this still works after upstream rewrites its history.
+/// Normally only index files that changed since the last sync are visited.
+/// If `full` is set, the entire index is walked instead, to backfill any missing crates.
+/// If `verify` is set, the entire index is walked too, and every existing crate file is checked
+/// against the index's checksums.
+///
+/// Crates that failed to download in previous syncs are retried as well,
+/// unless they have been marked as ignored in mirror-crates-failures.toml.
+/// Index files that can't be read are reported as failures, and the rest are synced anyway.
 pub fn sync_crates_files(
     path: &Path,
     mirror: &MirrorSection,
     crates: &CratesSection,
+    full: bool,
+    verify: bool,
     user_agent: &HeaderValue,
+    failed: &mut Vec<FailedDownload>,
 ) -> Result<(), SyncError> {
     let prefix = format!("{} Syncing crates files...     ", style("[2/3]").bold());
 
@@ -139,16 +333,26 @@ pub fn sync_crates_files(
     let repo_path = path.join("crates.io-index");
     let repo = Repository::open(repo_path)?;
 
-    // Set the crates.io URL, or None if default
-    let crates_source = if crates.source == "https://crates.io/api/v1/crates" {
-        None
-    } else {
-        Some(crates.source.as_ref())
-    };
+    let crates_source = crates_source(crates);
+
+    // Split previous failures into those to retry, and those the user wants ignored
+    let (ignored, retries): (Vec<CrateFailure>, Vec<CrateFailure>) = get_crate_failures(path)?
+        .failures
+        .into_iter()
+        .partition(|f| f.ignore);
+    let previous_attempts: HashMap<(String, String), usize> = retries
+        .iter()
+        .map(|f| ((f.name.clone(), f.vers.clone()), f.attempts))
+        .collect();
 
     // Find References for origin/master and master (if it exists)
+    // Pretend master doesn't exist if doing a full rescan, or verifying every crate file.
     let origin_master = repo.find_reference("refs/remotes/origin/master")?;
-    let master = repo.find_reference("refs/heads/master").ok();
+    let master = if full || verify {
+        None
+    } else {
+        repo.find_reference("refs/heads/master").ok()
+    };
 
     // Diff between the two references, or find all files if master doesn't exist
     let origin_tree = origin_master.peel_to_tree()?;
@@ -160,18 +364,17 @@ pub fn sync_crates_files(
     }?;
 
     // Run one pass to figure out a total count
-    let mut count = 0;
+    let mut count = retries.len();
     diff.foreach(
         &mut |delta, _| {
             let df = delta.new_file();
-            let p = df.path().unwrap();
-            if p == Path::new("config.json") {
+            // Files only in master, like the local overlay's, have nothing to download.
+            if df.path() == Some(Path::new("config.json")) || delta.status() == Delta::Deleted {
                 return true;
             }
-            let oid = df.id();
-            let blob = repo.find_blob(oid).unwrap();
-            let data = blob.content();
-            count += Cursor::new(data).lines().count();
+            if let Ok(blob) = repo.find_blob(df.id()) {
+                count += Cursor::new(blob.content()).lines().count();
+            }
             true
         },
         None,
@@ -180,45 +383,104 @@ pub fn sync_crates_files(
     )?;
 
     let (pb_thread, sender) = progress_bar(Some(count), prefix);
+    let failed_crates: Mutex<Vec<(CrateEntry, FailedDownload)>> = Mutex::new(vec![]);
+    // Index files or lines that couldn't be read, so there's no crate to retry.
+    let mut bad_index_files: Vec<FailedDownload> = vec![];
+    let mut walk_result = Ok(());
 
     Pool::new(crates.download_threads as u32).scoped(|scoped| {
-        diff.foreach(
+        let failed_crates = &failed_crates;
+        let sync_entry = |c: CrateEntry| {
+            let s = sender.clone();
+            scoped.execute(move || {
+                if let Err(e) = sync_one_crate_entry(
+                    path,
+                    crates_source,
+                    mirror.retries,
+                    &c,
+                    verify,
+                    user_agent,
+                ) {
+                    s.send(ProgressBarMessage::Println(format!(
+                        "Downloading {} {} failed: {:?}",
+                        &c.name, &c.vers, e
+                    )))
+                    .expect("Channel send should not fail");
+                    let failed_download =
+                        FailedDownload::new(&crate_entry_url(crates_source, &c), &e);
+                    failed_crates
+                        .lock()
+                        .expect("Mutex lock should not fail")
+                        .push((c, failed_download));
+                }
+                s.send(ProgressBarMessage::Increment)
+                    .expect("progress bar increment error");
+            });
+        };
+
+        // Retry the crates that failed last time
+        for f in &retries {
+            sync_entry(CrateEntry {
+                name: f.name.clone(),
+                vers: f.vers.clone(),
+                cksum: f.cksum.clone(),
+                yanked: false,
+            });
+        }
+
+        walk_result = diff.foreach(
             &mut |delta, _| {
                 let df = delta.new_file();
-                let p = df.path().unwrap();
-                if p == Path::new("config.json") {
+                let p = match df.path() {
+                    Some(p) => p,
+                    None => return true,
+                };
+                if p == Path::new("config.json") || delta.status() == Delta::Deleted {
                     return true;
                 }
-                let oid = df.id();
-
-                let blob = repo.find_blob(oid).unwrap();
-                let data = blob.content();
-                for line in Cursor::new(data).lines() {
-                    let line = line.unwrap();
-                    let c: CrateEntry = serde_json::from_str(&line).unwrap();
-                    let s = sender.clone();
-                    scoped.execute(move || {
-                        match sync_one_crate_entry(
-                            path,
-                            crates_source,
-                            mirror.retries,
-                            &c,
-                            user_agent,
-                        ) {
-                            Ok(())
-                            | Err(DownloadError::NotFound(_, _, _))
-                            | Err(DownloadError::MismatchedHash(_, _)) => {}
-                            Err(e) => {
-                                s.send(ProgressBarMessage::Println(format!(
-                                    "Downloading {} {} failed: {:?}",
-                                    &c.name, &c.vers, e
-                                )))
-                                .expect("Channel send should not fail");
-                            }
+                let mut bad_index_file = |error: String| {
+                    sender
+                        .send(ProgressBarMessage::Println(format!(
+                            "Reading index file {} failed: {}",
+                            p.display(),
+                            error
+                        )))
+                        .expect("Channel send should not fail");
+                    bad_index_files.push(FailedDownload {
+                        url: p.display().to_string(),
+                        error,
+                    });
+                };
+
+                let blob = match repo.find_blob(df.id()) {
+                    Ok(blob) => blob,
+                    Err(e) => {
+                        bad_index_file(e.to_string());
+                        return true;
+                    }
+                };
+                for line in Cursor::new(blob.content()).lines() {
+                    let c: CrateEntry = match line
+                        .map_err(|e| e.to_string())
+                        .and_then(|line| serde_json::from_str(&line).map_err(|e| e.to_string()))
+                    {
+                        Ok(c) => c,
+                        Err(e) => {
+                            bad_index_file(e);
+                            sender
+                                .send(ProgressBarMessage::Increment)
+                                .expect("progress bar increment error");
+                            continue;
                         }
-                        s.send(ProgressBarMessage::Increment)
+                    };
+                    if previous_attempts.contains_key(&(c.name.clone(), c.vers.clone())) {
+                        // Already being retried, don't download the same file twice at once.
+                        sender
+                            .send(ProgressBarMessage::Increment)
                             .expect("progress bar increment error");
-                    });
+                        continue;
+                    }
+                    sync_entry(c);
                 }
 
                 true
@@ -226,8 +488,7 @@ pub fn sync_crates_files(
             None,
             None,
             None,
-        )
-        .unwrap();
+        );
     });
 
     sender
@@ -235,98 +496,327 @@ pub fn sync_crates_files(
         .expect("Channel send should not fail");
     pb_thread.join().expect("Thread join should not fail");
 
-    Ok(())
-}
+    walk_result?;
 
-/// Check if the config.json in master matches what we're expecting.
-/// If config.json doesn't match what we've set previously, we need to update it.
-pub fn is_config_json_up_to_date(
-    repo: &Repository,
-    master_tree: &Tree,
-    content: &[u8],
-) -> Result<bool, SyncError> {
-    let existing_config_json = master_tree.get_name("config.json").unwrap();
+    let failed_crates = failed_crates
+        .into_inner()
+        .expect("Mutex into_inner should not fail");
+    let errors = failed_crates.len() + bad_index_files.len();
+    failed.append(&mut bad_index_files);
 
-    let config_blob = existing_config_json.to_object(&repo)?.peel_to_blob()?;
-    let config_blob_content = config_blob.content();
+    // Keep track of everything that failed, so it gets retried next time
+    let mut failures = CrateFailuresFile { failures: ignored };
+    for (c, failed_download) in failed_crates {
+        let attempts = previous_attempts
+            .get(&(c.name.clone(), c.vers.clone()))
+            .unwrap_or(&0)
+            + 1;
+        failures.failures.push(CrateFailure {
+            name: c.name,
+            vers: c.vers,
+            cksum: c.cksum,
+            error: failed_download.error.clone(),
+            attempts,
+            ignore: false,
+        });
+        failed.push(failed_download);
+    }
+    write_crate_failures(path, &failures)?;
 
-    Ok(config_blob_content == content)
+    if errors == 0 {
+        Ok(())
+    } else {
+        Err(SyncError::FailedDownloads(errors))
+    }
 }
 
-/// Create a new config.json, add it to an index, and commit the index.
-pub fn commit_new_config_json(
-    repo: &Repository,
-    master: &Reference,
-    origin_master_tree: &Tree,
-    signature: &Signature,
-    content: &[u8],
-) -> Result<(), SyncError> {
-    // Get the git index and clear it.
+/// A git index entry for a regular file, to add with `Index::add_frombuffer`.
+pub fn file_index_entry(path: &str) -> IndexEntry {
+    IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode: 0o100644,
+        uid: 0,
+        gid: 0,
+        file_size: 0,
+        id: Oid::from_bytes(&[0; 20]).expect("OID from zeroes should not fail"),
+        flags: 0,
+        flags_extended: 0,
+        path: path.as_bytes().to_vec(),
+    }
+}
+
+/// Build master's tree from `base`: origin/master's files, plus config.json and the local overlay.
+///
+/// Any file in `base` that differs from origin/master, other than config.json, came from a
+/// previous overlay. Those files are reset first, so crates removed from the overlay disappear
+/// from the index again.
+fn build_master_tree<'r>(
+    repo: &'r Repository,
+    origin_tree: &Tree,
+    base: &Tree,
+    overlay: &Overlay,
+    config_json: &[u8],
+) -> Result<Tree<'r>, SyncError> {
     let mut index = repo.index()?;
     index.clear()?;
+    index.read_tree(base)?;
 
-    // Read the origin master's files into the index.
-    index.read_tree(&origin_master_tree)?;
-
-    // Add our config.json change into the index.
-    index.add_frombuffer(
-        &IndexEntry {
-            ctime: IndexTime::new(0, 0),
-            mtime: IndexTime::new(0, 0),
-            dev: 0,
-            ino: 0,
-            mode: 0o100644,
-            uid: 0,
-            gid: 0,
-            file_size: 0,
-            id: Oid::from_bytes(&[0; 20]).expect("OID from zeroes should not fail"),
-            flags: 0,
-            flags_extended: 0,
-            path: b"config.json".to_vec(),
-        },
-        content,
-    )?;
+    let diff = repo.diff_tree_to_tree(Some(origin_tree), Some(base), None)?;
+    for delta in diff.deltas() {
+        let file = delta.new_file().path().or_else(|| delta.old_file().path());
+        let file = match file.and_then(|f| f.to_str()) {
+            Some(file) if file != "config.json" => file,
+            _ => continue,
+        };
+        match origin_tree.get_path(Path::new(file)) {
+            Ok(entry) => {
+                let blob = entry.to_object(repo)?.peel_to_blob()?;
+                index.add_frombuffer(&file_index_entry(file), blob.content())?;
+            }
+            Err(_) => index.remove_path(Path::new(file))?,
+        }
+    }
+    for (file, content) in overlay.files() {
+        index.add_frombuffer(&file_index_entry(file), content)?;
+    }
+    index.add_frombuffer(&file_index_entry("config.json"), config_json)?;
 
     // Write the index file back to disk (the .git/index file).
     // This ensures the git working directory doesn't get staged,
     // so the entire repository doesn't get deleted in master.
     let oid = index.write_tree()?;
     index.write()?;
+    Ok(repo.find_tree(oid)?)
+}
+
+/// Point master at origin/master, with a commit of `tree` on top if it differs.
+fn set_master_from_origin(
+    repo: &Repository,
+    origin_master: &Reference,
+    tree: &Tree,
+    signature: &Signature,
+    log_message: &str,
+) -> Result<(), SyncError> {
+    let origin_commit = origin_master.peel_to_commit()?;
+    let target = if tree.id() == origin_commit.tree_id() {
+        origin_commit.id()
+    } else {
+        repo.commit(
+            None,
+            signature,
+            signature,
+            "Update config.json and local overlay",
+            tree,
+            &[&origin_commit],
+        )?
+    };
+    // Use a plain reference update instead of repo.branch(), since libgit2
+    // refuses to force-update the branch HEAD points to.
+    repo.reference("refs/heads/master", target, true, log_message)?;
+
+    Ok(())
+}
+
+/// Create master by branching from origin/master.
+pub fn create_master_branch(
+    repo: &Repository,
+    origin_master: &Reference,
+    tree: &Tree,
+    signature: &Signature,
+) -> Result<(), SyncError> {
+    set_master_from_origin(repo, origin_master, tree, signature, "Create master")
+}
+
+/// Point master at origin/master, discarding master's previous history.
+///
+/// This is used when upstream has rewritten its history, so the two branches can't be merged.
+pub fn reset_master_branch(
+    repo: &Repository,
+    origin_master: &Reference,
+    tree: &Tree,
+    signature: &Signature,
+) -> Result<(), SyncError> {
+    set_master_from_origin(
+        repo,
+        origin_master,
+        tree,
+        signature,
+        "Reset master to rewritten origin/master",
+    )
+}
 
-    // Perform the actual commit
-    let parent_commit = master.peel_to_commit()?;
-    let tree = repo.find_tree(oid)?;
+/// The commit message for a squashed snapshot of origin/master.
+fn snapshot_message(origin_commit: &Commit) -> String {
+    format!("Snapshot of crates.io-index at {}", origin_commit.id())
+}
+
+/// The commit message for an update of a squashed master to a newer origin/master.
+fn snapshot_update_message(origin_commit: &Commit) -> String {
+    format!("Update to crates.io-index at {}", origin_commit.id())
+}
+
+/// Check whether a squashed master already has the current origin/master's tree.
+///
+/// Snapshot and update commits name the origin/master commit they were made from, and only
+/// config.json commits go on top of them, so only the first few commits need to be checked.
+pub fn is_squashed_master_current(
+    origin_master: &Reference,
+    master: &Reference,
+) -> Result<bool, SyncError> {
+    let origin_id = origin_master.peel_to_commit()?.id().to_string();
+
+    let mut commit = master.peel_to_commit()?;
+    for _ in 0..4 {
+        if commit
+            .message()
+            .is_some_and(|m| m.ends_with(origin_id.as_str()))
+        {
+            return Ok(true);
+        }
+        let parent = commit.parents().next();
+        match parent {
+            Some(parent) => commit = parent,
+            None => break,
+        }
+    }
+
+    Ok(false)
+}
+
+/// Check whether a squashed master has grown past the configured number of commits or age,
+/// counting from its snapshot commit.
+pub fn squash_threshold_reached(
+    master: &Reference,
+    max_commits: usize,
+    max_age_days: u64,
+) -> Result<bool, SyncError> {
+    let mut commit = master.peel_to_commit()?;
+    let mut depth = 0;
+    loop {
+        let parent = commit.parents().next();
+        match parent {
+            Some(parent) => commit = parent,
+            None => break,
+        }
+        depth += 1;
+        if depth > max_commits {
+            return Ok(true);
+        }
+    }
+
+    // Anything but a snapshot at the bottom is left over from before squash_index was enabled.
+    if !commit
+        .message()
+        .is_some_and(|m| m.starts_with("Snapshot of crates.io-index at "))
+    {
+        return Ok(true);
+    }
+    let age = chrono::Utc::now().timestamp() - commit.time().seconds();
+    Ok(age > max_age_days as i64 * 24 * 60 * 60)
+}
+
+/// Add a commit on top of a squashed master with origin/master's files, as `tree`,
+/// so clients fetching the index only download what changed.
+pub fn update_squashed_master_branch(
+    repo: &Repository,
+    origin_master: &Reference,
+    master: &Reference,
+    tree: &Tree,
+    signature: &Signature,
+) -> Result<(), SyncError> {
+    let origin_commit = origin_master.peel_to_commit()?;
+    let master_commit = master.peel_to_commit()?;
 
     repo.commit(
         Some("refs/heads/master"),
-        &signature,
-        &signature,
-        "Update config.json to mirror URL",
-        &tree,
-        &[&parent_commit],
+        signature,
+        signature,
+        &snapshot_update_message(&origin_commit),
+        tree,
+        &[&master_commit],
     )?;
 
     Ok(())
 }
 
-/// Create master by branching from origin/master.
-pub fn create_master_branch(repo: &Repository, origin_master: &Reference) -> Result<(), SyncError> {
+/// Replace master with a single parentless commit of origin/master's files, as `tree`.
+pub fn squash_master_branch(
+    repo: &Repository,
+    origin_master: &Reference,
+    tree: &Tree,
+    signature: &Signature,
+) -> Result<(), SyncError> {
     let origin_commit = origin_master.peel_to_commit()?;
-    if let Some(origin_target) = origin_master.target() {
-        let b = repo.branch("master", &origin_commit, true)?;
-        b.into_reference().set_target(origin_target, "")?;
-    } else {
-        return Err(SyncError::GitTargetNotFound);
-    }
+
+    let snapshot = repo.commit(
+        None,
+        signature,
+        signature,
+        &snapshot_message(&origin_commit),
+        tree,
+        &[],
+    )?;
+    repo.reference("refs/heads/master", snapshot, true, "Squash master")?;
 
     Ok(())
 }
 
-/// Merge new commits from origin/master into master.
+/// Prune history that's no longer reachable from the crates.io-index repository.
+///
+/// libgit2 can't garbage collect, so this uses the git command line, if it's installed.
+pub fn prune_crates_repo(repo_path: &Path) {
+    let commands: &[&[&str]] = &[
+        &["reflog", "expire", "--expire=now", "--all"],
+        &["gc", "--prune=now", "--quiet"],
+    ];
+
+    for args in commands {
+        match Command::new("git")
+            .arg("-C")
+            .arg(repo_path)
+            .args(*args)
+            .status()
+        {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                eprintln!("Pruning crates.io-index failed: git exited with {}", status);
+                return;
+            }
+            Err(e) => {
+                eprintln!("Could not run git to prune crates.io-index: {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Check whether master and origin/master share any history at all.
+pub fn has_common_history(
+    repo: &Repository,
+    origin_master: &Reference,
+    master: &Reference,
+) -> Result<bool, SyncError> {
+    let origin_commit = origin_master.peel_to_commit()?;
+    let master_commit = master.peel_to_commit()?;
+
+    match repo.merge_base(origin_commit.id(), master_commit.id()) {
+        Ok(_) => Ok(true),
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Merge new commits from origin/master into master, with config.json and the local overlay
+/// updated in the merge commit.
 pub fn merge_into_master(
     repo: &Repository,
     origin_master: &Reference,
     master: &Reference,
+    overlay: &Overlay,
+    config_json: &[u8],
     signature: &Signature,
 ) -> Result<(), SyncError> {
     let origin_commit = origin_master.peel_to_commit()?;
@@ -339,14 +829,19 @@ pub fn merge_into_master(
     let ancestor_commit = repo.find_commit(merge_base)?;
     let ancestor = ancestor_commit.tree()?;
 
-    let mut idx = repo.merge_trees(&ancestor, &master_tree, &origin_tree, None)?;
+    // Master's own files (config.json, the local overlay) are rewritten before committing anyway,
+    // so upstream wins any conflicts.
+    let mut merge_options = MergeOptions::new();
+    merge_options.file_favor(FileFavor::Theirs);
+    let mut idx = repo.merge_trees(&ancestor, &master_tree, &origin_tree, Some(&merge_options))?;
 
-    let result_tree = repo.find_tree(idx.write_tree_to(repo)?)?;
+    let merged_tree = repo.find_tree(idx.write_tree_to(repo)?)?;
+    let result_tree = build_master_tree(repo, &origin_tree, &merged_tree, overlay, config_json)?;
 
     let _merge_commit = repo.commit(
         Some("HEAD"),
-        &signature,
-        &signature,
+        signature,
+        signature,
         "Merge origin/master into master",
         &result_tree,
         &[&master_commit, &origin_commit],
@@ -358,22 +853,56 @@ pub fn merge_into_master(
 #[derive(Debug, Serialize)]
 struct ConfigJson {
     dl: String,
-    api: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api: Option<String>,
+    #[serde(rename = "auth-required", skip_serializing_if = "std::ops::Not::not")]
+    auth_required: bool,
 }
 
 /// Build the config.json content, based on what base_url is set to in mirror.toml.
-pub fn build_config_json_content(base_url: &str) -> Result<Vec<u8>, SyncError> {
+///
+/// The API is served from base_url too, if the registry has one. If the server requires
+/// authentication, cargo is told to send its token with every request.
+pub fn build_config_json_content(
+    base_url: &str,
+    has_api: bool,
+    auth_required: bool,
+) -> Result<Vec<u8>, SyncError> {
+    let api = if has_api { Some(base_url) } else { None };
+    config_json_content(base_url, api, auth_required)
+}
+
+/// Build a config.json with separate download and API URLs.
+pub fn config_json_content(
+    dl: &str,
+    api: Option<&str>,
+    auth_required: bool,
+) -> Result<Vec<u8>, SyncError> {
     let config_json = ConfigJson {
-        dl: base_url.to_string(),
-        api: base_url.to_string(),
+        dl: dl.to_string(),
+        api: api.map(|api| api.to_string()),
+        auth_required,
     };
 
     Ok(serde_json::to_vec_pretty(&config_json)?)
 }
 
 /// Merge the crates.io-index's master branch with origin/master,
-/// keeping config.json up to date.
-pub fn merge_crates_repo(path: &Path, serve: &ServeSection) -> Result<(), SyncError> {
+/// keeping config.json and the local overlay up to date in the same commit.
+///
+/// If upstream history was rewritten, master is rebuilt from origin/master instead,
+/// with a commit of config.json and the overlay on top.
+///
+/// If squash_index is set, master instead starts from a single snapshot of origin/master,
+/// with one commit per sync on top. It's squashed again once it has grown past
+/// squash_index_max_commits commits, or its snapshot is older than squash_index_max_days.
+pub fn merge_crates_repo(
+    path: &Path,
+    crates: &CratesSection,
+    options: &CratesSyncOptions,
+    history_rewritten: bool,
+    overlay: &Overlay,
+) -> Result<(), SyncError> {
     eprintln!("{} Merging crates.io-index...  ", style("[3/3]").bold());
 
     let repo_path = path.join("crates.io-index");
@@ -384,35 +913,91 @@ pub fn merge_crates_repo(path: &Path, serve: &ServeSection) -> Result<(), SyncEr
     let origin_master = repo.find_reference("refs/remotes/origin/master")?;
     let origin_master_tree = origin_master.peel_to_tree()?;
 
-    if let Ok(master) = repo.find_reference("refs/heads/master") {
-        // Attempt to merge origin/master into master.
-        merge_into_master(&repo, &origin_master, &master, &signature)?;
-    } else {
-        // If master doesn't exist, branch from origin/master.
-        create_master_branch(&repo, &origin_master)?;
-    }
+    // If base_url is set, point config.json at the mirror. Otherwise, keep crates.io's,
+    // in case the user removes base_url after the fact.
+    let config_json = match options.base_url {
+        Some(base_url) => {
+            build_config_json_content(base_url, options.has_api, options.auth_required)?
+        }
+        None => DEFAULT_CONFIG_JSON_CONTENT.to_vec(),
+    };
+    let new_tree = || {
+        build_master_tree(
+            &repo,
+            &origin_master_tree,
+            &origin_master_tree,
+            overlay,
+            &config_json,
+        )
+    };
 
-    // At this point, master should exist and be in a merged/consistent state.
-    let master = repo.find_reference("refs/heads/master")?;
-    let master_tree = master.peel_to_tree()?;
+    let squash = crates.squash_index.unwrap_or(false);
+    let mut squashed = false;
 
-    // If base_url is set, update config.json if it needs to be updated.
-    if let Some(ref base_url) = serve.base_url {
-        let content = build_config_json_content(&base_url)?;
-        if !is_config_json_up_to_date(&repo, &master_tree, &content)? {
-            commit_new_config_json(&repo, &master, &origin_master_tree, &signature, &content)?;
+    if squash {
+        let max_commits = crates.squash_index_max_commits.unwrap_or(100);
+        let max_age_days = crates.squash_index_max_days.unwrap_or(30);
+        match repo.find_reference("refs/heads/master") {
+            Ok(master) if !squash_threshold_reached(&master, max_commits, max_age_days)? => {
+                // Squashing makes every client download the whole index again, so only add
+                // to master while it's small enough.
+                if !is_squashed_master_current(&origin_master, &master)? {
+                    let tree = new_tree()?;
+                    update_squashed_master_branch(
+                        &repo,
+                        &origin_master,
+                        &master,
+                        &tree,
+                        &signature,
+                    )?;
+                }
+            }
+            _ => {
+                squash_master_branch(&repo, &origin_master, &new_tree()?, &signature)?;
+                squashed = true;
+            }
         }
-    } else {
-        // This section is useful in case the user removes base_url after the fact.
-        if !is_config_json_up_to_date(&repo, &master_tree, DEFAULT_CONFIG_JSON_CONTENT)? {
-            commit_new_config_json(
+    } else if let Ok(master) = repo.find_reference("refs/heads/master") {
+        if history_rewritten || !has_common_history(&repo, &origin_master, &master)? {
+            // Upstream history was squashed or force-pushed, so there's nothing to merge with.
+            eprintln!("Rebuilding master from the rewritten origin/master.");
+            reset_master_branch(&repo, &origin_master, &new_tree()?, &signature)?;
+        } else {
+            // Attempt to merge origin/master into master.
+            merge_into_master(
                 &repo,
+                &origin_master,
                 &master,
-                &origin_master_tree,
+                overlay,
+                &config_json,
                 &signature,
-                DEFAULT_CONFIG_JSON_CONTENT,
             )?;
         }
+    } else {
+        // If master doesn't exist, branch from origin/master.
+        create_master_branch(&repo, &origin_master, &new_tree()?, &signature)?;
+    }
+
+    // An up-to-date squashed master still needs any config.json or overlay changes.
+    let master = repo.find_reference("refs/heads/master")?;
+    let master_commit = master.peel_to_commit()?;
+    let master_tree = master_commit.tree()?;
+    let tree = build_master_tree(
+        &repo,
+        &origin_master_tree,
+        &master_tree,
+        overlay,
+        &config_json,
+    )?;
+    if tree.id() != master_tree.id() {
+        repo.commit(
+            Some("refs/heads/master"),
+            &signature,
+            &signature,
+            "Update config.json and local overlay",
+            &tree,
+            &[&master_commit],
+        )?;
     }
 
     // add `git-daemon-export-ok` file so we can serve it later
@@ -421,37 +1006,405 @@ pub fn merge_crates_repo(path: &Path, serve: &ServeSection) -> Result<(), SyncEr
     export.push("git-daemon-export-ok");
     let _ = File::create(export);
 
+    if squashed {
+        prune_crates_repo(&repo_path);
+    }
+
     Ok(())
 }
 
+/// How to sync one registry: crates.io, or one of the other mirrored registries.
+pub struct CratesSyncOptions<'a> {
+    /// The sync report section failures go in, "crates" or "registry:<name>".
+    pub section: &'a str,
+    /// Where the registry's crates are served from, for its config.json.
+    pub base_url: Option<&'a str>,
+    /// Whether the crates API is served from base_url, as it is for crates.io only.
+    pub has_api: bool,
+    pub auth_required: bool,
+    /// Check every crate in the index, instead of just the new ones.
+    pub full: bool,
+    /// Also check the hashes of crate files already in the mirror.
+    pub verify: bool,
+}
+
+/// Record a failed phase of the crates sync in the sync report.
+fn report_failure(
+    report: &mut SyncReport,
+    section: &str,
+    phase: &str,
+    e: &SyncError,
+    failed: Vec<FailedDownload>,
+) {
+    let count = match e {
+        SyncError::FailedDownloads(count) => *count,
+        _ => 1,
+    };
+    report.add_failure(section, phase, count, format!("{:?}", e), failed);
+}
+
 /// Synchronize crates.io mirror.
 pub fn sync(
     path: &Path,
     mirror: &MirrorSection,
     crates: &CratesSection,
-    serve: &ServeSection,
+    options: &CratesSyncOptions,
     user_agent: &HeaderValue,
+    report: &mut SyncReport,
 ) -> Result<(), MirrorError> {
     eprintln!("{}", style("Syncing Crates repositories...").bold());
 
-    if let Err(e) = sync_crates_repo(path, crates) {
-        eprintln!("Downloading crates.io-index repository failed: {:?}", e);
-        eprintln!("You will need to sync again to finish this download.");
-        return Ok(());
+    let history_rewritten = match sync_crates_repo(path, crates) {
+        Ok(history_rewritten) => history_rewritten,
+        Err(e) => {
+            eprintln!("Downloading crates.io-index repository failed: {:?}", e);
+            eprintln!("You will need to sync again to finish this download.");
+            report_failure(report, options.section, "index", &e, vec![]);
+            return Ok(());
+        }
+    };
+
+    let mut failed = vec![];
+    let files_result = if crates.sync_files.unwrap_or(true) {
+        sync_crates_files(
+            path,
+            mirror,
+            crates,
+            options.full,
+            options.verify,
+            user_agent,
+            &mut failed,
+        )
+    } else {
+        eprintln!("Crates files sync is disabled, skipping...");
+        Ok(())
+    };
+    match files_result {
+        Ok(()) => {}
+        Err(e @ SyncError::FailedDownloads(_)) => {
+            // Individual crate failures shouldn't hold back the rest of the index.
+            eprintln!("Downloading some crates failed: {:?}", e);
+            report_failure(report, options.section, "files", &e, failed);
+        }
+        Err(e) => {
+            eprintln!("Downloading crates failed: {:?}", e);
+            eprintln!("You will need to sync again to finish this download.");
+            report_failure(report, options.section, "files", &e, failed);
+            return Ok(());
+        }
     }
 
-    if let Err(e) = sync_crates_files(path, mirror, crates, user_agent) {
-        eprintln!("Downloading crates failed: {:?}", e);
+    // Without an overlay_dir, any previous overlay is removed from the index.
+    let mut overlay = match &crates.overlay_dir {
+        Some(overlay_dir) => match crate::overlay::read(path, crates, overlay_dir) {
+            Ok(overlay) => overlay,
+            Err(e) => {
+                eprintln!("Reading the local overlay failed: {:?}", e);
+                eprintln!("You will need to sync again to finish this download.");
+                report_failure(report, options.section, "overlay", &e, vec![]);
+                return Ok(());
+            }
+        },
+        None => Overlay::default(),
+    };
+
+    if let Err(e) = merge_crates_repo(path, crates, options, history_rewritten, &overlay) {
+        eprintln!("Merging crates.io-index repository failed: {:?}", e);
         eprintln!("You will need to sync again to finish this download.");
+        report_failure(report, options.section, "merge", &e, vec![]);
         return Ok(());
     }
 
-    if let Err(e) = merge_crates_repo(path, serve) {
-        eprintln!("Merging crates.io-index repository failed: {:?}", e);
-        eprintln!("You will need to sync again to finish this download.");
+    if !overlay.problems.is_empty() {
+        let problems = std::mem::take(&mut overlay.problems);
+        eprintln!("Some local overlay crates were left out:");
+        for problem in &problems {
+            eprintln!("  {}: {}", problem.url, problem.error);
+        }
+        report_failure(
+            report,
+            options.section,
+            "overlay",
+            &SyncError::FailedDownloads(problems.len()),
+            problems,
+        );
     }
 
     eprintln!("{}", style("Syncing Crates repositories complete!").bold());
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Time;
+
+    static OPTIONS: CratesSyncOptions = CratesSyncOptions {
+        section: "crates",
+        base_url: None,
+        has_api: true,
+        auth_required: false,
+        full: false,
+        verify: false,
+    };
+
+    fn crates_section(source: &str) -> CratesSection {
+        toml::from_str(&format!(
+            "sync = true\ndownload_threads = 1\nsource = \"{}\"\nsource_index = \"\"\n",
+            source
+        ))
+        .unwrap()
+    }
+
+    /// Commit files to the index repository without moving any branch.
+    fn commit(
+        repo: &Repository,
+        files: &[(&str, &str)],
+        message: &str,
+        parents: &[&Commit],
+        time: Option<i64>,
+    ) -> Oid {
+        let mut index = repo.index().unwrap();
+        index.clear().unwrap();
+        for (file, content) in files {
+            index
+                .add_frombuffer(&file_index_entry(file), content.as_bytes())
+                .unwrap();
+        }
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = match time {
+            Some(time) => Signature::new("test", "test@test", &Time::new(time, 0)).unwrap(),
+            None => Signature::now("test", "test@test").unwrap(),
+        };
+        repo.commit(None, &signature, &signature, message, &tree, parents)
+            .unwrap()
+    }
+
+    fn index_repo(path: &Path) -> Repository {
+        Repository::init(path.join("crates.io-index")).unwrap()
+    }
+
+    fn set_ref(repo: &Repository, name: &str, oid: Oid) {
+        repo.reference(name, oid, true, "").unwrap();
+    }
+
+    static SERDE: (&str, &str) = (
+        "se/rd/serde",
+        "{\"name\":\"serde\",\"vers\":\"1.0.0\",\"cksum\":\"00\",\"yanked\":false}\n",
+    );
+
+    #[test]
+    fn retries_and_records_failed_crates() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = index_repo(dir.path());
+        let origin = commit(
+            &repo,
+            &[("config.json", "{}\n"), SERDE],
+            "Initial",
+            &[],
+            None,
+        );
+        set_ref(&repo, "refs/remotes/origin/master", origin);
+
+        let mirror = MirrorSection {
+            retries: 0,
+            contact: None,
+        };
+        // Nothing listens on port 1, so every download fails.
+        let crates = crates_section("http://127.0.0.1:1");
+        let user_agent = HeaderValue::from_static("panamax-test");
+        let sync = || {
+            let mut failed = vec![];
+            let result = sync_crates_files(
+                dir.path(),
+                &mirror,
+                &crates,
+                false,
+                false,
+                &user_agent,
+                &mut failed,
+            );
+            assert!(matches!(result, Err(SyncError::FailedDownloads(1))));
+            assert_eq!(failed.len(), 1);
+            get_crate_failures(dir.path()).unwrap().failures
+        };
+
+        let failures = sync();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(
+            (failures[0].name.as_str(), failures[0].vers.as_str()),
+            ("serde", "1.0.0")
+        );
+        assert_eq!(failures[0].attempts, 1);
+
+        // The index still lists it, but it's only downloaded once, as a retry.
+        let failures = sync();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].attempts, 2);
+
+        // Ignored crates are kept in the file, and not retried.
+        set_ref(&repo, "refs/heads/master", origin);
+        let mut file = get_crate_failures(dir.path()).unwrap();
+        file.failures[0].ignore = true;
+        write_crate_failures(dir.path(), &file).unwrap();
+        let mut failed = vec![];
+        sync_crates_files(
+            dir.path(),
+            &mirror,
+            &crates,
+            false,
+            false,
+            &user_agent,
+            &mut failed,
+        )
+        .unwrap();
+        let failures = get_crate_failures(dir.path()).unwrap().failures;
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].ignore);
+        assert_eq!(failures[0].attempts, 2);
+    }
+
+    #[test]
+    fn reports_unreadable_index_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = index_repo(dir.path());
+        let origin = commit(
+            &repo,
+            &[("config.json", "{}\n"), ("se/rd/serde", "not json\n")],
+            "Initial",
+            &[],
+            None,
+        );
+        set_ref(&repo, "refs/remotes/origin/master", origin);
+
+        let mirror = MirrorSection {
+            retries: 0,
+            contact: None,
+        };
+        let mut failed = vec![];
+        let result = sync_crates_files(
+            dir.path(),
+            &mirror,
+            &crates_section("http://127.0.0.1:1"),
+            false,
+            false,
+            &HeaderValue::from_static("panamax-test"),
+            &mut failed,
+        );
+        assert!(matches!(result, Err(SyncError::FailedDownloads(1))));
+        assert_eq!(failed[0].url, "se/rd/serde");
+    }
+
+    #[test]
+    fn rebuilds_master_after_history_rewrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = index_repo(dir.path());
+        let crates = crates_section("https://crates.io/api/v1/crates");
+
+        let old_origin = commit(&repo, &[("config.json", "{}\n"), SERDE], "Old", &[], None);
+        set_ref(&repo, "refs/remotes/origin/master", old_origin);
+        merge_crates_repo(dir.path(), &crates, &OPTIONS, false, &Overlay::default()).unwrap();
+
+        // Upstream squashed its history into an unrelated commit.
+        let new_origin = commit(&repo, &[("config.json", "{}\n"), SERDE], "New", &[], None);
+        set_ref(&repo, "refs/remotes/origin/master", new_origin);
+        merge_crates_repo(dir.path(), &crates, &OPTIONS, true, &Overlay::default()).unwrap();
+
+        let master = repo.refname_to_id("refs/heads/master").unwrap();
+        assert!(repo.graph_descendant_of(master, new_origin).unwrap());
+        assert!(!repo.graph_descendant_of(master, old_origin).unwrap());
+        let master_tree = repo.find_commit(master).unwrap().tree().unwrap();
+        let config_json = master_tree.get_name("config.json").unwrap();
+        let config_json = repo.find_blob(config_json.id()).unwrap();
+        assert_eq!(config_json.content(), DEFAULT_CONFIG_JSON_CONTENT);
+    }
+
+    #[test]
+    fn squashes_past_commit_count_or_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = index_repo(dir.path());
+        let now = chrono::Utc::now().timestamp();
+
+        let snapshot = commit(
+            &repo,
+            &[SERDE],
+            "Snapshot of crates.io-index at 1234",
+            &[],
+            Some(now),
+        );
+        let mut tip = snapshot;
+        for _ in 0..3 {
+            let parent = repo.find_commit(tip).unwrap();
+            tip = commit(
+                &repo,
+                &[SERDE],
+                "Update to crates.io-index at 5678",
+                &[&parent],
+                Some(now),
+            );
+        }
+        set_ref(&repo, "refs/heads/master", tip);
+        let master = repo.find_reference("refs/heads/master").unwrap();
+        assert!(!squash_threshold_reached(&master, 3, 30).unwrap());
+        assert!(squash_threshold_reached(&master, 2, 30).unwrap());
+
+        let old = now - 31 * 24 * 60 * 60;
+        let old_snapshot = commit(
+            &repo,
+            &[SERDE],
+            "Snapshot of crates.io-index at 1234",
+            &[],
+            Some(old),
+        );
+        set_ref(&repo, "refs/heads/master", old_snapshot);
+        let master = repo.find_reference("refs/heads/master").unwrap();
+        assert!(squash_threshold_reached(&master, 100, 30).unwrap());
+        assert!(!squash_threshold_reached(&master, 100, 40).unwrap());
+
+        // A master from before squash_index was enabled has no snapshot at the bottom.
+        let unsquashed = commit(&repo, &[SERDE], "Initial", &[], Some(now));
+        set_ref(&repo, "refs/heads/master", unsquashed);
+        let master = repo.find_reference("refs/heads/master").unwrap();
+        assert!(squash_threshold_reached(&master, 100, 30).unwrap());
+    }
+
+    #[test]
+    fn finds_current_squashed_master_by_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = index_repo(dir.path());
+        let origin = commit(&repo, &[SERDE], "Upstream", &[], None);
+        set_ref(&repo, "refs/remotes/origin/master", origin);
+        let origin_master = repo.find_reference("refs/remotes/origin/master").unwrap();
+        let origin_commit = repo.find_commit(origin).unwrap();
+
+        let snapshot = commit(
+            &repo,
+            &[SERDE],
+            &snapshot_message(&origin_commit),
+            &[],
+            None,
+        );
+        let config = commit(
+            &repo,
+            &[SERDE],
+            "Update config.json and local overlay",
+            &[&repo.find_commit(snapshot).unwrap()],
+            None,
+        );
+        set_ref(&repo, "refs/heads/master", config);
+        let master = repo.find_reference("refs/heads/master").unwrap();
+        assert!(is_squashed_master_current(&origin_master, &master).unwrap());
+
+        let other = commit(
+            &repo,
+            &[SERDE],
+            "Snapshot of crates.io-index at 1234",
+            &[],
+            None,
+        );
+        set_ref(&repo, "refs/heads/master", other);
+        let master = repo.find_reference("refs/heads/master").unwrap();
+        assert!(!is_squashed_master_current(&origin_master, &master).unwrap());
+    }
+}
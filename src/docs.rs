@@ -0,0 +1,268 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use console::style;
+use reqwest::header::HeaderValue;
+use semver::Version;
+
+use crate::crates::{latest_crate_version, SyncError};
+use crate::download::{download, DownloadError, FailedDownload};
+use crate::mirror::{DocsSection, MirrorSection, SyncReport};
+use crate::safe_path::{is_valid_crate_name, is_valid_crate_version};
+
+/// Largest total size of an extracted documentation archive, in bytes.
+static MAX_EXTRACTED_SIZE: u64 = 1024 * 1024 * 1024;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum DocsError {
+        Io(err: io::Error) {
+            from()
+        }
+        Download(err: DownloadError) {
+            from()
+        }
+        Index(err: SyncError) {
+            from()
+        }
+        Zip(err: zip::result::ZipError) {
+            from()
+        }
+        BadEntry(entry: String) {
+            display("`{}` is not a crate name, or a crate name and version like `serde@1.0.0`.", entry)
+        }
+        NotInIndex(name: String) {
+            display("`{}` has no versions in the mirror's crates.io-index.", name)
+        }
+        NoDocs(name: String, vers: String) {
+            display("No documentation was found for {} {}.", name, vers)
+        }
+        TooLarge {
+            display("The documentation archive is larger than {} bytes when extracted.", MAX_EXTRACTED_SIZE)
+        }
+    }
+}
+
+/// Parse an allowlist entry, `name` or `name@version`.
+fn parse_entry(entry: &str) -> Option<(&str, Option<&str>)> {
+    let mut parts = entry.splitn(2, '@');
+    let name = parts.next()?.trim();
+    let vers = parts.next().map(|v| v.trim());
+    if !is_valid_crate_name(name) || !vers.is_none_or(is_valid_crate_version) {
+        return None;
+    }
+    Some((name, vers))
+}
+
+/// Copy a directory tree.
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let to = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to)?;
+        } else {
+            fs::copy(entry.path(), to)?;
+        }
+    }
+    Ok(())
+}
+
+/// Extract a docs.rs documentation archive into a directory.
+///
+/// Entries with `..` or absolute paths are skipped, and extraction stops once the files add up
+/// to more than MAX_EXTRACTED_SIZE.
+fn extract_zip(zip_path: &Path, to: &Path) -> Result<(), DocsError> {
+    let mut archive = zip::ZipArchive::new(File::open(zip_path)?)?;
+    let mut remaining = MAX_EXTRACTED_SIZE;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let out_path = match file.enclosed_name() {
+            Some(name) => to.join(name),
+            None => {
+                eprintln!(
+                    "Skipping {:?} in {:?}, it's outside of the archive",
+                    file.name(),
+                    zip_path
+                );
+                continue;
+            }
+        };
+        if file.is_dir() {
+            fs::create_dir_all(&out_path)?;
+        } else {
+            if let Some(parent) = out_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // The sizes in the archive can't be trusted, so count what's actually written.
+            let written = io::copy(
+                &mut (&mut file).take(remaining + 1),
+                &mut File::create(&out_path)?,
+            )?;
+            if written > remaining {
+                return Err(DocsError::TooLarge);
+            }
+            remaining -= written;
+        }
+    }
+    Ok(())
+}
+
+/// Mirror one crate version's documentation into `docs/<name>/<vers>/`.
+///
+/// The drop folder is checked first, then docs.rs, if downloading is enabled.
+fn sync_one_crate_docs(
+    path: &Path,
+    mirror: &MirrorSection,
+    docs: &DocsSection,
+    name: &str,
+    vers: &str,
+    user_agent: &HeaderValue,
+) -> Result<(), DocsError> {
+    let crate_dir = path.join("docs").join(name);
+    let docs_dir = crate_dir.join(vers);
+    if docs_dir.exists() {
+        return Ok(());
+    }
+    // Build the docs next to their final place, so a failed sync never leaves them half done.
+    let part_dir = crate_dir.join(format!(".{}.part", vers));
+    if part_dir.exists() {
+        fs::remove_dir_all(&part_dir)?;
+    }
+
+    if let Some(drop_dir) = &docs.drop_dir {
+        let drop_docs = path.join(drop_dir).join(name).join(vers);
+        if drop_docs.is_dir() {
+            copy_dir(&drop_docs, &part_dir)?;
+            fs::rename(&part_dir, &docs_dir)?;
+            return Ok(());
+        }
+    }
+
+    if !docs.download.unwrap_or(true) {
+        return Err(DocsError::NoDocs(name.to_string(), vers.to_string()));
+    }
+
+    let source = docs.source.as_deref().unwrap_or("https://docs.rs");
+    let url = format!("{}/crate/{}/{}/download", source, name, vers);
+    let zip_path = crate_dir.join(format!(".{}.zip", vers));
+    download(&url, &zip_path, None, mirror.retries, false, user_agent)?;
+    if let Err(e) = extract_zip(&zip_path, &part_dir) {
+        // The download would reuse a leftover archive, so remove it to fetch a fresh one next time.
+        let _ = fs::remove_file(&zip_path);
+        let _ = fs::remove_dir_all(&part_dir);
+        return Err(e);
+    }
+    fs::rename(&part_dir, &docs_dir)?;
+    fs::remove_file(&zip_path)?;
+    Ok(())
+}
+
+/// Resolve an allowlist entry to a crate name and version.
+fn resolve_entry(path: &Path, entry: &str) -> Result<(String, String), DocsError> {
+    let (name, vers) = parse_entry(entry).ok_or_else(|| DocsError::BadEntry(entry.to_string()))?;
+    let vers = match vers {
+        Some(vers) => vers.to_string(),
+        None => latest_crate_version(path, name)?
+            .ok_or_else(|| DocsError::NotInIndex(name.to_string()))?,
+    };
+    Ok((name.to_string(), vers))
+}
+
+/// Sync documentation for the allowlisted crates.
+pub fn sync(
+    path: &Path,
+    mirror: &MirrorSection,
+    docs: &DocsSection,
+    user_agent: &HeaderValue,
+    report: &mut SyncReport,
+) {
+    eprintln!("{}", style("Syncing crate documentation...").bold());
+
+    let mut failed = vec![];
+    for entry in &docs.crates {
+        let result = resolve_entry(path, entry).and_then(|(name, vers)| {
+            sync_one_crate_docs(path, mirror, docs, &name, &vers, user_agent)
+        });
+        if let Err(e) = result {
+            eprintln!("Syncing documentation for {} failed: {}", entry, e);
+            failed.push(FailedDownload {
+                url: entry.clone(),
+                error: e.to_string(),
+            });
+        }
+    }
+
+    if !failed.is_empty() {
+        report.add_failure(
+            "docs",
+            "docs",
+            failed.len(),
+            format!("{} crates' documentation failed to sync", failed.len()),
+            failed,
+        );
+    }
+
+    eprintln!("{}", style("Syncing crate documentation complete!").bold());
+}
+
+/// The newest version of a crate with documentation in the mirror, for `/docs/<name>/latest/`.
+pub fn latest_docs_version(path: &Path, name: &str) -> Option<String> {
+    fs::read_dir(path.join("docs").join(name))
+        .ok()?
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|vers| Version::parse(&vers).ok())
+        .max()
+        .map(|v| v.to_string())
+}
+
+/// Find the page to open for `/docs/<name>/<vers>/`: rustdoc puts it in a directory named
+/// after the crate, with dashes replaced by underscores.
+pub fn docs_index_page(path: &Path, name: &str, vers: &str) -> Option<PathBuf> {
+    let docs_dir = path.join("docs").join(name).join(vers);
+    let page = Path::new(&name.replace('-', "_")).join("index.html");
+    if docs_dir.join(&page).is_file() {
+        Some(page)
+    } else if docs_dir.join("index.html").is_file() {
+        Some(PathBuf::from("index.html"))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn parses_entries() {
+        assert_eq!(parse_entry("serde"), Some(("serde", None)));
+        assert_eq!(parse_entry("serde@1.0.0"), Some(("serde", Some("1.0.0"))));
+        assert_eq!(parse_entry("../serde"), None);
+        assert_eq!(parse_entry("serde@../.."), None);
+        assert_eq!(parse_entry("@1.0.0"), None);
+    }
+
+    #[test]
+    fn skips_entries_outside_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let zip_path = dir.path().join("docs.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        let options = zip::write::FileOptions::default();
+        for name in &["serde/index.html", "../escaped.html", "/absolute.html"] {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(b"<html></html>").unwrap();
+        }
+        zip.finish().unwrap();
+
+        let to = dir.path().join("out").join("docs");
+        extract_zip(&zip_path, &to).unwrap();
+        assert!(to.join("serde").join("index.html").is_file());
+        assert!(!dir.path().join("out").join("escaped.html").exists());
+        assert!(!to.join("absolute.html").exists());
+    }
+}
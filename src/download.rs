@@ -1,5 +1,6 @@
 use reqwest::header::{HeaderValue, USER_AGENT};
 use reqwest::Client;
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 use std::fs::File;
 use std::io::{Read, Write};
@@ -20,6 +21,22 @@ quick_error! {
     }
 }
 
+/// A download that failed during a sync, kept for the sync report.
+#[derive(Debug, Serialize)]
+pub struct FailedDownload {
+    pub url: String,
+    pub error: String,
+}
+
+impl FailedDownload {
+    pub fn new(url: &str, error: &DownloadError) -> FailedDownload {
+        FailedDownload {
+            url: url.to_string(),
+            error: format!("{:?}", error),
+        }
+    }
+}
+
 thread_local!(static CLIENT: Client = Client::new());
 
 /// Download a URL and return it as a string.
@@ -71,6 +88,14 @@ pub fn create_file_create_dir(path: &Path) -> Result<File, DownloadError> {
     Ok(file_res?)
 }
 
+/// Calculate the sha256 hash of a file, as a hex string.
+pub fn sha256_file(path: &Path) -> Result<String, DownloadError> {
+    let mut f = File::open(path)?;
+    let mut sha256 = Sha256::new();
+    io::copy(&mut f, &mut sha256)?;
+    Ok(format!("{:x}", sha256.result()))
+}
+
 pub fn move_if_exists(from: &Path, to: &Path) -> Result<(), DownloadError> {
     if from.exists() {
         fs::rename(from, to)?;
@@ -1,128 +1,156 @@
 // substantial portion from `cargo-cacher:/src/git.rs`
 // https://github.com/ChrisMacNaughton/cargo-cacher
 
-// use std::ascii::AsciiExt;
-use std::io;
-use std::io::prelude::*;
-use std::process::{Command, Stdio};
-use std::{collections::HashMap, path::Path};
-
-// Iron Stuff
-use iron::headers::ContentType;
-use iron::mime::{Mime, SubLevel, TopLevel};
-use iron::prelude::*;
-use iron::response::BodyReader;
-use iron::status::{self, Status};
-
-pub fn git(req: &mut Request, path: &Path) -> IronResult<Response> {
-    let repo_path = path.join("crates.io-index");
-
-    eprintln!("path: {:?}", &repo_path);
-    eprintln!("Raw GIT request: {:?}", req);
-    // let content_type: &str = match req.headers.get::<ContentType>() {
-    //     Some(s) => {
-    //         s.get_param("Content-Type")
-    //             .map(|s| s.as_str())
-    //             .unwrap_or("")
-    //     }
-    //     None => "",
-    // };
-
-    let content_type: String = match req.headers.get() {
-        Some(&ContentType(Mime(TopLevel::Application, SubLevel::Ext(ref s), _))) => {
-            format!("application/{}", s)
-        }
-        _ => "".into(),
-    };
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::Stdio;
+
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio_util::io::ReaderStream;
+
+use crate::serve::text_response;
+
+/// Largest request body passed on to git. Fetch negotiations are much smaller than this.
+static MAX_REQUEST_SIZE: usize = 10 * 1024 * 1024;
+
+/// Get a request header as a string, or an empty string if it's missing.
+fn header_str(req: &Request<Body>, name: &str) -> String {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_string()
+}
+
+pub async fn git(
+    req: Request<Body>,
+    repo_path: &Path,
+    url_prefix: &str,
+    remote_addr: Option<SocketAddr>,
+) -> Response<Body> {
+    let path_info = req
+        .uri()
+        .path()
+        .strip_prefix(url_prefix)
+        .unwrap_or("")
+        .to_string();
+    let method = req.method().as_str().to_string();
+    let query_string = req.uri().query().unwrap_or("").to_string();
+    let content_type = header_str(&req, "Content-Type");
+    let content_encoding = header_str(&req, "Content-Encoding");
+    let git_protocol = header_str(&req, "Git-Protocol");
+    let content_length = header_str(&req, "Content-Length");
+    let mut body = req.into_body();
 
-    let path_info = if req.url.path().join("/").starts_with("/") {
-        req.url.path().join("/").to_string()
-    } else {
-        format!("/{}", req.url.path().join("/"))
-    };
-    let path_info = path_info.replace("/index/", "/");
-    eprintln!("path_info: {}", path_info);
-    let method = format!("{:?}", req.method).to_ascii_uppercase();
-    let query_string = req.url.query().unwrap_or("");
-    let remote_addr = req.remote_addr.to_string();
     let mut cmd = Command::new("git");
     cmd.arg("http-backend");
     // Required environment variables
     cmd.env("REQUEST_METHOD", method);
-    cmd.env("GIT_PROJECT_ROOT", &repo_path);
+    cmd.env("GIT_PROJECT_ROOT", repo_path);
     cmd.env("PATH_INFO", path_info);
 
     cmd.env("REMOTE_USER", "");
-    cmd.env("REMOTE_ADDR", remote_addr);
+    cmd.env(
+        "REMOTE_ADDR",
+        remote_addr.map(|a| a.ip().to_string()).unwrap_or_default(),
+    );
     cmd.env("QUERY_STRING", query_string);
     cmd.env("CONTENT_TYPE", content_type);
+    // Without a length, git reads the body until stdin is closed.
+    if !content_length.is_empty() {
+        cmd.env("CONTENT_LENGTH", content_length);
+    }
+    // Lets git decompress gzipped requests itself.
+    cmd.env("HTTP_CONTENT_ENCODING", content_encoding);
+    cmd.env("HTTP_GIT_PROTOCOL", git_protocol);
     cmd.stderr(Stdio::inherit())
         .stdout(Stdio::piped())
         .stdin(Stdio::piped());
     let mut p = match cmd.spawn() {
         Ok(s) => s,
         Err(_) => {
-            return Ok(Response::with((
-                status::InternalServerError,
-                "Failed to run git",
-            )))
+            return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to run git");
         }
     };
-    let _ = io::copy(&mut req.body, &mut p.stdin.take().unwrap());
-    // Parse the headers coming out, and the pass through the rest of the
-    // process back down the stack.
-    //
-    // Note that we have to be careful to not drop the process which will wait
-    // for the process to exit (and we haven't read stdout)
-
-    let out = match p.wait_with_output() {
-        Ok(s) => s,
-        _ => {
-            return Ok(Response::with((
-                status::InternalServerError,
-                "Failed to run git",
-            )))
+
+    // Stream the request body in concurrently. Writing it all before reading stdout
+    // can deadlock, if git fills the stdout pipe before it's done reading stdin.
+    let mut stdin = p.stdin.take().expect("Child stdin should be piped");
+    tokio::spawn(async move {
+        let mut written = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    eprintln!("Reading git request body failed: {:?}", e);
+                    break;
+                }
+            };
+            written += chunk.len();
+            if written > MAX_REQUEST_SIZE {
+                // Closing stdin early makes git fail the request.
+                eprintln!("Git request body is larger than {} bytes", MAX_REQUEST_SIZE);
+                break;
+            }
+            if stdin.write_all(&chunk).await.is_err() {
+                break;
+            }
         }
-    };
-    // debug!("Out: {}", String::from_utf8_lossy(out));
-    let mut rdr = io::BufReader::new(io::Cursor::new(out.stdout));
-
-    let mut headers = HashMap::new();
-    for line in rdr.by_ref().lines() {
-        let line = match line {
-            Ok(s) => s,
-            _ => break,
-        };
-        if line == "" || line == "\r" {
+    });
+
+    let mut stdout = BufReader::new(p.stdout.take().expect("Child stdout should be piped"));
+
+    // Reap the process once it's done.
+    tokio::spawn(async move {
+        let _ = p.wait().await;
+    });
+
+    // Parse the CGI headers coming out, and pass the rest of the output
+    // through as the response body, as it's produced.
+    let mut response = Response::new(Body::empty());
+    let mut headers_done = false;
+    loop {
+        let mut line = String::new();
+        match stdout.read_line(&mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            headers_done = true;
             break;
         }
 
         let mut parts = line.splitn(2, ':');
-        let key = parts.next().unwrap();
-        let value = parts.next().unwrap();
-        let value = &value[1..];
-        headers
-            .entry(key.to_string())
-            .or_insert(Vec::new())
-            .push(value.to_string());
+        let key = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+
+        if key.eq_ignore_ascii_case("Status") {
+            // e.g. "Status: 404 Not Found"
+            if let Some(status) = value
+                .split(' ')
+                .next()
+                .and_then(|c| StatusCode::from_bytes(c.as_bytes()).ok())
+            {
+                *response.status_mut() = status;
+            }
+        } else if let (Ok(key), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            response.headers_mut().append(key, value);
+        }
     }
 
-    // let mut buf = Vec::new();
-    // let _ = rdr.read_to_end(&mut buf);
-
-    let content_type = headers
-        .get("Content-Type")
-        .unwrap_or(&vec![])
-        .first()
-        .unwrap_or(&"".to_string())
-        .clone()
-        .split("/")
-        .last()
-        .unwrap_or("")
-        .into();
-    Ok(Response::with((
-        Status::Ok,
-        BodyReader(rdr),
-        Mime(TopLevel::Application, SubLevel::Ext(content_type), vec![]),
-    )))
+    // git exited (or crashed) before finishing its headers, so there's no response to pass on.
+    if !headers_done {
+        return text_response(StatusCode::INTERNAL_SERVER_ERROR, "git http-backend failed");
+    }
+
+    *response.body_mut() = Body::wrap_stream(ReaderStream::new(stdout));
+    response
 }
@@ -0,0 +1,156 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// How many syncs the journal keeps. Older entries are dropped when a sync is added, except
+/// the last successful one, so the journal stays small enough to read on every request.
+static MAX_ENTRIES: usize = 100;
+
+/// One sync run, as recorded in `mirror-sync-journal.jsonl`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncJournalEntry {
+    /// When the sync started, in seconds since the Unix epoch.
+    pub started: u64,
+    /// When the sync finished, in seconds since the Unix epoch.
+    pub finished: u64,
+    /// Whether everything was synced without errors.
+    pub success: bool,
+    /// Number of failed downloads.
+    pub failures: usize,
+}
+
+impl SyncJournalEntry {
+    /// Describe a sync that started at `started` and just finished.
+    pub fn finished_now(started: SystemTime, success: bool, failures: usize) -> SyncJournalEntry {
+        SyncJournalEntry {
+            started: unix_time(started),
+            finished: unix_time(SystemTime::now()),
+            success,
+            failures,
+        }
+    }
+}
+
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Append an entry to the mirror's sync journal, one JSON object per line.
+pub fn append(mirror_path: &Path, entry: &SyncJournalEntry) -> io::Result<()> {
+    let mut entries = read(mirror_path)?;
+    entries.push(entry.clone());
+    let entries = trim(entries);
+
+    let path = mirror_path.join("mirror-sync-journal.jsonl");
+    let tmp_path = path.with_extension("jsonl.part");
+    let mut f = File::create(&tmp_path)?;
+    for entry in entries {
+        writeln!(f, "{}", serde_json::to_string(&entry)?)?;
+    }
+    drop(f);
+    fs::rename(&tmp_path, &path)
+}
+
+/// Keep the last `MAX_ENTRIES` entries, plus the last successful one if it's older.
+fn trim(mut entries: Vec<SyncJournalEntry>) -> Vec<SyncJournalEntry> {
+    if entries.len() <= MAX_ENTRIES {
+        return entries;
+    }
+    let kept = entries.split_off(entries.len() - MAX_ENTRIES);
+    match entries.into_iter().rev().find(|e| e.success) {
+        Some(last_success) if !kept.iter().any(|e| e.success) => {
+            std::iter::once(last_success).chain(kept).collect()
+        }
+        _ => kept,
+    }
+}
+
+/// Read every entry of the mirror's sync journal, oldest first.
+///
+/// Lines that can't be parsed, like one cut short by a crash, are skipped.
+pub fn read(mirror_path: &Path) -> io::Result<Vec<SyncJournalEntry>> {
+    let content = match fs::read_to_string(mirror_path.join("mirror-sync-journal.jsonl")) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(finished: u64, success: bool) -> SyncJournalEntry {
+        SyncJournalEntry {
+            started: finished.saturating_sub(10),
+            finished,
+            success,
+            failures: if success { 0 } else { 1 },
+        }
+    }
+
+    #[test]
+    fn appends_and_reads_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(read(dir.path()).unwrap().is_empty());
+
+        append(dir.path(), &entry(100, true)).unwrap();
+        append(dir.path(), &entry(200, false)).unwrap();
+        let entries = read(dir.path()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].finished, 100);
+        assert!(entries[0].success);
+        assert_eq!(entries[1].finished, 200);
+        assert_eq!(entries[1].failures, 1);
+    }
+
+    #[test]
+    fn skips_unreadable_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let line = serde_json::to_string(&entry(100, true)).unwrap();
+        fs::write(
+            dir.path().join("mirror-sync-journal.jsonl"),
+            format!("{}\n{{\"started\": 1\n", line),
+        )
+        .unwrap();
+        let entries = read(dir.path()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].finished, 100);
+    }
+
+    #[test]
+    fn keeps_the_last_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..MAX_ENTRIES as u64 + 5 {
+            append(dir.path(), &entry(i, true)).unwrap();
+        }
+        let entries = read(dir.path()).unwrap();
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        assert_eq!(entries[0].finished, 5);
+        assert_eq!(entries.last().unwrap().finished, MAX_ENTRIES as u64 + 4);
+    }
+
+    #[test]
+    fn keeps_the_last_success() {
+        let dir = tempfile::tempdir().unwrap();
+        append(dir.path(), &entry(1, true)).unwrap();
+        append(dir.path(), &entry(2, true)).unwrap();
+        for i in 0..MAX_ENTRIES as u64 + 5 {
+            append(dir.path(), &entry(10 + i, false)).unwrap();
+        }
+        let entries = read(dir.path()).unwrap();
+        assert_eq!(entries.len(), MAX_ENTRIES + 1);
+        assert_eq!(entries[0].finished, 2);
+        assert!(entries[0].success);
+        assert!(entries[1..].iter().all(|e| !e.success));
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use semver::Version;
+use serde_derive::Serialize;
+
+use crate::journal::{self, SyncJournalEntry};
+use crate::metrics::index_commit_time;
+use crate::rustup::get_channel_history;
+
+/// One toolchain release available in the mirror.
+#[derive(Serialize, Debug)]
+pub struct Toolchain {
+    pub date: String,
+    /// e.g. "1.41.0". Nightly and beta releases have no version.
+    pub version: Option<String>,
+}
+
+/// A `rustup-init` download for one platform.
+#[derive(Serialize, Debug)]
+pub struct RustupInit {
+    pub platform: String,
+    pub url: String,
+}
+
+/// Everything shown on the mirror's landing page.
+#[derive(Serialize, Debug)]
+pub struct MirrorStatus {
+    pub base_url: String,
+    pub last_sync: Option<SyncJournalEntry>,
+    pub last_successful_sync: Option<u64>,
+    pub index_commit_time: Option<u64>,
+    /// Toolchains per channel, newest first.
+    pub toolchains: BTreeMap<String, Vec<Toolchain>>,
+    pub rustup_init: Vec<RustupInit>,
+    pub cargo_config: String,
+    pub rustup_env: String,
+}
+
+impl MirrorStatus {
+    /// Gather the mirror's status. `base_url` is where clients reach the server.
+    pub fn load(path: &Path, base_url: &str) -> MirrorStatus {
+        let journal = journal::read(path).unwrap_or_default();
+
+        let base_url = base_url.trim_end_matches('/');
+        let mut toolchains = BTreeMap::new();
+        for channel in &["stable", "beta", "nightly"] {
+            if let Ok(history) = get_channel_history(path, channel) {
+                let mut releases: Vec<Toolchain> = history
+                    .versions
+                    .iter()
+                    .map(|(date, files)| Toolchain {
+                        date: date.clone(),
+                        // Every `rust-<version>-` file of a release has the same version.
+                        version: files.iter().filter_map(|f| release_version(f)).min(),
+                    })
+                    .collect();
+                releases.sort_by(|a, b| b.date.cmp(&a.date));
+                if !releases.is_empty() {
+                    toolchains.insert(channel.to_string(), releases);
+                }
+            }
+        }
+
+        MirrorStatus {
+            base_url: base_url.to_string(),
+            last_sync: journal.last().cloned(),
+            last_successful_sync: journal.iter().rev().find(|e| e.success).map(|e| e.finished),
+            index_commit_time: index_commit_time(path),
+            toolchains,
+            rustup_init: rustup_init_downloads(path, base_url),
+            cargo_config: format!(
+                "[source.panamax]\nregistry = \"{}/index\"\n\n[source.crates-io]\nreplace-with = \"panamax\"\n",
+                base_url
+            ),
+            rustup_env: format!(
+                "export RUSTUP_DIST_SERVER={}\nexport RUSTUP_UPDATE_ROOT={}/rustup\n",
+                base_url, base_url
+            ),
+        }
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut html = String::new();
+        html.push_str(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Panamax</title>\n</head>\n<body>\n",
+        );
+        html.push_str("<h1>Panamax mirror</h1>\n");
+
+        html.push_str("<h2>Status</h2>\n<ul>\n");
+        match &self.last_sync {
+            Some(last_sync) => html.push_str(&format!(
+                "<li>Last sync: {} ({})</li>\n",
+                format_time(last_sync.finished),
+                if last_sync.success {
+                    "succeeded".to_string()
+                } else {
+                    format!("failed, {} failed downloads", last_sync.failures)
+                }
+            )),
+            None => html.push_str("<li>Last sync: unknown</li>\n"),
+        }
+        if let Some(last_successful_sync) = self.last_successful_sync {
+            html.push_str(&format!(
+                "<li>Last successful sync: {}</li>\n",
+                format_time(last_successful_sync)
+            ));
+        }
+        if let Some(index_commit_time) = self.index_commit_time {
+            html.push_str(&format!(
+                "<li>Newest crates.io-index commit: {}</li>\n",
+                format_time(index_commit_time)
+            ));
+        }
+        html.push_str("</ul>\n");
+
+        html.push_str("<h2>Toolchains</h2>\n");
+        if self.toolchains.is_empty() {
+            html.push_str("<p>No toolchains are mirrored.</p>\n");
+        }
+        for (channel, releases) in &self.toolchains {
+            html.push_str(&format!("<h3>{}</h3>\n<ul>\n", escape(channel)));
+            for release in releases {
+                match &release.version {
+                    Some(version) => html.push_str(&format!(
+                        "<li>{} ({})</li>\n",
+                        escape(version),
+                        escape(&release.date)
+                    )),
+                    None => html.push_str(&format!("<li>{}</li>\n", escape(&release.date))),
+                }
+            }
+            html.push_str("</ul>\n");
+        }
+
+        html.push_str("<h2>Installing rustup</h2>\n");
+        if self.rustup_init.is_empty() {
+            html.push_str("<p>No rustup-init files are mirrored.</p>\n");
+        } else {
+            html.push_str("<ul>\n");
+            for init in &self.rustup_init {
+                html.push_str(&format!(
+                    "<li><a href=\"{}\">{}</a></li>\n",
+                    escape(&init.url),
+                    escape(&init.platform)
+                ));
+            }
+            html.push_str("</ul>\n");
+        }
+
+        html.push_str("<h2>Configuring rustup</h2>\n");
+        html.push_str("<p>Add this to your <code>.bashrc</code> (or equivalent):</p>\n");
+        html.push_str(&format!("<pre>{}</pre>\n", escape(&self.rustup_env)));
+
+        html.push_str("<h2>Configuring cargo</h2>\n");
+        html.push_str("<p>Add this to <code>~/.cargo/config</code>:</p>\n");
+        html.push_str(&format!("<pre>{}</pre>\n", escape(&self.cargo_config)));
+
+        html.push_str("<p>This page is also available as <a href=\"/mirror.json\">JSON</a>.</p>\n");
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+/// Get the Rust version from a release file name, like `rust-1.41.0-x86_64-unknown-linux-gnu.tar.xz`.
+///
+/// Component files, like `rust-std-1.41.0-...`, and nightly or beta files have no version.
+fn release_version(file: &str) -> Option<String> {
+    let file_name = file.rsplit('/').next()?;
+    let version = file_name.strip_prefix("rust-")?.split('-').next()?;
+    Version::parse(version).ok().map(|v| v.to_string())
+}
+
+/// Find the platforms with a `rustup-init` in the mirror.
+fn rustup_init_downloads(path: &Path, base_url: &str) -> Vec<RustupInit> {
+    let mut downloads = vec![];
+    let entries = match fs::read_dir(path.join("rustup").join("dist")) {
+        Ok(entries) => entries,
+        Err(_) => return downloads,
+    };
+    for entry in entries.flatten() {
+        let platform = entry.file_name().to_string_lossy().into_owned();
+        for file_name in &["rustup-init", "rustup-init.exe"] {
+            if entry.path().join(file_name).is_file() {
+                downloads.push(RustupInit {
+                    url: format!("{}/rustup/dist/{}/{}", base_url, platform, file_name),
+                    platform: platform.clone(),
+                });
+            }
+        }
+    }
+    downloads.sort_by(|a, b| a.platform.cmp(&b.platform));
+    downloads
+}
+
+fn format_time(unix_time: u64) -> String {
+    httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(unix_time))
+}
+
+/// Escape text for HTML.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gets_release_versions() {
+        assert_eq!(
+            release_version("dist/2020-01-30/rust-1.41.0-x86_64-unknown-linux-gnu.tar.xz"),
+            Some("1.41.0".to_string())
+        );
+        assert_eq!(
+            release_version("rust-1.42.0-beta.1-x86_64-unknown-linux-gnu.tar.xz"),
+            Some("1.42.0".to_string())
+        );
+        for file in &[
+            "rust-std-1.41.0-x86_64-unknown-linux-gnu.tar.xz",
+            "rust-src-1.41.0.tar.xz",
+            "rust-docs-1.41.0-x86_64-unknown-linux-gnu.tar.xz",
+            "rust-nightly-x86_64-unknown-linux-gnu.tar.xz",
+            "cargo-0.42.0-x86_64-unknown-linux-gnu.tar.xz",
+            "rust-",
+        ] {
+            assert_eq!(release_version(file), None, "{:?}", file);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use hyper::server::conn::Http;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, watch};
+use tokio_rustls::TlsAcceptor;
+
+use crate::mirror::MirrorError;
+
+/// How long a client gets to finish the TLS handshake.
+static TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The longest to wait before accepting again after an error, like running out of file descriptors.
+static MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// An address for the server to listen on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BindAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl BindAddr {
+    /// Parse a `bind` entry: either an IP address and port, or `unix:` followed by a socket path.
+    pub fn parse(addr: &str) -> Result<BindAddr, MirrorError> {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(MirrorError::BadBindAddress(addr.to_string()));
+            }
+            return Ok(BindAddr::Unix(PathBuf::from(path)));
+        }
+        addr.parse()
+            .map(BindAddr::Tcp)
+            .map_err(|_| MirrorError::BadBindAddress(addr.to_string()))
+    }
+}
+
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindAddr::Tcp(addr) => write!(f, "{}", addr),
+            BindAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A socket accepting connections for the server.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// A connection accepted from a `Listener`.
+enum Connection {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Listener {
+    pub async fn bind(addr: &BindAddr) -> io::Result<Listener> {
+        match addr {
+            BindAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            BindAddr::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+
+                // A socket left behind by a server that didn't shut down cleanly
+                // would make the bind fail, so clear it away first.
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.clone()))
+            }
+            #[cfg(not(unix))]
+            BindAddr::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Unix domain sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    async fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, remote_addr) = listener.accept().await?;
+                Ok(Connection::Tcp(stream, remote_addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(Connection::Unix(stream))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Accept connections until shutdown, serving each one on its own task.
+///
+/// `make_service` gets the client's address, which is unknown for Unix domain sockets.
+/// Each connection holds a clone of `done` until it's finished.
+pub async fn accept_loop<F, S>(
+    listener: Listener,
+    tls: Option<TlsAcceptor>,
+    make_service: F,
+    keep_alive: bool,
+    mut shutdown: watch::Receiver<()>,
+    done: mpsc::Sender<()>,
+) where
+    F: Fn(Option<SocketAddr>) -> S,
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let mut backoff = None;
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.changed() => break,
+        };
+        let connection = match accepted {
+            Ok(connection) => {
+                backoff = None;
+                connection
+            }
+            // The client went away before we accepted it, which says nothing about the listener.
+            Err(e) if is_connection_error(&e) => continue,
+            Err(e) => {
+                // Errors like EMFILE would fail again straight away, so back off like hyper does.
+                let delay = backoff.map_or(Duration::from_millis(10), |d: Duration| {
+                    (d * 2).min(MAX_ACCEPT_BACKOFF)
+                });
+                backoff = Some(delay);
+                eprintln!("Accepting connection failed: {}", e);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => continue,
+                    _ = shutdown.changed() => break,
+                }
+            }
+        };
+
+        let tls = tls.clone();
+        let shutdown = shutdown.clone();
+        let done = done.clone();
+        match connection {
+            Connection::Tcp(stream, remote_addr) => {
+                let service = make_service(Some(remote_addr));
+                tokio::spawn(async move {
+                    serve_stream(stream, tls, service, keep_alive, shutdown).await;
+                    drop(done);
+                });
+            }
+            #[cfg(unix)]
+            Connection::Unix(stream) => {
+                let service = make_service(None);
+                tokio::spawn(async move {
+                    serve_stream(stream, tls, service, keep_alive, shutdown).await;
+                    drop(done);
+                });
+            }
+        }
+    }
+}
+
+/// Errors from accepting a connection that only affect that connection.
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Run the TLS handshake if needed, then serve the connection.
+///
+/// The handshake is given up on after `TLS_HANDSHAKE_TIMEOUT`, or when the server shuts down.
+async fn serve_stream<I, S>(
+    io: I,
+    tls: Option<TlsAcceptor>,
+    service: S,
+    keep_alive: bool,
+    mut shutdown: watch::Receiver<()>,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let tls = match tls {
+        Some(tls) => tls,
+        None => return serve_connection(io, service, keep_alive, shutdown).await,
+    };
+
+    let handshake = tokio::select! {
+        handshake = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(io)) => handshake,
+        _ = shutdown.changed() => return,
+    };
+    match handshake {
+        Ok(Ok(io)) => serve_connection(io, service, keep_alive, shutdown).await,
+        Ok(Err(e)) => eprintln!("TLS handshake failed: {}", e),
+        Err(_) => eprintln!("TLS handshake timed out"),
+    }
+}
+
+/// Serve requests on a connection, until the client closes it or the server shuts down.
+async fn serve_connection<I, S>(
+    io: I,
+    service: S,
+    keep_alive: bool,
+    mut shutdown: watch::Receiver<()>,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let conn = Http::new()
+        .http1_only(true)
+        .http1_keep_alive(keep_alive)
+        .serve_connection(io, service);
+    tokio::pin!(conn);
+
+    let result = tokio::select! {
+        result = conn.as_mut() => result,
+        _ = shutdown.changed() => {
+            // Let the current request finish, then close the connection.
+            conn.as_mut().graceful_shutdown();
+            conn.as_mut().await
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Connection error: {}", e);
+    }
+}
@@ -0,0 +1,89 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use fs2::FileExt;
+
+use crate::mirror::MirrorError;
+
+/// How long to sleep between attempts when waiting for another sync to finish.
+static LOCK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A lock on a mirror directory, held for the duration of a sync.
+///
+/// The lock is an OS advisory lock (`flock`, or `LockFileEx` on Windows) on a `mirror.lock`
+/// file, so it's released by the OS if the process dies. The file also holds the PID of the
+/// process holding the lock, but only for error messages. The file itself is left in place.
+pub struct MirrorLock {
+    file: File,
+}
+
+impl MirrorLock {
+    /// Acquire the lock for a mirror directory.
+    ///
+    /// If another sync holds the lock, either wait for it to be released (if `wait` is set),
+    /// or return `MirrorError::Locked`.
+    pub fn acquire(mirror_path: &Path, wait: bool) -> Result<MirrorLock, MirrorError> {
+        let path = mirror_path.join("mirror.lock");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // The running sync's PID is read from the file, so it's only truncated once locked.
+            .truncate(false)
+            .open(&path)?;
+        let mut waiting = false;
+
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => break,
+                Err(e) if e.kind() == fs2::lock_contended_error().kind() => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            let pid = read_pid(&mut file);
+            if !wait {
+                return Err(MirrorError::Locked(pid));
+            }
+            if !waiting {
+                match pid {
+                    Some(pid) => eprintln!(
+                        "Mirror is locked by another sync (PID {}), waiting for it to finish...",
+                        pid
+                    ),
+                    None => {
+                        eprintln!("Mirror is locked by another sync, waiting for it to finish...")
+                    }
+                }
+                waiting = true;
+            }
+            thread::sleep(LOCK_POLL_INTERVAL);
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", process::id())?;
+        Ok(MirrorLock { file })
+    }
+}
+
+impl Drop for MirrorLock {
+    fn drop(&mut self) {
+        // Clear the PID while the lock is still held. The lock itself is released when the
+        // file is closed.
+        if let Err(e) = self.file.set_len(0) {
+            eprintln!("Could not clear lock file: {:?}", e);
+        }
+    }
+}
+
+/// Read the PID of the process holding the lock, if it has written it yet.
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
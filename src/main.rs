@@ -1,19 +1,36 @@
 use std::path::PathBuf;
+use std::process;
 use structopt::StructOpt;
 
 #[macro_use]
 extern crate quick_error;
-#[macro_use]
-extern crate router;
 
+mod access_log;
+mod api;
+mod browse;
+mod cache;
 mod crates;
+mod docs;
 mod download;
 mod git;
+mod journal;
+mod landing;
+mod listener;
+mod lock;
+mod metrics;
 mod middleware;
 mod mirror;
+mod overlay;
 mod progress_bar;
+mod publish;
+mod pull_through;
+mod range;
 mod rustup;
+mod safe_path;
 mod serve;
+mod stats;
+mod tls;
+mod upload_pack;
 
 /// Mirror rustup and crates.io repositories, for offline Rust and cargo usage.
 #[derive(Debug, StructOpt)]
@@ -32,6 +49,23 @@ enum Panamax {
         /// Mirror directory.
         #[structopt(parse(from_os_str))]
         path: PathBuf,
+
+        /// Wait for another sync of the same mirror to finish, instead of exiting.
+        #[structopt(long = "wait")]
+        wait: bool,
+
+        /// Write a JSON report of every failed download to this file.
+        #[structopt(long = "report", parse(from_os_str))]
+        report: Option<PathBuf>,
+
+        /// Check the entire crates.io-index for missing crate files, not just recent changes.
+        #[structopt(long = "full-crates")]
+        full_crates: bool,
+
+        /// Verify the checksums of every existing crate file, re-downloading any that don't match.
+        /// This checks the entire crates.io-index, like --full-crates.
+        #[structopt(long = "verify-crates")]
+        verify_crates: bool,
     },
 
     /// Serve an existing mirror directory.
@@ -46,10 +80,20 @@ enum Panamax {
 fn main() {
     env_logger::init();
     let opt = Panamax::from_args();
-    match opt {
+    let res = match opt {
         Panamax::Init { path } => mirror::init(&path),
-        Panamax::Sync { path } => mirror::sync(&path),
+        Panamax::Sync {
+            path,
+            wait,
+            report,
+            full_crates,
+            verify_crates,
+        } => mirror::sync(&path, wait, report.as_deref(), full_crates, verify_crates),
         Panamax::Serve { path } => serve::serve(&path),
+    };
+
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        process::exit(e.exit_code());
     }
-    .unwrap();
 }
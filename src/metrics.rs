@@ -0,0 +1,353 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use git2::Repository;
+use hyper::body::HttpBody;
+use hyper::{Body, Response, StatusCode};
+
+use crate::journal::{self, unix_time};
+
+/// Upper bounds of the git request latency histogram buckets, in seconds.
+static GIT_LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// Count of observations in each of `GIT_LATENCY_BUCKETS`, not cumulative.
+    buckets: [u64; 10],
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Default)]
+struct Counters {
+    /// Requests, by route and status code.
+    requests: BTreeMap<(&'static str, u16), u64>,
+    /// Bytes served, by route.
+    bytes: BTreeMap<&'static str, u64>,
+    /// Time until git responses are fully sent, by git backend.
+    git_latency: BTreeMap<&'static str, Histogram>,
+}
+
+/// Request metrics, exposed in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<Counters>,
+}
+
+/// The route label of a request path, from its first path segment.
+pub fn route_name(path: &str) -> &'static str {
+    match path.trim_start_matches('/').split('/').next() {
+        Some("") | Some("mirror.json") => "home",
+        Some("api") => "api",
+        Some("crates") => "crates",
+        Some("browse") => "browse",
+        Some("dist") => "dist",
+        Some("docs") => "docs",
+        Some("rustup") => "rustup",
+        Some("index") => "index",
+        Some("stats") => "stats",
+        Some("metrics") => "metrics",
+        Some("private") => "private",
+        Some("registries") => "registries",
+        _ => "other",
+    }
+}
+
+/// Call `on_end` with the number of body bytes sent, once the whole body has been sent.
+///
+/// Bodies of a known size are counted right away. Streamed bodies, like files and git
+/// responses, are passed through a channel that counts them.
+pub fn measure_body<F>(res: Response<Body>, on_end: F) -> Response<Body>
+where
+    F: FnOnce(u64) + Send + 'static,
+{
+    if let Some(len) = res.body().size_hint().exact() {
+        on_end(len);
+        return res;
+    }
+
+    let (parts, mut body) = res.into_parts();
+    let (mut sender, counted) = Body::channel();
+    tokio::spawn(async move {
+        let mut bytes = 0;
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) => {
+                    let len = chunk.len() as u64;
+                    // The client went away.
+                    if sender.send_data(chunk).await.is_err() {
+                        break;
+                    }
+                    bytes += len;
+                }
+                Err(_) => {
+                    sender.abort();
+                    break;
+                }
+            }
+        }
+        on_end(bytes);
+    });
+    Response::from_parts(parts, counted)
+}
+
+impl Metrics {
+    pub fn record_request(&self, route: &'static str, status: StatusCode) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters
+            .requests
+            .entry((route, status.as_u16()))
+            .or_default() += 1;
+    }
+
+    pub fn record_bytes(&self, route: &'static str, bytes: u64) {
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .bytes
+            .entry(route)
+            .or_default() += bytes;
+    }
+
+    pub fn record_git_latency(&self, backend: &'static str, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let mut counters = self.counters.lock().unwrap();
+        let histogram = counters.git_latency.entry(backend).or_default();
+        if let Some(i) = GIT_LATENCY_BUCKETS.iter().position(|&le| seconds <= le) {
+            histogram.buckets[i] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    /// Render the request metrics, plus sync status read from the mirror directory.
+    pub fn render(&self, mirror_path: &Path) -> String {
+        let mut out = String::new();
+        self.render_requests(&mut out);
+        render_sync_status(&mut out, mirror_path);
+        out
+    }
+
+    fn render_requests(&self, out: &mut String) {
+        let counters = self.counters.lock().unwrap();
+
+        out.push_str("# HELP panamax_requests_total Requests served, by route and status code.\n");
+        out.push_str("# TYPE panamax_requests_total counter\n");
+        for ((route, status), count) in &counters.requests {
+            let _ = writeln!(
+                out,
+                "panamax_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                route, status, count
+            );
+        }
+
+        out.push_str("# HELP panamax_response_bytes_total Bytes served, by route.\n");
+        out.push_str("# TYPE panamax_response_bytes_total counter\n");
+        for (route, bytes) in &counters.bytes {
+            let _ = writeln!(
+                out,
+                "panamax_response_bytes_total{{route=\"{}\"}} {}",
+                route, bytes
+            );
+        }
+
+        out.push_str(
+            "# HELP panamax_git_response_seconds Time until git responses are fully sent.\n",
+        );
+        out.push_str("# TYPE panamax_git_response_seconds histogram\n");
+        for (backend, histogram) in &counters.git_latency {
+            let mut cumulative = 0;
+            for (le, count) in GIT_LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "panamax_git_response_seconds_bucket{{backend=\"{}\",le=\"{}\"}} {}",
+                    backend, le, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "panamax_git_response_seconds_bucket{{backend=\"{}\",le=\"+Inf\"}} {}",
+                backend, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "panamax_git_response_seconds_sum{{backend=\"{}\"}} {}",
+                backend, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "panamax_git_response_seconds_count{{backend=\"{}\"}} {}",
+                backend, histogram.count
+            );
+        }
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Render the last sync's status from the sync journal, and the age of the index.
+fn render_sync_status(out: &mut String, mirror_path: &Path) {
+    let entries = journal::read(mirror_path).unwrap_or_default();
+
+    if let Some(last) = entries.last() {
+        gauge(
+            out,
+            "panamax_last_sync_timestamp_seconds",
+            "When the last sync finished.",
+            last.finished,
+        );
+        gauge(
+            out,
+            "panamax_last_sync_success",
+            "Whether the last sync finished without errors.",
+            last.success as u64,
+        );
+        gauge(
+            out,
+            "panamax_last_sync_failures",
+            "Number of failed downloads in the last sync.",
+            last.failures as u64,
+        );
+    }
+    if let Some(last_success) = entries.iter().rev().find(|e| e.success) {
+        gauge(
+            out,
+            "panamax_last_successful_sync_timestamp_seconds",
+            "When the last sync without errors finished.",
+            last_success.finished,
+        );
+    }
+
+    if let Some(commit_time) = index_commit_time(mirror_path) {
+        let age = unix_time(SystemTime::now()).saturating_sub(commit_time);
+        gauge(
+            out,
+            "panamax_index_commit_age_seconds",
+            "Age of the newest upstream commit in the crates.io-index.",
+            age,
+        );
+    }
+}
+
+/// The commit time of the upstream crates.io-index, as last fetched.
+pub fn index_commit_time(mirror_path: &Path) -> Option<u64> {
+    let repo = Repository::open(mirror_path.join("crates.io-index")).ok()?;
+    let commit = repo
+        .find_reference("refs/remotes/origin/master")
+        .ok()?
+        .peel_to_commit()
+        .ok()?;
+    Some(commit.time().seconds().max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::SyncJournalEntry;
+    use std::sync::Arc;
+
+    #[test]
+    fn names_routes() {
+        assert_eq!(route_name("/"), "home");
+        assert_eq!(route_name("/mirror.json"), "home");
+        assert_eq!(route_name("/crates/serde/1.0.0/download"), "crates");
+        assert_eq!(route_name("/index/info/refs"), "index");
+        assert_eq!(route_name("/registries/internal/index"), "registries");
+        assert_eq!(route_name("/wp-admin"), "other");
+    }
+
+    #[test]
+    fn renders_requests() {
+        let metrics = Metrics::default();
+        metrics.record_request("crates", StatusCode::OK);
+        metrics.record_request("crates", StatusCode::OK);
+        metrics.record_request("crates", StatusCode::NOT_FOUND);
+        metrics.record_bytes("crates", 100);
+        metrics.record_bytes("crates", 50);
+        metrics.record_git_latency("native", Duration::from_millis(20));
+        metrics.record_git_latency("native", Duration::from_secs(20));
+
+        let mut out = String::new();
+        metrics.render_requests(&mut out);
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines.contains(&"panamax_requests_total{route=\"crates\",status=\"200\"} 2"));
+        assert!(lines.contains(&"panamax_requests_total{route=\"crates\",status=\"404\"} 1"));
+        assert!(lines.contains(&"panamax_response_bytes_total{route=\"crates\"} 150"));
+        // Buckets are cumulative, and slower requests than the last bucket only count in +Inf.
+        assert!(lines
+            .contains(&"panamax_git_response_seconds_bucket{backend=\"native\",le=\"0.01\"} 0"));
+        assert!(lines
+            .contains(&"panamax_git_response_seconds_bucket{backend=\"native\",le=\"0.025\"} 1"));
+        assert!(
+            lines.contains(&"panamax_git_response_seconds_bucket{backend=\"native\",le=\"10\"} 1")
+        );
+        assert!(lines
+            .contains(&"panamax_git_response_seconds_bucket{backend=\"native\",le=\"+Inf\"} 2"));
+        assert!(lines.contains(&"panamax_git_response_seconds_count{backend=\"native\"} 2"));
+    }
+
+    #[tokio::test]
+    async fn measures_streamed_bodies() {
+        let measured = Arc::new(Mutex::new(None));
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data("hello ".into()).await.unwrap();
+            sender.send_data("world".into()).await.unwrap();
+        });
+        let measured_clone = measured.clone();
+        let res = measure_body(Response::new(body), move |bytes| {
+            *measured_clone.lock().unwrap() = Some(bytes)
+        });
+        assert_eq!(*measured.lock().unwrap(), None);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, "hello world");
+        // The count is recorded right after the last chunk is passed on.
+        for _ in 0..100 {
+            if measured.lock().unwrap().is_some() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(*measured.lock().unwrap(), Some(11));
+
+        // Bodies of a known size are counted right away.
+        let measured_clone = measured.clone();
+        measure_body(Response::new(Body::from("abc")), move |bytes| {
+            *measured_clone.lock().unwrap() = Some(bytes)
+        });
+        assert_eq!(*measured.lock().unwrap(), Some(3));
+    }
+
+    #[test]
+    fn renders_sync_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut out = String::new();
+        render_sync_status(&mut out, dir.path());
+        assert_eq!(out, "");
+
+        let entry = |finished, success, failures| SyncJournalEntry {
+            started: finished - 10,
+            finished,
+            success,
+            failures,
+        };
+        journal::append(dir.path(), &entry(100, true, 0)).unwrap();
+        journal::append(dir.path(), &entry(200, false, 3)).unwrap();
+        render_sync_status(&mut out, dir.path());
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines.contains(&"panamax_last_sync_timestamp_seconds 200"));
+        assert!(lines.contains(&"panamax_last_sync_success 0"));
+        assert!(lines.contains(&"panamax_last_sync_failures 3"));
+        assert!(lines.contains(&"panamax_last_successful_sync_timestamp_seconds 100"));
+    }
+}
@@ -0,0 +1,432 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::header::{HeaderValue, AUTHORIZATION, RETRY_AFTER, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::mirror::{AuthSection, MirrorError};
+use crate::serve::text_response;
+
+/// Failed password checks allowed per user and client address in each `FAILURE_WINDOW`,
+/// before further attempts are refused without checking them.
+static MAX_FAILURES: u32 = 10;
+
+static FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Authorization headers remembered as verified. When full, the cache is emptied and
+/// passwords get checked again.
+static MAX_VERIFIED: usize = 1024;
+
+/// A user name, and the address of the client that tried it.
+type FailureKey = (String, Option<IpAddr>);
+
+/// Credentials accepted by the server.
+pub struct Auth {
+    /// htpasswd users, mapped to their password hashes.
+    users: HashMap<String, String>,
+    /// SHA-256 digests of the accepted bearer tokens.
+    tokens: HashSet<Vec<u8>>,
+    /// SHA-256 digests of Authorization headers that already passed a bcrypt check.
+    /// bcrypt is slow on purpose, and cargo makes a request for every crate.
+    verified: Mutex<HashSet<Vec<u8>>>,
+    /// Recent failed password checks per user and client address: how many, and when the
+    /// first one was. Keying on the address too means other clients can't lock a user out.
+    failures: Mutex<HashMap<FailureKey, (u32, Instant)>>,
+    realm: String,
+}
+
+/// Why a request was refused.
+#[derive(Debug, PartialEq)]
+pub enum AuthFailure {
+    /// Credentials were missing or wrong.
+    Unauthorized,
+    /// Too many wrong passwords were tried for this user recently.
+    TooManyAttempts,
+}
+
+/// The credentials in an Authorization header.
+#[derive(Debug, PartialEq)]
+enum Credentials<'a> {
+    Basic(String, String),
+    Token(&'a str),
+    Invalid,
+}
+
+impl Auth {
+    /// Load the htpasswd and token files. Relative paths are relative to the mirror directory.
+    pub fn load(mirror_path: &Path, auth: &AuthSection) -> Result<Auth, MirrorError> {
+        let mut users = HashMap::new();
+        if let Some(htpasswd) = &auth.htpasswd {
+            let htpasswd = mirror_path.join(htpasswd);
+            for line in read_auth_file(&htpasswd)? {
+                let (user, hash) = line.split_once(':').unwrap_or((&line, ""));
+                if !is_supported_hash(hash) {
+                    return Err(MirrorError::Auth(format!(
+                        "Unsupported password hash for user `{}` in `{}`, use bcrypt or SHA1",
+                        user,
+                        htpasswd.display()
+                    )));
+                }
+                users.insert(user.to_string(), hash.to_string());
+            }
+        }
+
+        let mut tokens = HashSet::new();
+        if let Some(tokens_file) = &auth.tokens_file {
+            for token in read_auth_file(&mirror_path.join(tokens_file))? {
+                tokens.insert(Sha256::digest(token.as_bytes()).to_vec());
+            }
+        }
+
+        if users.is_empty() && tokens.is_empty() {
+            return Err(MirrorError::Auth(
+                "No users or tokens configured, nobody would be able to access the mirror"
+                    .to_string(),
+            ));
+        }
+
+        Ok(Auth {
+            users,
+            tokens,
+            verified: Mutex::new(HashSet::new()),
+            failures: Mutex::new(HashMap::new()),
+            realm: auth.realm.clone().unwrap_or_else(|| "Panamax".to_string()),
+        })
+    }
+
+    /// Also accept these tokens, given as SHA-256 digests.
+    pub fn accept_token_digests<'a>(&mut self, digests: impl Iterator<Item = &'a Vec<u8>>) {
+        self.tokens.extend(digests.cloned());
+    }
+
+    /// Check a request's credentials.
+    ///
+    /// On success, returns the user name for HTTP Basic authentication.
+    pub async fn check(
+        &self,
+        req: &Request<Body>,
+        remote_addr: Option<SocketAddr>,
+    ) -> Result<Option<String>, AuthFailure> {
+        let header = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("")
+            .trim();
+
+        match parse_authorization(header) {
+            Credentials::Basic(user, password) => {
+                let client = remote_addr.map(|addr| addr.ip());
+                self.check_password(header, &user, client, password).await?;
+                Ok(Some(user))
+            }
+            Credentials::Token(token) if self.check_token(token) => Ok(None),
+            Credentials::Token(_) | Credentials::Invalid => Err(AuthFailure::Unauthorized),
+        }
+    }
+
+    async fn check_password(
+        &self,
+        header: &str,
+        user: &str,
+        client: Option<IpAddr>,
+        password: String,
+    ) -> Result<(), AuthFailure> {
+        let header_digest = Sha256::digest(header.as_bytes()).to_vec();
+        if self.verified.lock().unwrap().contains(&header_digest) {
+            return Ok(());
+        }
+
+        let hash = match self.users.get(user) {
+            Some(hash) => hash.clone(),
+            None => return Err(AuthFailure::Unauthorized),
+        };
+
+        let failure_key = (user.to_string(), client);
+        if let Some((count, since)) = self.failures.lock().unwrap().get(&failure_key) {
+            if *count >= MAX_FAILURES && since.elapsed() < FAILURE_WINDOW {
+                return Err(AuthFailure::TooManyAttempts);
+            }
+        }
+
+        // bcrypt takes a while, so keep it off the threads serving other requests.
+        let authorized = tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+            .await
+            .unwrap_or(false);
+
+        if authorized {
+            let mut verified = self.verified.lock().unwrap();
+            if verified.len() >= MAX_VERIFIED {
+                verified.clear();
+            }
+            verified.insert(header_digest);
+            self.failures.lock().unwrap().remove(&failure_key);
+            Ok(())
+        } else {
+            let mut failures = self.failures.lock().unwrap();
+            // Forget windows that are over, so the map doesn't keep every address ever seen.
+            failures.retain(|_, (_, since)| since.elapsed() < FAILURE_WINDOW);
+            let entry = failures.entry(failure_key).or_insert((0, Instant::now()));
+            entry.0 += 1;
+            Err(AuthFailure::Unauthorized)
+        }
+    }
+
+    fn check_token(&self, token: &str) -> bool {
+        self.tokens
+            .contains(Sha256::digest(token.as_bytes()).as_slice())
+    }
+
+    /// The response for a refused request.
+    pub fn failure_response(&self, failure: AuthFailure) -> Response<Body> {
+        match failure {
+            AuthFailure::Unauthorized => {
+                let mut res = text_response(StatusCode::UNAUTHORIZED, "Authentication required");
+                let challenge = format!("Basic realm=\"{}\"", self.realm);
+                if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+                    res.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+                }
+                res
+            }
+            AuthFailure::TooManyAttempts => {
+                let mut res = text_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "Too many failed login attempts, try again later",
+                );
+                res.headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(FAILURE_WINDOW.as_secs()));
+                res
+            }
+        }
+    }
+}
+
+/// Read the non-empty, non-comment lines of an htpasswd or token file.
+pub fn read_auth_file(path: &Path) -> Result<Vec<String>, MirrorError> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| MirrorError::Auth(format!("Could not read `{}`: {}", path.display(), e)))?;
+    Ok(content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect())
+}
+
+fn is_supported_hash(hash: &str) -> bool {
+    hash.starts_with("{SHA}")
+        || hash.starts_with("$2y$")
+        || hash.starts_with("$2b$")
+        || hash.starts_with("$2a$")
+}
+
+/// Check a password against an htpasswd hash.
+fn verify_password(password: &str, hash: &str) -> bool {
+    if let Some(sha1) = hash.strip_prefix("{SHA}") {
+        let digest = base64::encode(Sha1::digest(password.as_bytes()));
+        constant_time_eq(digest.as_bytes(), sha1.as_bytes())
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+/// Compare two byte strings in time that only depends on their lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Parse an Authorization header.
+fn parse_authorization(header: &str) -> Credentials<'_> {
+    if header.is_empty() {
+        return Credentials::Invalid;
+    }
+    let (scheme, credentials) = match header.split_once(' ') {
+        Some((scheme, credentials)) => (scheme, credentials.trim()),
+        None => ("", header),
+    };
+
+    if scheme.eq_ignore_ascii_case("Basic") {
+        match decode_basic(credentials) {
+            Some((user, password)) => Credentials::Basic(user, password),
+            None => Credentials::Invalid,
+        }
+    } else if scheme.eq_ignore_ascii_case("Bearer") {
+        Credentials::Token(credentials)
+    } else {
+        // cargo sends its registry token as the whole header, without a scheme.
+        Credentials::Token(header)
+    }
+}
+
+/// Decode HTTP Basic credentials into a user and password.
+fn decode_basic(credentials: &str) -> Option<(String, String)> {
+    let decoded = base64::decode(credentials).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", base64::encode(credentials))
+    }
+
+    fn auth(users: &[(&str, &str)], tokens: &[&str]) -> Auth {
+        Auth {
+            users: users
+                .iter()
+                .map(|(user, hash)| (user.to_string(), hash.to_string()))
+                .collect(),
+            tokens: tokens
+                .iter()
+                .map(|token| Sha256::digest(token.as_bytes()).to_vec())
+                .collect(),
+            verified: Mutex::new(HashSet::new()),
+            failures: Mutex::new(HashMap::new()),
+            realm: "Panamax".to_string(),
+        }
+    }
+
+    fn request(authorization: Option<&str>) -> Request<Body> {
+        let mut req = Request::new(Body::empty());
+        if let Some(authorization) = authorization {
+            req.headers_mut()
+                .insert(AUTHORIZATION, HeaderValue::from_str(authorization).unwrap());
+        }
+        req
+    }
+
+    impl Auth {
+        async fn check_from(&self, req: &Request<Body>) -> Result<Option<String>, AuthFailure> {
+            self.check(req, Some(([10, 0, 0, 1], 1234).into())).await
+        }
+    }
+
+    /// `{SHA}` hash of "secret", as written by `htpasswd -s`.
+    static SECRET_SHA1: &str = "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=";
+
+    #[test]
+    fn parses_basic_credentials() {
+        assert_eq!(
+            parse_authorization(&basic("alice:pass:word")),
+            Credentials::Basic("alice".to_string(), "pass:word".to_string())
+        );
+        assert_eq!(
+            parse_authorization(&format!("basic   {}", base64::encode("bob:"))),
+            Credentials::Basic("bob".to_string(), "".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_tokens() {
+        assert_eq!(
+            parse_authorization("Bearer  abc123 "),
+            Credentials::Token("abc123")
+        );
+        assert_eq!(parse_authorization("abc123"), Credentials::Token("abc123"));
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        assert_eq!(parse_authorization(""), Credentials::Invalid);
+        // Not base64.
+        assert_eq!(parse_authorization("Basic %%%"), Credentials::Invalid);
+        // No colon between user and password.
+        assert_eq!(parse_authorization(&basic("alice")), Credentials::Invalid);
+        // Not UTF-8.
+        let not_utf8 = format!("Basic {}", base64::encode([0xff, 0xfe, b':', b'x']));
+        assert_eq!(parse_authorization(&not_utf8), Credentials::Invalid);
+    }
+
+    #[test]
+    fn verifies_sha1_passwords() {
+        assert!(verify_password("secret", SECRET_SHA1));
+        assert!(!verify_password("Secret", SECRET_SHA1));
+        assert!(!verify_password("", SECRET_SHA1));
+        assert!(!verify_password("secret", "$2y$05$notarealbcrypthash"));
+    }
+
+    #[test]
+    fn compares_in_constant_time() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[tokio::test]
+    async fn checks_requests() {
+        let auth = auth(&[("alice", SECRET_SHA1)], &["token"]);
+        assert_eq!(
+            auth.check_from(&request(Some(&basic("alice:secret"))))
+                .await,
+            Ok(Some("alice".to_string()))
+        );
+        assert_eq!(
+            auth.check_from(&request(Some("Bearer token"))).await,
+            Ok(None)
+        );
+        assert_eq!(auth.check_from(&request(Some("token"))).await, Ok(None));
+        assert_eq!(
+            auth.check_from(&request(Some("Bearer nope"))).await,
+            Err(AuthFailure::Unauthorized)
+        );
+        assert_eq!(
+            auth.check_from(&request(Some(&basic("mallory:secret"))))
+                .await,
+            Err(AuthFailure::Unauthorized)
+        );
+        assert_eq!(
+            auth.check_from(&request(None)).await,
+            Err(AuthFailure::Unauthorized)
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_failed_passwords() {
+        let auth = auth(&[("alice", SECRET_SHA1)], &[]);
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(
+                auth.check_from(&request(Some(&basic("alice:wrong")))).await,
+                Err(AuthFailure::Unauthorized)
+            );
+        }
+        // Even the right password is refused until the window is over.
+        assert_eq!(
+            auth.check_from(&request(Some(&basic("alice:secret"))))
+                .await,
+            Err(AuthFailure::TooManyAttempts)
+        );
+        // Other clients can still log in as the same user.
+        let other_client = Some(([10, 0, 0, 2], 1234).into());
+        assert_eq!(
+            auth.check(&request(Some(&basic("alice:secret"))), other_client)
+                .await,
+            Ok(Some("alice".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn bounds_verified_cache() {
+        let users: Vec<(String, &str)> = (0..=MAX_VERIFIED)
+            .map(|i| (format!("user{}", i), SECRET_SHA1))
+            .collect();
+        let users: Vec<(&str, &str)> = users.iter().map(|(u, h)| (u.as_str(), *h)).collect();
+        let auth = auth(&users, &[]);
+        for (user, _) in &users {
+            let header = basic(&format!("{}:secret", user));
+            assert!(auth.check_from(&request(Some(&header))).await.is_ok());
+            assert!(auth.verified.lock().unwrap().len() <= MAX_VERIFIED);
+        }
+        assert_eq!(auth.verified.lock().unwrap().len(), 1);
+    }
+}
@@ -1,12 +1,33 @@
-use iron::prelude::*;
-use iron::AfterMiddleware;
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
 
-pub struct CorsMiddleware;
+/// Allow the mirror's files to be fetched from any origin.
+pub fn cors(res: &mut Response<Body>) {
+    res.headers_mut()
+        .insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+}
+
+/// Whether a request is a CORS preflight, which browsers send without credentials.
+pub fn is_preflight(req: &Request<Body>) -> bool {
+    req.method() == Method::OPTIONS && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
 
-impl AfterMiddleware for CorsMiddleware {
-    fn after(&self, _req: &mut Request, mut res: Response) -> IronResult<Response> {
-        res.headers
-            .set(iron::headers::AccessControlAllowOrigin::Any);
-        Ok(res)
-    }
+/// Answer a CORS preflight request.
+pub fn preflight_response() -> Response<Body> {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = StatusCode::NO_CONTENT;
+    let headers = res.headers_mut();
+    headers.insert(
+        ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static("GET, HEAD, OPTIONS"),
+    );
+    headers.insert(
+        ACCESS_CONTROL_ALLOW_HEADERS,
+        HeaderValue::from_static("Authorization, Range, If-None-Match, If-Modified-Since"),
+    );
+    headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("86400"));
+    res
 }
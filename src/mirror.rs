@@ -1,19 +1,170 @@
-use std::path::Path;
-use std::{fs, io};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use std::{fmt, fs, io};
 
 use console::style;
 use reqwest::header::HeaderValue;
 use serde_derive::{Deserialize, Serialize};
 
+use crate::access_log::AccessLogFormat;
+use crate::crates::CratesSyncOptions;
+use crate::download::FailedDownload;
+use crate::journal::SyncJournalEntry;
+use crate::lock::MirrorLock;
+use crate::safe_path::is_plain_segment;
+
 quick_error! {
     #[derive(Debug)]
     pub enum MirrorError {
         Io(err: io::Error) {
             from()
+            display("I/O error: {}", err)
         }
         Parse(err: toml::de::Error) {
             from()
+            display("Could not parse mirror.toml: {}", err)
+        }
+        Locked(pid: Option<u32>) {
+            display("Mirror is already being synced by another process{}. \
+                     Use --wait to wait for it to finish.",
+                    pid.map(|pid| format!(" (PID {})", pid)).unwrap_or_default())
+        }
+        Report(err: serde_json::Error) {
+            from()
+            display("Could not write sync report: {}", err)
+        }
+        SyncFailed(report: SyncReport) {
+            display("{}", report)
+        }
+        MissingServeSection {
+            display("The [serve] section of mirror.toml is missing.")
+        }
+        BadBindAddress(addr: String) {
+            display("Invalid bind address `{}`, expected an IP address and port, or unix:<path>.", addr)
+        }
+        BadRegistryName(name: String) {
+            display("`{}` can't be used as a registry name, it must be a plain directory name.", name)
+        }
+        BadRegistryDir(name: String, dir: PathBuf) {
+            display("Registry `{}` can't use `{}` as its dir, it must be a relative subdirectory of the mirror, apart from the mirror's own directories (like `crates` and `crates.io-index`) and other registries.", name, dir.display())
+        }
+        BadStaticDir(dir: String) {
+            display("`{}` can't be served as a static directory, it must be a plain top-level directory name.", dir)
+        }
+        Auth(msg: String) {
+            display("Authentication configuration error: {}", msg)
+        }
+        Tls(msg: String) {
+            display("TLS configuration error: {}", msg)
+        }
+        Publish(msg: String) {
+            display("Private registry error: {}", msg)
+        }
+    }
+}
+
+impl MirrorError {
+    /// The process exit code to use for this error.
+    ///
+    /// * 1: any other error (bad configuration, I/O errors)
+    /// * 2: the mirror is locked by another sync
+    /// * 3: rustup sync failures
+    /// * 4: crates sync failures
+    /// * 5: both rustup and crates sync failures
+    /// * 6: only documentation sync failures
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MirrorError::Locked(_) => 2,
+            MirrorError::SyncFailed(report) => {
+                match (
+                    report.failure_count("rustup") > 0,
+                    report.crates_failure_count() > 0,
+                ) {
+                    (true, false) => 3,
+                    (false, true) => 4,
+                    (true, true) => 5,
+                    (false, false) if report.failure_count("docs") > 0 => 6,
+                    (false, false) => 1,
+                }
+            }
+            _ => 1,
+        }
+    }
+}
+
+/// One phase of a sync (e.g. syncing the stable channel) that did not complete.
+#[derive(Serialize, Debug)]
+pub struct PhaseFailure {
+    /// Either "rustup", "crates", "registry:<name>" or "docs".
+    pub section: String,
+    pub phase: String,
+    /// Number of failures in this phase (downloads, or 1 if the phase failed outright).
+    pub count: usize,
+    pub error: String,
+    pub failed_downloads: Vec<FailedDownload>,
+}
+
+/// Summary of everything that failed during a sync.
+#[derive(Serialize, Debug, Default)]
+pub struct SyncReport {
+    pub failures: Vec<PhaseFailure>,
+    /// The error that stopped the sync early, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl SyncReport {
+    pub fn add_failure(
+        &mut self,
+        section: &str,
+        phase: &str,
+        count: usize,
+        error: String,
+        failed_downloads: Vec<FailedDownload>,
+    ) {
+        self.failures.push(PhaseFailure {
+            section: section.to_string(),
+            phase: phase.to_string(),
+            count,
+            error,
+            failed_downloads,
+        });
+    }
+
+    /// Total number of failures in a section ("rustup", "crates" or "docs").
+    pub fn failure_count(&self, section: &str) -> usize {
+        self.failures
+            .iter()
+            .filter(|f| f.section == section)
+            .map(|f| f.count)
+            .sum()
+    }
+
+    /// Total number of failures in crates.io and the other registries.
+    pub fn crates_failure_count(&self) -> usize {
+        self.failures
+            .iter()
+            .filter(|f| f.section == "crates" || f.section.starts_with("registry:"))
+            .map(|f| f.count)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sync completed with failures:")?;
+        for failure in &self.failures {
+            write!(
+                f,
+                "\n  {} {}: {} failed",
+                failure.section, failure.phase, failure.count
+            )?;
         }
+        Ok(())
     }
 }
 
@@ -41,12 +192,169 @@ pub struct CratesSection {
     pub download_threads: usize,
     pub source: String,
     pub source_index: String,
+    pub squash_index: Option<bool>,
+    /// Squash the index again once master has more commits than this on top of its snapshot.
+    pub squash_index_max_commits: Option<usize>,
+    /// Squash the index again once its snapshot is older than this many days.
+    pub squash_index_max_days: Option<u64>,
+    pub sync_files: Option<bool>,
+    /// A directory of extra index files (under `index/`) and `.crate` files (under `crates/`),
+    /// merged into the served index.
+    pub overlay_dir: Option<PathBuf>,
+    /// Overlay crates allowed to share a name with a crates.io crate.
+    pub overlay_override: Option<Vec<String>>,
+}
+
+/// Who can see the download statistics at `/stats`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StatsAccess {
+    /// Only clients connecting from the same machine, or over a Unix domain socket.
+    Local,
+    /// Every client.
+    Public,
+    /// Nobody, `/stats` is not served.
+    Off,
+}
+
+/// How the crates.io-index git repository is served.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum GitBackend {
+    /// Built-in implementation of git's smart HTTP protocol.
+    Native,
+    /// The `git http-backend` command, which requires git to be installed.
+    HttpBackend,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ServeSection {
     pub port: u16,
+    pub bind: Option<Vec<String>>,
     pub base_url: Option<String>,
+    pub git_backend: Option<GitBackend>,
+    pub worker_threads: Option<usize>,
+    pub blocking_threads: Option<usize>,
+    pub keep_alive: Option<bool>,
+    /// Seconds to wait for in-flight requests on shutdown, before exiting anyway.
+    pub shutdown_timeout: Option<u64>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub http_redirect_port: Option<u16>,
+    pub auth: Option<AuthSection>,
+    pub pull_through: Option<bool>,
+    pub static_dirs: Option<Vec<String>>,
+    pub access_log: Option<AccessLogFormat>,
+    pub access_log_path: Option<PathBuf>,
+    pub stats_access: Option<StatsAccess>,
+    pub publish: Option<PublishSection>,
+}
+
+/// A private registry that crates can be published to, served under `/private`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PublishSection {
+    /// URL where the server's `/private` path can be accessed from.
+    pub base_url: String,
+    /// A file of `user:token` lines. Each user can publish with their token.
+    pub tokens_file: PathBuf,
+    /// Largest `.crate` file accepted, in bytes.
+    pub max_upload_size: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuthSection {
+    pub htpasswd: Option<PathBuf>,
+    pub tokens_file: Option<PathBuf>,
+    pub realm: Option<String>,
+}
+
+/// Another registry to mirror, besides crates.io, served under `/registries/<name>/`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegistrySection {
+    pub name: String,
+    /// Where the registry is kept, `registries/<name>` in the mirror directory by default.
+    /// It's laid out like the mirror's crates.io part, with `crates.io-index` and `crates`.
+    pub dir: Option<PathBuf>,
+    /// URL where this registry's crates can be accessed from,
+    /// like `http://panamax.internal/registries/<name>/crates`.
+    pub base_url: Option<String>,
+    #[serde(flatten)]
+    pub crates: CratesSection,
+}
+
+impl RegistrySection {
+    pub fn dir(&self, mirror_path: &Path) -> PathBuf {
+        mirror_path.join(self.relative_dir())
+    }
+
+    fn relative_dir(&self) -> PathBuf {
+        match &self.dir {
+            Some(dir) => dir.clone(),
+            None => Path::new("registries").join(&self.name),
+        }
+    }
+
+    /// The sync report section this registry's failures go in.
+    pub fn report_section(&self) -> String {
+        format!("registry:{}", self.name)
+    }
+}
+
+/// Top-level directories of the mirror that registries can't be kept in.
+static MIRROR_DIRS: &[&str] = &[
+    "crates",
+    "crates.io-index",
+    "dist",
+    "rustup",
+    "docs",
+    "private",
+    "private-index",
+];
+
+/// Check that registry names are plain directory names, and that every registry has its own
+/// directory, apart from the crates.io mirror's and the other registries'.
+pub fn check_registries(registries: &[RegistrySection]) -> Result<(), MirrorError> {
+    let mut dirs: Vec<PathBuf> = vec![];
+    for registry in registries {
+        if !is_plain_segment(&registry.name) {
+            return Err(MirrorError::BadRegistryName(registry.name.clone()));
+        }
+        let dir = registry.relative_dir();
+        let bad_dir = || MirrorError::BadRegistryDir(registry.name.clone(), dir.clone());
+        if dir.as_os_str().is_empty()
+            || !dir.components().all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(bad_dir());
+        }
+        if MIRROR_DIRS
+            .iter()
+            .any(|mirror_dir| dir.starts_with(mirror_dir))
+        {
+            return Err(bad_dir());
+        }
+        if dirs
+            .iter()
+            .any(|other| dir.starts_with(other) || other.starts_with(&dir))
+        {
+            return Err(bad_dir());
+        }
+        dirs.push(dir);
+    }
+    Ok(())
+}
+
+/// Documentation to mirror for offline use, served under `/docs/<crate>/<version>/`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocsSection {
+    pub sync: bool,
+    /// Crates to mirror documentation for, as `name` (the latest version) or `name@version`.
+    pub crates: Vec<String>,
+    /// Where to download documentation archives from, docs.rs by default.
+    pub source: Option<String>,
+    /// Set to false to only use documentation from `drop_dir`.
+    pub download: Option<bool>,
+    /// A directory of `<crate>/<version>/` folders of `cargo doc` output.
+    pub drop_dir: Option<PathBuf>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -54,6 +362,8 @@ pub struct Mirror {
     pub mirror: MirrorSection,
     pub rustup: Option<RustupSection>,
     pub crates: Option<CratesSection>,
+    pub docs: Option<DocsSection>,
+    pub registries: Option<Vec<RegistrySection>>,
     pub serve: Option<ServeSection>,
 }
 
@@ -102,6 +412,19 @@ pub fn init(path: &Path) -> Result<(), MirrorError> {
     Ok(())
 }
 
+/// Build the User-Agent for upstream requests, with the contact information if it's set.
+pub fn user_agent_string(mirror: &MirrorSection) -> String {
+    if let Some(ref contact) = mirror.contact {
+        if contact != "your@email.com" {
+            format!("Panamax/{} ({})", env!("CARGO_PKG_VERSION"), contact)
+        } else {
+            default_user_agent()
+        }
+    } else {
+        default_user_agent()
+    }
+}
+
 pub fn default_user_agent() -> String {
     eprintln!("{}", style("No contact information was provided!").bold());
     eprintln!(
@@ -115,7 +438,13 @@ pub fn default_user_agent() -> String {
     )
 }
 
-pub fn sync(path: &Path) -> Result<(), MirrorError> {
+pub fn sync(
+    path: &Path,
+    wait: bool,
+    report_path: Option<&Path>,
+    full_crates: bool,
+    verify_crates: bool,
+) -> Result<(), MirrorError> {
     if !path.join("mirror.toml").exists() {
         eprintln!(
             "Mirror base not found! Run panamax init {} first.",
@@ -123,19 +452,12 @@ pub fn sync(path: &Path) -> Result<(), MirrorError> {
         );
         return Ok(());
     }
+    // Hold the lock until the end of the sync, so overlapping syncs don't clobber each other.
+    let _lock = MirrorLock::acquire(path, wait)?;
+
     let mirror = load_mirror_toml(path)?;
 
-    // Handle the contact information
-
-    let user_agent_str = if let Some(ref contact) = mirror.mirror.contact {
-        if contact != "your@email.com" {
-            format!("Panamax/{} ({})", env!("CARGO_PKG_VERSION"), contact)
-        } else {
-            default_user_agent()
-        }
-    } else {
-        default_user_agent()
-    };
+    let user_agent_str = user_agent_string(&mirror.mirror);
 
     let user_agent = match HeaderValue::from_str(&user_agent_str) {
         Ok(h) => h,
@@ -147,9 +469,58 @@ pub fn sync(path: &Path) -> Result<(), MirrorError> {
         }
     };
 
+    let mut report = SyncReport::default();
+    let started = SystemTime::now();
+
+    let result = sync_sections(
+        path,
+        mirror,
+        full_crates,
+        verify_crates,
+        &user_agent,
+        &mut report,
+    );
+
+    // Record every sync, even failed ones, so monitoring can tell when the mirror is stale.
+    let failures = report.failure_count("rustup")
+        + report.crates_failure_count()
+        + report.failure_count("docs");
+    let entry =
+        SyncJournalEntry::finished_now(started, result.is_ok() && report.is_empty(), failures);
+    if let Err(e) = crate::journal::append(path, &entry) {
+        eprintln!("Could not write the sync journal: {}", e);
+    }
+
+    // Write the report even if the sync stopped early, as that's when it's needed most.
+    if let Err(e) = &result {
+        report.error = Some(e.to_string());
+    }
+    if let Some(report_path) = report_path {
+        fs::write(report_path, serde_json::to_vec_pretty(&report)?)?;
+    }
+    result?;
+
+    if !report.is_empty() {
+        return Err(MirrorError::SyncFailed(report));
+    }
+
+    eprintln!("Sync complete.");
+
+    Ok(())
+}
+
+/// Sync the rustup, crates, registries and docs sections of the mirror, as configured.
+fn sync_sections(
+    path: &Path,
+    mirror: Mirror,
+    full_crates: bool,
+    verify_crates: bool,
+    user_agent: &HeaderValue,
+    report: &mut SyncReport,
+) -> Result<(), MirrorError> {
     if let Some(rustup) = mirror.rustup {
         if rustup.sync {
-            crate::rustup::sync(path, &mirror.mirror, &rustup, &user_agent)?;
+            crate::rustup::sync(path, &mirror.mirror, &rustup, user_agent, report)?;
         } else {
             eprintln!("Rustup sync is disabled, skipping...");
         }
@@ -157,10 +528,22 @@ pub fn sync(path: &Path) -> Result<(), MirrorError> {
         eprintln!("Rustup section missing, skipping...");
     }
 
-    match (mirror.crates, mirror.serve) {
+    let registries = mirror.registries.unwrap_or_default();
+    check_registries(&registries)?;
+
+    let auth_required = mirror.serve.as_ref().is_some_and(|s| s.auth.is_some());
+    match (mirror.crates, &mirror.serve) {
         (Some(crates), Some(serve)) => {
             if crates.sync {
-                crate::crates::sync(path, &mirror.mirror, &crates, &serve, &user_agent)?
+                let options = CratesSyncOptions {
+                    section: "crates",
+                    base_url: serve.base_url.as_deref(),
+                    has_api: true,
+                    auth_required,
+                    full: full_crates,
+                    verify: verify_crates,
+                };
+                crate::crates::sync(path, &mirror.mirror, &crates, &options, user_agent, report)?
             } else {
                 eprintln!("Crates sync is disabled, skipping...");
             }
@@ -170,7 +553,123 @@ pub fn sync(path: &Path) -> Result<(), MirrorError> {
         }
     }
 
-    eprintln!("Sync complete.");
+    for registry in registries {
+        if registry.crates.sync {
+            eprintln!(
+                "{}",
+                style(format!("Syncing registry `{}`...", registry.name)).bold()
+            );
+            let section = registry.report_section();
+            let options = CratesSyncOptions {
+                section: &section,
+                base_url: registry.base_url.as_deref(),
+                // Only crates.io's API is served, so cargo isn't pointed at one.
+                has_api: false,
+                auth_required,
+                full: full_crates,
+                verify: verify_crates,
+            };
+            crate::crates::sync(
+                &registry.dir(path),
+                &mirror.mirror,
+                &registry.crates,
+                &options,
+                user_agent,
+                report,
+            )?
+        } else {
+            eprintln!("Registry `{}` sync is disabled, skipping...", registry.name);
+        }
+    }
+
+    // Docs go last, as the latest crate versions are found in the freshly synced index.
+    if let Some(docs) = mirror.docs {
+        if docs.sync {
+            crate::docs::sync(path, &mirror.mirror, &docs, user_agent, report);
+        } else {
+            eprintln!("Docs sync is disabled, skipping...");
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(name: &str, dir: Option<&str>) -> RegistrySection {
+        let mut toml = format!(
+            "name = \"{}\"\nsync = true\ndownload_threads = 1\nsource = \"\"\nsource_index = \"\"\n",
+            name
+        );
+        if let Some(dir) = dir {
+            toml.push_str(&format!("dir = \"{}\"\n", dir));
+        }
+        toml::from_str(&toml).unwrap()
+    }
+
+    #[test]
+    fn accepts_separate_registry_dirs() {
+        let registries = [
+            registry("vendor", None),
+            registry("other", Some("other-registry")),
+            registry("nested", Some("registries/more/nested")),
+        ];
+        assert!(check_registries(&registries).is_ok());
+    }
+
+    #[test]
+    fn refuses_bad_registry_dirs() {
+        for dir in &[
+            "",
+            ".",
+            "./vendor",
+            "../vendor",
+            "/srv/vendor",
+            "registries/../crates",
+            "crates",
+            "crates/vendor",
+            "crates.io-index",
+            "private",
+        ] {
+            let result = check_registries(&[registry("vendor", Some(dir))]);
+            assert!(
+                matches!(result, Err(MirrorError::BadRegistryDir(..))),
+                "{:?}",
+                dir
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_overlapping_registry_dirs() {
+        for (first, second) in &[
+            (None, None),
+            (Some("vendor"), Some("vendor/inner")),
+            (Some("vendor/inner"), Some("vendor")),
+            (None, Some("registries")),
+        ] {
+            let registries = [registry("vendor", *first), registry("vendor", *second)];
+            assert!(
+                matches!(
+                    check_registries(&registries),
+                    Err(MirrorError::BadRegistryDir(..))
+                ),
+                "{:?} {:?}",
+                first,
+                second
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_bad_registry_names() {
+        for name in &["", "..", "a/b", ".hidden"] {
+            assert!(matches!(
+                check_registries(&[registry(name, None)]),
+                Err(MirrorError::BadRegistryName(_))
+            ));
+        }
+    }
+}
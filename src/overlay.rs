@@ -0,0 +1,359 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use console::style;
+use git2::{Repository, Tree};
+use serde_derive::Deserialize;
+
+use crate::crates::{index_file_path, SyncError};
+use crate::download::{sha256_file, FailedDownload};
+use crate::mirror::CratesSection;
+use crate::safe_path::{is_valid_crate_name, is_valid_crate_version};
+
+/// The fields of an overlay index line that are checked before it's served.
+#[derive(Deserialize, Debug)]
+struct OverlayEntry {
+    name: String,
+    vers: String,
+    cksum: String,
+}
+
+/// One crate's file in the overlay index.
+struct OverlayCrate {
+    name: String,
+    /// The file's lines, as they are.
+    lines: Vec<String>,
+    entries: Vec<OverlayEntry>,
+}
+
+/// Collect the files under the overlay's `index/` directory. Each file is named after its crate,
+/// and may be anywhere under `index/`, in the usual index layout or not.
+fn find_index_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            find_index_files(&entry.path(), files)?;
+        } else if entry.file_name() != "config.json" {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Read and check one crate's overlay index file.
+fn read_overlay_crate(file: &Path) -> Result<OverlayCrate, String> {
+    let name = file
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if !is_valid_crate_name(&name) {
+        return Err(format!("`{}` is not a valid crate name", name));
+    }
+    let content = fs::read_to_string(file).map_err(|e| e.to_string())?;
+
+    let mut lines = vec![];
+    let mut entries = vec![];
+    for line in content.lines().filter(|l| !l.trim().is_empty()) {
+        let entry: OverlayEntry =
+            serde_json::from_str(line).map_err(|e| format!("Invalid index line: {}", e))?;
+        if !is_valid_crate_version(&entry.vers) {
+            return Err(format!("`{}` is not a valid version", entry.vers));
+        }
+        if entry.name.to_lowercase() != name.to_lowercase() {
+            return Err(format!(
+                "`{}` {} is in the index file for `{}`",
+                entry.name, entry.vers, name
+            ));
+        }
+        lines.push(line.to_string());
+        entries.push(entry);
+    }
+    Ok(OverlayCrate {
+        name,
+        lines,
+        entries,
+    })
+}
+
+fn blob_content(repo: &Repository, tree: &Tree, path: &str) -> Option<Vec<u8>> {
+    let entry = tree.get_path(Path::new(path)).ok()?;
+    let blob = entry.to_object(repo).ok()?.peel_to_blob().ok()?;
+    Some(blob.content().to_vec())
+}
+
+/// Copy an overlay crate's `.crate` files into the mirror, checking them against the index.
+fn copy_crate_files(
+    path: &Path,
+    overlay_dir: &Path,
+    krate: &OverlayCrate,
+    problems: &mut Vec<FailedDownload>,
+) {
+    for entry in &krate.entries {
+        let source = overlay_dir
+            .join("crates")
+            .join(format!("{}-{}.crate", entry.name, entry.vers));
+        let dest = path
+            .join("crates")
+            .join(&entry.name)
+            .join(&entry.vers)
+            .join("download");
+        let label = format!("{} {}", entry.name, entry.vers);
+
+        if dest.exists() && sha256_file(&dest).ok().as_deref() == Some(entry.cksum.as_str()) {
+            continue;
+        }
+        if !source.exists() {
+            problems.push(FailedDownload {
+                url: label,
+                error: format!("Missing {}", source.display()),
+            });
+            continue;
+        }
+        if sha256_file(&source).ok().as_deref() != Some(entry.cksum.as_str()) {
+            problems.push(FailedDownload {
+                url: label,
+                error: format!("{} doesn't match the index checksum", source.display()),
+            });
+            continue;
+        }
+        let copied = dest
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::copy(&source, &dest).map(|_| ()));
+        if let Err(e) = copied {
+            problems.push(FailedDownload {
+                url: label,
+                error: e.to_string(),
+            });
+        }
+    }
+}
+
+/// The local overlay's index files, ready to be committed to master along with the merge.
+#[derive(Default)]
+pub struct Overlay {
+    /// The content of every index file the overlay adds or replaces.
+    files: BTreeMap<String, Vec<u8>>,
+    /// Overlay crates that were left out, and why.
+    pub problems: Vec<FailedDownload>,
+}
+
+impl Overlay {
+    pub fn files(&self) -> impl Iterator<Item = (&String, &Vec<u8>)> {
+        self.files.iter()
+    }
+}
+
+/// Read the local overlay, and copy its `.crate` files into the mirror.
+///
+/// Overlay crates whose names are taken on crates.io are left out, unless they're listed in
+/// `overlay_override`. So are crates whose names clash with another overlay crate's.
+pub fn read(path: &Path, crates: &CratesSection, overlay_dir: &Path) -> Result<Overlay, SyncError> {
+    eprintln!("{}", style("Reading local overlay...").bold());
+
+    let overlay_dir = path.join(overlay_dir);
+    let repo = Repository::open(path.join("crates.io-index"))?;
+    let origin_tree = repo
+        .find_reference("refs/remotes/origin/master")?
+        .peel_to_tree()?;
+    let overrides = crates.overlay_override.clone().unwrap_or_default();
+
+    let mut overlay = Overlay::default();
+    let mut index_files = vec![];
+    find_index_files(&overlay_dir.join("index"), &mut index_files)?;
+    index_files.sort();
+
+    // Crate names are unique regardless of case, and of `-` versus `_`.
+    let mut seen: BTreeMap<String, PathBuf> = BTreeMap::new();
+    for file in index_files {
+        let krate = match read_overlay_crate(&file) {
+            Ok(krate) => krate,
+            Err(error) => {
+                overlay.problems.push(FailedDownload {
+                    url: file.display().to_string(),
+                    error,
+                });
+                continue;
+            }
+        };
+
+        let normalized = krate.name.to_lowercase().replace('-', "_");
+        if let Some(first) = seen.get(&normalized) {
+            overlay.problems.push(FailedDownload {
+                url: file.display().to_string(),
+                error: format!("The same crate name as {}", first.display()),
+            });
+            continue;
+        }
+        seen.insert(normalized, file.clone());
+
+        let file_path = index_file_path(&krate.name);
+        let on_crates_io = [krate.name.replace('_', "-"), krate.name.replace('-', "_")]
+            .iter()
+            .any(|variant| blob_content(&repo, &origin_tree, &index_file_path(variant)).is_some());
+        let content = if on_crates_io {
+            if !overrides
+                .iter()
+                .any(|o| o.eq_ignore_ascii_case(&krate.name))
+            {
+                overlay.problems.push(FailedDownload {
+                    url: krate.name.clone(),
+                    error: "Conflicts with a crates.io crate, add it to overlay_override to merge"
+                        .to_string(),
+                });
+                continue;
+            }
+            // Keep the crates.io versions, with the overlay's versions replacing any they share.
+            let upstream = blob_content(&repo, &origin_tree, &file_path).unwrap_or_default();
+            let mut lines: Vec<String> = String::from_utf8_lossy(&upstream)
+                .lines()
+                .filter(|line| {
+                    serde_json::from_str::<OverlayEntry>(line)
+                        .map_or(true, |e| !krate.entries.iter().any(|o| o.vers == e.vers))
+                })
+                .map(|line| line.to_string())
+                .collect();
+            lines.extend(krate.lines.iter().cloned());
+            lines
+        } else {
+            krate.lines.clone()
+        };
+
+        let mut content = content.join("\n");
+        content.push('\n');
+        overlay.files.insert(file_path, content.into_bytes());
+        copy_crate_files(path, &overlay_dir, &krate, &mut overlay.problems);
+    }
+
+    Ok(overlay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crates::{file_index_entry, merge_crates_repo, CratesSyncOptions};
+    use git2::Signature;
+
+    /// Make a crates.io-index whose origin/master has one crate, `serde`.
+    fn index_repo(path: &Path) -> Repository {
+        let repo = Repository::init(path.join("crates.io-index")).unwrap();
+        let mut index = repo.index().unwrap();
+        for (file, content) in &[
+            ("config.json", "{}\n"),
+            (
+                "se/rd/serde",
+                "{\"name\":\"serde\",\"vers\":\"1.0.0\",\"cksum\":\"00\"}\n",
+            ),
+        ] {
+            index
+                .add_frombuffer(&file_index_entry(file), content.as_bytes())
+                .unwrap();
+        }
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("test", "test@test").unwrap();
+        let commit = repo
+            .commit(None, &signature, &signature, "Initial", &tree, &[])
+            .unwrap();
+        repo.reference("refs/remotes/origin/master", commit, true, "")
+            .unwrap();
+        drop(tree);
+        repo
+    }
+
+    fn crates_section(overlay_dir: Option<&str>) -> CratesSection {
+        let mut toml =
+            "sync = true\ndownload_threads = 1\nsource = \"\"\nsource_index = \"\"\n".to_string();
+        if let Some(overlay_dir) = overlay_dir {
+            toml.push_str(&format!("overlay_dir = \"{}\"\n", overlay_dir));
+        }
+        toml::from_str(&toml).unwrap()
+    }
+
+    static OPTIONS: CratesSyncOptions = CratesSyncOptions {
+        section: "crates",
+        base_url: None,
+        has_api: true,
+        auth_required: false,
+        full: false,
+        verify: false,
+    };
+
+    fn master_files(repo: &Repository) -> Vec<String> {
+        let tree = repo
+            .find_reference("refs/heads/master")
+            .unwrap()
+            .peel_to_tree()
+            .unwrap();
+        let mut files = vec![];
+        tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+            if entry.kind() == Some(git2::ObjectType::Blob) {
+                files.push(format!("{}{}", dir, entry.name().unwrap()));
+            }
+            git2::TreeWalkResult::Ok
+        })
+        .unwrap();
+        files
+    }
+
+    /// Add a crate to the overlay, with its index file in `index/<index_dir>`.
+    fn write_overlay_crate(overlay_dir: &Path, index_dir: &str, name: &str) {
+        let crate_file = overlay_dir
+            .join("crates")
+            .join(format!("{}-0.1.0.crate", name));
+        fs::create_dir_all(crate_file.parent().unwrap()).unwrap();
+        fs::write(&crate_file, b"crate").unwrap();
+        let cksum = sha256_file(&crate_file).unwrap();
+        let index_file = overlay_dir.join("index").join(index_dir).join(name);
+        fs::create_dir_all(index_file.parent().unwrap()).unwrap();
+        fs::write(
+            &index_file,
+            format!(
+                "{{\"name\":\"{}\",\"vers\":\"0.1.0\",\"cksum\":\"{}\"}}\n",
+                name, cksum
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn applies_overlay_in_merge_and_removes_it_when_unset() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = index_repo(dir.path());
+        write_overlay_crate(&dir.path().join("overlay"), "", "internal");
+
+        let crates = crates_section(Some("overlay"));
+        let overlay = read(dir.path(), &crates, Path::new("overlay")).unwrap();
+        assert!(overlay.problems.is_empty());
+        merge_crates_repo(dir.path(), &crates, &OPTIONS, false, &overlay).unwrap();
+        assert_eq!(
+            master_files(&repo),
+            vec!["config.json", "in/te/internal", "se/rd/serde"]
+        );
+        assert!(dir.path().join("crates/internal/0.1.0/download").is_file());
+        // Branching master and adding the overlay is a single commit.
+        let master = repo.find_reference("refs/heads/master").unwrap();
+        assert_eq!(master.peel_to_commit().unwrap().parent_count(), 1);
+
+        let crates = crates_section(None);
+        merge_crates_repo(dir.path(), &crates, &OPTIONS, false, &Overlay::default()).unwrap();
+        assert_eq!(master_files(&repo), vec!["config.json", "se/rd/serde"]);
+    }
+
+    #[test]
+    fn reports_duplicate_names() {
+        let dir = tempfile::tempdir().unwrap();
+        index_repo(dir.path());
+        let overlay_dir = dir.path().join("overlay");
+        write_overlay_crate(&overlay_dir, "", "foo-bar");
+        write_overlay_crate(&overlay_dir, "other", "foo_bar");
+
+        let crates = crates_section(Some("overlay"));
+        let overlay = read(dir.path(), &crates, Path::new("overlay")).unwrap();
+        assert_eq!(overlay.files.len(), 1);
+        assert_eq!(overlay.problems.len(), 1);
+    }
+}
@@ -0,0 +1,845 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use git2::{Repository, Signature};
+use hyper::header::{AUTHORIZATION, CONTENT_LENGTH};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use semver::Version;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::api::json_response;
+use crate::crates::{
+    config_json_content, crate_exists, file_index_entry, index_file_path, SyncError,
+};
+use crate::download::append_to_path;
+use crate::middleware::auth::read_auth_file;
+use crate::mirror::{MirrorError, PublishSection};
+use crate::safe_path::is_valid_crate_name;
+use crate::serve::{read_body_limited, BodyError};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum PublishError {
+        Io(err: io::Error) {
+            from()
+            display("I/O error: {}", err)
+        }
+        Git(err: git2::Error) {
+            from()
+            display("Could not update the private index: {}", err)
+        }
+        Json(err: serde_json::Error) {
+            from()
+            display("Invalid JSON: {}", err)
+        }
+        Index(err: SyncError) {
+            from()
+            display("Could not read the crates.io-index: {}", err)
+        }
+        Join(err: tokio::task::JoinError) {
+            from()
+            display("Publish task failed: {}", err)
+        }
+        Body(err: BodyError) {
+            from()
+            display("Could not read the request: {}", err)
+        }
+        Unauthorized {
+            display("A valid publish token is required")
+        }
+        Forbidden(msg: String) {
+            display("{}", msg)
+        }
+        Invalid(msg: String) {
+            display("{}", msg)
+        }
+        NotFound(msg: String) {
+            display("{}", msg)
+        }
+    }
+}
+
+impl PublishError {
+    fn status(&self) -> StatusCode {
+        match self {
+            PublishError::Unauthorized => StatusCode::UNAUTHORIZED,
+            PublishError::Forbidden(_) => StatusCode::FORBIDDEN,
+            PublishError::Invalid(_) | PublishError::Json(_) => StatusCode::BAD_REQUEST,
+            PublishError::NotFound(_) => StatusCode::NOT_FOUND,
+            PublishError::Body(BodyError::TooLarge(_)) => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Default largest `.crate` file accepted, as on crates.io.
+static DEFAULT_MAX_UPLOAD_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Room for the metadata sent along with a `.crate` file, which includes its README.
+static MAX_METADATA_SIZE: u64 = 1024 * 1024;
+
+/// Largest owners request accepted.
+static MAX_OWNERS_REQUEST_SIZE: usize = 10 * 1024 * 1024;
+
+/// The metadata cargo sends with `cargo publish`.
+#[derive(Deserialize, Debug)]
+struct PublishMetadata {
+    name: String,
+    vers: String,
+    deps: Vec<PublishDep>,
+    features: BTreeMap<String, Vec<String>>,
+    links: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PublishDep {
+    name: String,
+    version_req: String,
+    features: Vec<String>,
+    optional: bool,
+    default_features: bool,
+    target: Option<String>,
+    kind: String,
+    registry: Option<String>,
+    /// The name the dependency is renamed to, if it's renamed.
+    explicit_name_in_toml: Option<String>,
+}
+
+/// One line of a crate's file in the private index.
+#[derive(Serialize, Debug)]
+struct IndexLine {
+    name: String,
+    vers: String,
+    deps: Vec<IndexLineDep>,
+    cksum: String,
+    features: BTreeMap<String, Vec<String>>,
+    /// Features using the `dep:` and `?/` syntax, which older cargo versions can't read.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    features2: BTreeMap<String, Vec<String>>,
+    yanked: bool,
+    links: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    v: Option<u32>,
+}
+
+#[derive(Serialize, Debug)]
+struct IndexLineDep {
+    name: String,
+    req: String,
+    features: Vec<String>,
+    optional: bool,
+    default_features: bool,
+    target: Option<String>,
+    kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registry: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    package: Option<String>,
+}
+
+impl IndexLine {
+    fn new(metadata: PublishMetadata, cksum: String) -> IndexLine {
+        let (features2, features): (BTreeMap<_, _>, BTreeMap<_, _>) =
+            metadata.features.into_iter().partition(|(_, values)| {
+                values
+                    .iter()
+                    .any(|v| v.starts_with("dep:") || v.contains("?/"))
+            });
+        IndexLine {
+            name: metadata.name,
+            vers: metadata.vers,
+            deps: metadata
+                .deps
+                .into_iter()
+                .map(|dep| match dep.explicit_name_in_toml.clone() {
+                    // The index names renamed dependencies by their new name.
+                    Some(renamed) => IndexLineDep {
+                        package: Some(dep.name.clone()),
+                        ..IndexLineDep::new(renamed, dep)
+                    },
+                    None => IndexLineDep::new(dep.name.clone(), dep),
+                })
+                .collect(),
+            cksum,
+            v: if features2.is_empty() { None } else { Some(2) },
+            features,
+            features2,
+            yanked: false,
+            links: metadata.links,
+        }
+    }
+}
+
+impl IndexLineDep {
+    fn new(name: String, dep: PublishDep) -> IndexLineDep {
+        IndexLineDep {
+            name,
+            req: dep.version_req,
+            features: dep.features,
+            optional: dep.optional,
+            default_features: dep.default_features,
+            target: dep.target,
+            kind: dep.kind,
+            registry: dep.registry,
+            package: None,
+        }
+    }
+}
+
+/// A private registry, with its own index, that crates can be published to.
+///
+/// Published `.crate` files go in the mirror's `private/crates/` directory, apart from the
+/// mirrored ones. Crate names that exist on crates.io can't be published.
+pub struct PrivateRegistry {
+    mirror_path: PathBuf,
+    repo_path: PathBuf,
+    /// SHA-256 digests of publish tokens, mapped to the user they belong to.
+    tokens: HashMap<Vec<u8>, String>,
+    max_upload_size: u64,
+    /// Index updates commit on top of each other, so they're made one at a time.
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl PrivateRegistry {
+    /// Load the publish tokens, and create the private index if it doesn't exist yet.
+    pub fn load(
+        mirror_path: &Path,
+        publish: &PublishSection,
+        auth_required: bool,
+    ) -> Result<PrivateRegistry, MirrorError> {
+        let mut tokens = HashMap::new();
+        for line in read_auth_file(&mirror_path.join(&publish.tokens_file))? {
+            let mut parts = line.splitn(2, ':');
+            let user = parts.next().unwrap_or("").trim();
+            let token = parts.next().unwrap_or("").trim();
+            if user.is_empty() || token.is_empty() {
+                return Err(MirrorError::Auth(format!(
+                    "Publish tokens must be written as `user:token`, in `{}`",
+                    publish.tokens_file.display()
+                )));
+            }
+            tokens.insert(Sha256::digest(token.as_bytes()).to_vec(), user.to_string());
+        }
+
+        let repo_path = mirror_path.join("private-index");
+        let base_url = publish.base_url.trim_end_matches('/');
+        let config_json = config_json_content(
+            &format!("{}/api/v1/crates", base_url),
+            Some(base_url),
+            auth_required,
+        )
+        .map_err(|e| MirrorError::Publish(format!("{:?}", e)))?;
+        init_private_index(&repo_path, &config_json)
+            .map_err(|e| MirrorError::Publish(e.to_string()))?;
+
+        Ok(PrivateRegistry {
+            mirror_path: mirror_path.to_path_buf(),
+            repo_path,
+            tokens,
+            max_upload_size: publish.max_upload_size.unwrap_or(DEFAULT_MAX_UPLOAD_SIZE),
+            lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn repo_path(&self) -> &Path {
+        &self.repo_path
+    }
+
+    /// The directory with the published crates, in a `crates` subdirectory.
+    pub fn crates_dir(&self) -> PathBuf {
+        private_dir(&self.mirror_path)
+    }
+
+    /// The SHA-256 digests of the publish tokens, so `[serve.auth]` can accept them too.
+    pub fn token_digests(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.tokens.keys()
+    }
+
+    /// Answer a publish, yank, unyank or owners request.
+    /// `segments` is the path after `/private/api/v1/crates`.
+    pub async fn handle(&self, req: Request<Body>, segments: &[&str]) -> Response<Body> {
+        let result = match self.user(&req) {
+            Some(user) => {
+                let method = req.method().clone();
+                match (&method, segments) {
+                    (&Method::PUT, ["new"]) => self.publish(req, &user).await,
+                    (&Method::DELETE, [name, vers, "yank"]) => {
+                        self.set_yanked(&user, name, vers, true).await
+                    }
+                    (&Method::PUT, [name, vers, "unyank"]) => {
+                        self.set_yanked(&user, name, vers, false).await
+                    }
+                    (&Method::GET, [name, "owners"]) => self.list_owners(name).await,
+                    (&Method::PUT, [name, "owners"]) => {
+                        self.change_owners(req, &user, name, true).await
+                    }
+                    (&Method::DELETE, [name, "owners"]) => {
+                        self.change_owners(req, &user, name, false).await
+                    }
+                    _ => Err(PublishError::NotFound("Not Found".to_string())),
+                }
+            }
+            None => Err(PublishError::Unauthorized),
+        };
+
+        match result {
+            Ok(json) => json_response(StatusCode::OK, &json),
+            Err(e) => {
+                if e.status() == StatusCode::INTERNAL_SERVER_ERROR {
+                    eprintln!("Private registry request failed: {}", e);
+                }
+                json_response(
+                    e.status(),
+                    &json!({ "errors": [{ "detail": e.to_string() }] }),
+                )
+            }
+        }
+    }
+
+    /// Find the user a request's token belongs to.
+    ///
+    /// cargo sends the token as the whole Authorization header, others may use `Bearer`.
+    fn user(&self, req: &Request<Body>) -> Option<String> {
+        let header = req.headers().get(AUTHORIZATION)?.to_str().ok()?.trim();
+        let token = match header.get(..7) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("Bearer ") => header[7..].trim(),
+            _ => header,
+        };
+        self.tokens
+            .get(Sha256::digest(token.as_bytes()).as_slice())
+            .cloned()
+    }
+
+    /// `PUT /api/v1/crates/new`
+    async fn publish(&self, req: Request<Body>, user: &str) -> Result<Value, PublishError> {
+        let limit = usize::try_from(self.max_upload_size.saturating_add(MAX_METADATA_SIZE))
+            .unwrap_or(usize::MAX);
+        let content_length = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| l.parse::<u64>().ok());
+        if content_length.is_some_and(|l| l > limit as u64) {
+            return Err(BodyError::TooLarge(limit).into());
+        }
+        let body = read_body_limited(req.into_body(), limit).await?;
+        let (metadata, crate_file) = parse_publish_body(&body)?;
+        if crate_file.len() as u64 > self.max_upload_size {
+            return Err(PublishError::Invalid(format!(
+                "Crates can be at most {} bytes",
+                self.max_upload_size
+            )));
+        }
+        let crate_file = crate_file.to_vec();
+
+        if !is_valid_crate_name(&metadata.name) || metadata.name.len() > 64 {
+            return Err(PublishError::Invalid(format!(
+                "`{}` is not a valid crate name",
+                metadata.name
+            )));
+        }
+        if Version::parse(&metadata.vers).is_err() {
+            return Err(PublishError::Invalid(format!(
+                "`{}` is not a valid semver version",
+                metadata.vers
+            )));
+        }
+
+        let _lock = self.lock.lock().await;
+        let mirror_path = self.mirror_path.clone();
+        let repo_path = self.repo_path.clone();
+        let user = user.to_string();
+        tokio::task::spawn_blocking(move || {
+            publish_crate(&mirror_path, &repo_path, &user, metadata, &crate_file)
+        })
+        .await??;
+
+        Ok(json!({
+            "warnings": { "invalid_categories": [], "invalid_badges": [], "other": [] }
+        }))
+    }
+
+    /// `DELETE /api/v1/crates/<name>/<version>/yank` and `PUT .../unyank`
+    async fn set_yanked(
+        &self,
+        user: &str,
+        name: &str,
+        vers: &str,
+        yanked: bool,
+    ) -> Result<Value, PublishError> {
+        let _lock = self.lock.lock().await;
+        let mirror_path = self.mirror_path.clone();
+        let repo_path = self.repo_path.clone();
+        let (user, name, vers) = (user.to_string(), name.to_string(), vers.to_string());
+        tokio::task::spawn_blocking(move || {
+            check_owner(&mirror_path, &repo_path, &user, &name)?;
+            set_yanked(&repo_path, &name, &vers, yanked)
+        })
+        .await??;
+        Ok(json!({ "ok": true }))
+    }
+
+    /// `GET /api/v1/crates/<name>/owners`
+    async fn list_owners(&self, name: &str) -> Result<Value, PublishError> {
+        let mirror_path = self.mirror_path.clone();
+        let mut owners = tokio::task::spawn_blocking(move || read_owners(&mirror_path)).await??;
+        let users = owners
+            .remove(name)
+            .ok_or_else(|| PublishError::NotFound(format!("Crate `{}` does not exist", name)))?;
+        Ok(json!({
+            "users": users
+                .iter()
+                .enumerate()
+                .map(|(id, login)| json!({ "id": id, "login": login, "name": null }))
+                .collect::<Vec<_>>()
+        }))
+    }
+
+    /// `PUT /api/v1/crates/<name>/owners` adds owners, `DELETE` removes them.
+    async fn change_owners(
+        &self,
+        req: Request<Body>,
+        user: &str,
+        name: &str,
+        add: bool,
+    ) -> Result<Value, PublishError> {
+        #[derive(Deserialize)]
+        struct OwnersRequest {
+            users: Vec<String>,
+        }
+        let body = read_body_limited(req.into_body(), MAX_OWNERS_REQUEST_SIZE).await?;
+        let request: OwnersRequest = serde_json::from_slice(&body)?;
+
+        for login in &request.users {
+            if add && !self.tokens.values().any(|u| u == login) {
+                return Err(PublishError::Invalid(format!(
+                    "Unknown user `{}`, users need a publish token",
+                    login
+                )));
+            }
+        }
+
+        let _lock = self.lock.lock().await;
+        let mirror_path = self.mirror_path.clone();
+        let repo_path = self.repo_path.clone();
+        let (user, name) = (user.to_string(), name.to_string());
+        tokio::task::spawn_blocking(move || {
+            check_owner(&mirror_path, &repo_path, &user, &name)?;
+            let mut owners = read_owners(&mirror_path)?;
+            let crate_owners = owners.entry(name).or_default();
+            for login in request.users {
+                if add {
+                    if !crate_owners.contains(&login) {
+                        crate_owners.push(login);
+                    }
+                } else {
+                    crate_owners.retain(|owner| *owner != login);
+                }
+            }
+            if crate_owners.is_empty() {
+                return Err(PublishError::Invalid(
+                    "Crates must keep at least one owner".to_string(),
+                ));
+            }
+            write_owners(&mirror_path, &owners)
+        })
+        .await??;
+
+        Ok(json!({
+            "ok": true,
+            "msg": if add { "Owners added" } else { "Owners removed" },
+        }))
+    }
+}
+
+/// Split a `cargo publish` request body into the crate's metadata and `.crate` file.
+///
+/// The body is the metadata's length as a little-endian u32, the metadata as JSON,
+/// then the same again for the `.crate` file.
+fn parse_publish_body(body: &[u8]) -> Result<(PublishMetadata, &[u8]), PublishError> {
+    fn read_part(body: &[u8]) -> Option<(&[u8], &[u8])> {
+        let len_bytes = body.get(..4)?;
+        let len = u32::from_le_bytes([len_bytes[0], len_bytes[1], len_bytes[2], len_bytes[3]]);
+        let end = 4usize.checked_add(len as usize)?;
+        Some((body.get(4..end)?, &body[end..]))
+    }
+
+    let malformed = || PublishError::Invalid("Malformed publish request".to_string());
+    let (metadata, rest) = read_part(body).ok_or_else(malformed)?;
+    let (crate_file, _) = read_part(rest).ok_or_else(malformed)?;
+    Ok((serde_json::from_slice(metadata)?, crate_file))
+}
+
+/// Create the private index repository, and keep its config.json up to date.
+fn init_private_index(repo_path: &Path, config_json: &[u8]) -> Result<(), PublishError> {
+    let repo = if repo_path.join(".git").exists() {
+        Repository::open(repo_path)?
+    } else {
+        eprintln!("Creating the private index at {}", repo_path.display());
+        Repository::init(repo_path)?
+    };
+
+    let current = read_index_file(&repo, "config.json")?;
+    if current.as_deref() != Some(config_json) {
+        commit_index_file(&repo, "config.json", config_json, "Update config.json")?;
+    }
+    repo.set_head("refs/heads/master")?;
+
+    // Needed for `git http-backend` to serve the repository.
+    File::create(repo_path.join(".git").join("git-daemon-export-ok"))?;
+    Ok(())
+}
+
+/// Read a file from the private index's master branch.
+fn read_index_file(repo: &Repository, file_path: &str) -> Result<Option<Vec<u8>>, PublishError> {
+    let tree = match repo.find_reference("refs/heads/master") {
+        Ok(master) => master.peel_to_tree()?,
+        Err(_) => return Ok(None),
+    };
+    let entry = match tree.get_path(Path::new(file_path)) {
+        Ok(entry) => entry,
+        Err(_) => return Ok(None),
+    };
+    Ok(Some(
+        entry.to_object(repo)?.peel_to_blob()?.content().to_vec(),
+    ))
+}
+
+/// Commit a new version of one file to the private index's master branch.
+fn commit_index_file(
+    repo: &Repository,
+    file_path: &str,
+    content: &[u8],
+    message: &str,
+) -> Result<(), PublishError> {
+    let parent = match repo.find_reference("refs/heads/master") {
+        Ok(master) => Some(master.peel_to_commit()?),
+        Err(_) => None,
+    };
+
+    let mut index = repo.index()?;
+    index.clear()?;
+    if let Some(parent) = &parent {
+        index.read_tree(&parent.tree()?)?;
+    }
+    index.add_frombuffer(&file_index_entry(file_path), content)?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    index.write()?;
+
+    let signature = Signature::now("Panamax", "panamax@panamax")?;
+    let parents: Vec<_> = parent.iter().collect();
+    repo.commit(
+        Some("refs/heads/master"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &parents,
+    )?;
+    Ok(())
+}
+
+/// Find the name a crate was first published under, if it's in the private index.
+///
+/// Crate names are unique regardless of case, and of `-` versus `_`.
+fn published_name(repo: &Repository, name: &str) -> Result<Option<String>, PublishError> {
+    let normalize = |name: &str| name.to_lowercase().replace('-', "_");
+    for variant in &[name.replace('_', "-"), name.replace('-', "_")] {
+        if let Some(content) = read_index_file(repo, &index_file_path(variant))? {
+            let first_line = content.split(|&b| b == b'\n').next().unwrap_or(&[]);
+            if let Ok(line) = serde_json::from_slice::<Value>(first_line) {
+                if let Some(existing) = line["name"].as_str() {
+                    if normalize(existing) == normalize(name) {
+                        return Ok(Some(existing.to_string()));
+                    }
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn publish_crate(
+    mirror_path: &Path,
+    repo_path: &Path,
+    user: &str,
+    metadata: PublishMetadata,
+    crate_file: &[u8],
+) -> Result<(), PublishError> {
+    let repo = Repository::open(repo_path)?;
+    let name = metadata.name.clone();
+    let vers = metadata.vers.clone();
+
+    let has_crates_io_index = mirror_path.join("crates.io-index").join(".git").exists();
+    if has_crates_io_index && crate_exists(mirror_path, &name)? {
+        return Err(PublishError::Forbidden(format!(
+            "`{}` is a crates.io crate, private crates need a different name",
+            name
+        )));
+    }
+
+    let file_path = index_file_path(&name);
+    match published_name(&repo, &name)? {
+        Some(existing) if existing != name => {
+            return Err(PublishError::Forbidden(format!(
+                "A crate named `{}` already exists",
+                existing
+            )));
+        }
+        Some(_) => check_owner(mirror_path, repo_path, user, &name)?,
+        None => {}
+    }
+
+    let mut content = read_index_file(&repo, &file_path)?.unwrap_or_default();
+    for line in content.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+        let line: Value = serde_json::from_slice(line)?;
+        if line["vers"].as_str() == Some(&vers) {
+            return Err(PublishError::Invalid(format!(
+                "{} {} has already been published",
+                name, vers
+            )));
+        }
+    }
+
+    // Write the crate file next to its final place first, and only move it there once the
+    // index lists it, so a failed publish leaves no crate behind.
+    let cksum = format!("{:x}", Sha256::digest(crate_file));
+    let crate_path = private_dir(mirror_path)
+        .join("crates")
+        .join(&name)
+        .join(&vers)
+        .join("download");
+    if let Some(parent) = crate_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let part_path = append_to_path(&crate_path, ".part");
+
+    // A new crate's owner is recorded before the index lists it, so published crates always
+    // have one. If the publish fails, the owner is taken out again.
+    let mut owners = read_owners(mirror_path)?;
+    let is_new = !owners.contains_key(&name);
+    if is_new {
+        owners.insert(name.clone(), vec![user.to_string()]);
+        write_owners(mirror_path, &owners)?;
+    }
+
+    let mut line = serde_json::to_vec(&IndexLine::new(metadata, cksum))?;
+    line.push(b'\n');
+    content.extend_from_slice(&line);
+    let result = fs::write(&part_path, crate_file)
+        .map_err(PublishError::from)
+        .and_then(|()| {
+            commit_index_file(
+                &repo,
+                &file_path,
+                &content,
+                &format!("Publish {} {}", name, vers),
+            )
+        });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&part_path);
+        if is_new {
+            owners.remove(&name);
+            let _ = write_owners(mirror_path, &owners);
+        }
+        return Err(e);
+    }
+    fs::rename(&part_path, &crate_path)?;
+    Ok(())
+}
+
+fn set_yanked(repo_path: &Path, name: &str, vers: &str, yanked: bool) -> Result<(), PublishError> {
+    let repo = Repository::open(repo_path)?;
+    let file_path = index_file_path(name);
+    let not_found = || PublishError::NotFound(format!("{} {} does not exist", name, vers));
+    let content = read_index_file(&repo, &file_path)?.ok_or_else(not_found)?;
+
+    let mut found = false;
+    let mut new_content = vec![];
+    for line in content.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+        let mut entry: Value = serde_json::from_slice(line)?;
+        if entry["vers"].as_str() == Some(vers) {
+            found = true;
+            entry["yanked"] = Value::Bool(yanked);
+            new_content.extend_from_slice(&serde_json::to_vec(&entry)?);
+        } else {
+            new_content.extend_from_slice(line);
+        }
+        new_content.push(b'\n');
+    }
+    if !found {
+        return Err(not_found());
+    }
+
+    if new_content != content {
+        let action = if yanked { "Yank" } else { "Unyank" };
+        commit_index_file(
+            &repo,
+            &file_path,
+            &new_content,
+            &format!("{} {} {}", action, name, vers),
+        )?;
+    }
+    Ok(())
+}
+
+/// Published crates are kept apart from the mirrored ones, so syncs never touch them.
+fn private_dir(mirror_path: &Path) -> PathBuf {
+    mirror_path.join("private")
+}
+
+/// Crate owners are kept in the mirror directory, as they aren't part of the index.
+fn owners_path(mirror_path: &Path) -> PathBuf {
+    mirror_path.join("mirror-private-owners.json")
+}
+
+fn read_owners(mirror_path: &Path) -> Result<BTreeMap<String, Vec<String>>, PublishError> {
+    match fs::read(owners_path(mirror_path)) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_owners(
+    mirror_path: &Path,
+    owners: &BTreeMap<String, Vec<String>>,
+) -> Result<(), PublishError> {
+    let path = owners_path(mirror_path);
+    let part_path = append_to_path(&path, ".part");
+    fs::write(&part_path, serde_json::to_vec_pretty(owners)?)?;
+    fs::rename(&part_path, &path)?;
+    Ok(())
+}
+
+/// Check that a crate exists in the private index, and that `user` owns it.
+fn check_owner(
+    mirror_path: &Path,
+    repo_path: &Path,
+    user: &str,
+    name: &str,
+) -> Result<(), PublishError> {
+    let repo = Repository::open(repo_path)?;
+    if published_name(&repo, name)?.as_deref() != Some(name) {
+        return Err(PublishError::NotFound(format!(
+            "Crate `{}` does not exist",
+            name
+        )));
+    }
+    let owners = read_owners(mirror_path)?;
+    if owners
+        .get(name)
+        .is_some_and(|o| o.iter().any(|u| u == user))
+    {
+        Ok(())
+    } else {
+        Err(PublishError::Forbidden(format!(
+            "`{}` is not an owner of `{}`",
+            user, name
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish_body(metadata: &[u8], crate_file: &[u8]) -> Vec<u8> {
+        let mut body = vec![];
+        body.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+        body.extend_from_slice(metadata);
+        body.extend_from_slice(&(crate_file.len() as u32).to_le_bytes());
+        body.extend_from_slice(crate_file);
+        body
+    }
+
+    fn is_invalid<T>(result: Result<T, PublishError>) -> bool {
+        matches!(result, Err(PublishError::Invalid(_)))
+    }
+
+    static METADATA: &[u8] =
+        br#"{"name":"foo","vers":"0.1.0","deps":[],"features":{},"links":null}"#;
+
+    #[test]
+    fn parses_publish_body() {
+        let body = publish_body(METADATA, b"crate");
+        let (metadata, crate_file) = parse_publish_body(&body).unwrap();
+        assert_eq!(metadata.name, "foo");
+        assert_eq!(metadata.vers, "0.1.0");
+        assert_eq!(crate_file, b"crate");
+    }
+
+    #[test]
+    fn refuses_truncated_publish_body() {
+        let body = publish_body(METADATA, b"crate");
+        for len in &[0, 3, 4, 10, METADATA.len() + 4, body.len() - 1] {
+            assert!(is_invalid(parse_publish_body(&body[..*len])), "{}", len);
+        }
+    }
+
+    #[test]
+    fn refuses_overflowing_lengths() {
+        let mut body = u32::MAX.to_le_bytes().to_vec();
+        body.extend_from_slice(METADATA);
+        assert!(is_invalid(parse_publish_body(&body)));
+
+        let mut body = publish_body(METADATA, b"");
+        let crate_len_at = 4 + METADATA.len();
+        body[crate_len_at..crate_len_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(is_invalid(parse_publish_body(&body)));
+    }
+
+    fn metadata() -> PublishMetadata {
+        serde_json::from_slice(METADATA).unwrap()
+    }
+
+    #[test]
+    fn publishes_with_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo_path = dir.path().join("private-index");
+        init_private_index(&repo_path, b"{}").unwrap();
+
+        publish_crate(dir.path(), &repo_path, "alice", metadata(), b"crate").unwrap();
+        let crate_path = dir.path().join("private/crates/foo/0.1.0/download");
+        assert_eq!(fs::read(crate_path).unwrap(), b"crate");
+        assert_eq!(read_owners(dir.path()).unwrap()["foo"], ["alice"]);
+        check_owner(dir.path(), &repo_path, "alice", "foo").unwrap();
+
+        // Other users can't publish new versions.
+        let mut metadata = metadata();
+        metadata.vers = "0.2.0".to_string();
+        assert!(matches!(
+            publish_crate(dir.path(), &repo_path, "mallory", metadata, b"crate"),
+            Err(PublishError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn removes_owner_of_failed_publish() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo_path = dir.path().join("private-index");
+        init_private_index(&repo_path, b"{}").unwrap();
+
+        // A directory in the way of the crate file makes the publish fail after the owner
+        // was recorded.
+        fs::create_dir_all(dir.path().join("private/crates/foo/0.1.0/download.part")).unwrap();
+        assert!(publish_crate(dir.path(), &repo_path, "alice", metadata(), b"crate").is_err());
+        assert!(!read_owners(dir.path()).unwrap().contains_key("foo"));
+        let repo = Repository::open(&repo_path).unwrap();
+        assert_eq!(published_name(&repo, "foo").unwrap(), None);
+    }
+
+    #[test]
+    fn refuses_bad_metadata() {
+        let body = publish_body(b"{\"name\":", b"crate");
+        assert!(matches!(
+            parse_publish_body(&body),
+            Err(PublishError::Json(_))
+        ));
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use reqwest::header::HeaderValue;
+use tokio::sync::OwnedMutexGuard;
+
+use crate::cache::{cache_control, CACHE_REVALIDATE};
+use crate::crates::{crates_source, find_crate_entry, sync_one_crate_entry, SyncError};
+use crate::download::{download, download_with_sha256_file, DownloadError};
+use crate::mirror::{default_user_agent, user_agent_string, Mirror};
+
+/// How long a pulled file that upstream replaces, like a channel manifest, is served before
+/// it's fetched again. A failed fetch also waits this long before the next try.
+static MUTABLE_FILE_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Fetches files missing from the mirror from upstream, as they're requested.
+pub struct PullThrough {
+    /// Upstream crates source, or None for the crates.io CDN.
+    crates_source: Option<String>,
+    /// Upstream rustup source, if rustup is mirrored.
+    rustup_source: Option<String>,
+    retries: usize,
+    user_agent: HeaderValue,
+    /// Files being fetched right now, so concurrent requests for one file only fetch it once.
+    in_flight: Mutex<HashMap<PathBuf, InFlight>>,
+    /// When files that upstream replaces were last fetched, or tried, by this server.
+    /// Files written by syncs aren't in here, so they're left alone until the next sync.
+    last_fetched: Mutex<HashMap<PathBuf, SystemTime>>,
+}
+
+/// A file being fetched, and the number of requests fetching or waiting for it.
+/// The last request removes it from `PullThrough::in_flight`.
+#[derive(Default)]
+struct InFlight {
+    mutex: Arc<tokio::sync::Mutex<()>>,
+    requests: usize,
+}
+
+/// A request's turn to fetch a file, see `PullThrough::lock`.
+struct InFlightGuard<'a> {
+    pull_through: &'a PullThrough,
+    file_path: PathBuf,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.guard = None;
+        let mut in_flight = self.pull_through.in_flight.lock().unwrap();
+        if let Some(file) = in_flight.get_mut(&self.file_path) {
+            file.requests -= 1;
+            if file.requests == 0 {
+                in_flight.remove(&self.file_path);
+            }
+        }
+    }
+}
+
+impl PullThrough {
+    pub fn new(mirror: &Mirror) -> PullThrough {
+        let user_agent =
+            HeaderValue::from_str(&user_agent_string(&mirror.mirror)).unwrap_or_else(|_| {
+                eprintln!("Your contact information contains invalid characters!");
+                HeaderValue::from_str(&default_user_agent())
+                    .expect("Default user agent should be a valid header")
+            });
+        PullThrough {
+            crates_source: mirror
+                .crates
+                .as_ref()
+                .and_then(|crates| crates_source(crates).map(|s| s.to_string())),
+            rustup_source: mirror.rustup.as_ref().map(|rustup| rustup.source.clone()),
+            retries: mirror.mirror.retries,
+            user_agent,
+            in_flight: Mutex::new(HashMap::new()),
+            last_fetched: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fetch a crate file, verifying it against the checksum in the mirror's index.
+    pub async fn fetch_crate(
+        &self,
+        mirror_path: &Path,
+        name: &str,
+        vers: &str,
+        file_path: &Path,
+    ) -> Result<(), SyncError> {
+        let _guard = self.lock(file_path).await;
+        if file_exists(file_path).await {
+            // Another request fetched it while we were waiting.
+            return Ok(());
+        }
+
+        let mirror_path = mirror_path.to_owned();
+        let name = name.to_string();
+        let vers = vers.to_string();
+        let source = self.crates_source.clone();
+        let retries = self.retries;
+        let user_agent = self.user_agent.clone();
+        spawn_blocking(move || {
+            let crate_entry = match find_crate_entry(&mirror_path, &name, &vers)? {
+                Some(crate_entry) => crate_entry,
+                None => {
+                    return Err(DownloadError::NotFound(
+                        404,
+                        format!("{} {}", name, vers),
+                        "Not in the crates.io-index".to_string(),
+                    )
+                    .into())
+                }
+            };
+            eprintln!("Fetching crate {} {} from upstream", name, vers);
+            sync_one_crate_entry(
+                &mirror_path,
+                source.as_deref(),
+                retries,
+                &crate_entry,
+                false,
+                &user_agent,
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Fetch a file under `dist/` or `rustup/`, verifying it against its `.sha256` file.
+    pub async fn fetch_rustup_file(
+        &self,
+        url_path: &str,
+        file_path: &Path,
+    ) -> Result<(), SyncError> {
+        let source = match &self.rustup_source {
+            Some(source) => source.clone(),
+            None => {
+                return Err(DownloadError::NotFound(
+                    404,
+                    url_path.to_string(),
+                    "Rustup isn't mirrored".to_string(),
+                )
+                .into())
+            }
+        };
+
+        let _guard = self.lock(file_path).await;
+        if !self.needs_rustup_fetch(url_path, file_path).await {
+            // Another request fetched it while we were waiting.
+            return Ok(());
+        }
+        let refresh = file_exists(file_path).await;
+        if cache_control(url_path) == CACHE_REVALIDATE {
+            // Recorded before trying, so a failure also waits for the TTL, and the stale copy
+            // is served in the meantime.
+            self.last_fetched
+                .lock()
+                .unwrap()
+                .insert(file_path.to_owned(), SystemTime::now());
+        }
+
+        let url = format!("{}{}", source, url_path);
+        let file_path = file_path.to_owned();
+        let retries = self.retries;
+        let user_agent = self.user_agent.clone();
+        spawn_blocking(move || {
+            eprintln!("Fetching {} from upstream", url);
+            // Hash and signature files have nothing to be verified against.
+            // A stale file is replaced once the new one is complete, so it can be served until then.
+            if url.ends_with(".sha256") || url.ends_with(".asc") {
+                download(&url, &file_path, None, retries, refresh, &user_agent)?;
+            } else {
+                download_with_sha256_file(&url, &file_path, retries, refresh, &user_agent)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Check whether a file under `dist/` or `rustup/` should be fetched from upstream: it's
+    /// missing, or it's one that upstream replaces, like a channel manifest, that this server
+    /// pulled and that has gone stale.
+    pub async fn needs_rustup_fetch(&self, url_path: &str, file_path: &Path) -> bool {
+        if !file_exists(file_path).await {
+            return true;
+        }
+        if cache_control(url_path) != CACHE_REVALIDATE {
+            return false;
+        }
+        let last_fetched = self.last_fetched.lock().unwrap().get(file_path).copied();
+        is_refetch_due(last_fetched, SystemTime::now())
+    }
+
+    /// Wait for our turn to fetch a file.
+    async fn lock(&self, file_path: &Path) -> InFlightGuard<'_> {
+        let mutex = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            let file = in_flight.entry(file_path.to_owned()).or_default();
+            file.requests += 1;
+            file.mutex.clone()
+        };
+        // If this request is cancelled while waiting, its guard still cleans up the entry.
+        let mut in_flight = InFlightGuard {
+            pull_through: self,
+            file_path: file_path.to_owned(),
+            guard: None,
+        };
+        in_flight.guard = Some(mutex.lock_owned().await);
+        in_flight
+    }
+}
+
+/// Check whether a file exists, without blocking.
+pub async fn file_exists(path: &Path) -> bool {
+    tokio::fs::metadata(path).await.is_ok()
+}
+
+/// Whether a pulled file that upstream replaces is due to be fetched again. Files that were
+/// never pulled, `None`, came from a sync and aren't.
+fn is_refetch_due(last_fetched: Option<SystemTime>, now: SystemTime) -> bool {
+    last_fetched
+        .and_then(|last_fetched| now.duration_since(last_fetched).ok())
+        .is_some_and(|age| age > MUTABLE_FILE_TTL)
+}
+
+async fn spawn_blocking<F>(f: F) -> Result<(), SyncError>
+where
+    F: FnOnce() -> Result<(), SyncError> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) => Err(SyncError::Io(e.into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pull_through(rustup_source: &str) -> PullThrough {
+        PullThrough {
+            crates_source: None,
+            rustup_source: Some(rustup_source.to_string()),
+            retries: 0,
+            user_agent: HeaderValue::from_static("panamax-test"),
+            in_flight: Mutex::new(HashMap::new()),
+            last_fetched: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn refetches_pulled_files_after_the_ttl() {
+        let now = SystemTime::now();
+        assert!(!is_refetch_due(None, now));
+        assert!(!is_refetch_due(Some(now), now));
+        assert!(!is_refetch_due(Some(now - MUTABLE_FILE_TTL), now));
+        assert!(is_refetch_due(
+            Some(now - MUTABLE_FILE_TTL - Duration::from_secs(1)),
+            now
+        ));
+        // A clock that went backwards doesn't make files stale.
+        assert!(!is_refetch_due(Some(now + Duration::from_secs(60)), now));
+    }
+
+    #[tokio::test]
+    async fn decides_what_to_fetch() {
+        let dir = tempfile::tempdir().unwrap();
+        let pull_through = pull_through("http://127.0.0.1:1");
+        let manifest = dir.path().join("channel-rust-stable.toml");
+        let archive = dir.path().join("rust.tar.xz");
+        let stale = SystemTime::now() - MUTABLE_FILE_TTL - Duration::from_secs(1);
+
+        // Missing files are always fetched.
+        assert!(
+            pull_through
+                .needs_rustup_fetch("/dist/channel-rust-stable.toml", &manifest)
+                .await
+        );
+
+        // Files from a sync are left alone, even if they'd be stale.
+        std::fs::write(&manifest, "synced").unwrap();
+        assert!(
+            !pull_through
+                .needs_rustup_fetch("/dist/channel-rust-stable.toml", &manifest)
+                .await
+        );
+
+        // Stale pulled files are fetched again, but dated ones never change.
+        std::fs::write(&archive, "archive").unwrap();
+        for path in [&manifest, &archive] {
+            pull_through
+                .last_fetched
+                .lock()
+                .unwrap()
+                .insert(path.clone(), stale);
+        }
+        assert!(
+            pull_through
+                .needs_rustup_fetch("/dist/channel-rust-stable.toml", &manifest)
+                .await
+        );
+        assert!(
+            !pull_through
+                .needs_rustup_fetch("/dist/2020-01-30/rust.tar.xz", &archive)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_stale_copy_when_upstream_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let pull_through = pull_through("http://127.0.0.1:1");
+        let url_path = "/dist/channel-rust-stable.toml";
+        let manifest = dir.path().join("channel-rust-stable.toml");
+        std::fs::write(&manifest, "stale").unwrap();
+        pull_through.last_fetched.lock().unwrap().insert(
+            manifest.clone(),
+            SystemTime::now() - MUTABLE_FILE_TTL - Duration::from_secs(1),
+        );
+
+        assert!(pull_through
+            .fetch_rustup_file(url_path, &manifest)
+            .await
+            .is_err());
+        assert_eq!(std::fs::read_to_string(&manifest).unwrap(), "stale");
+        // The failure counts as a try, so the next requests get the stale copy right away.
+        assert!(!pull_through.needs_rustup_fetch(url_path, &manifest).await);
+    }
+}
@@ -0,0 +1,218 @@
+use std::time::SystemTime;
+
+use hyper::header::{HeaderMap, IF_RANGE, RANGE};
+
+/// More ranges than this in one request are ignored, and the whole file is served instead.
+static MAX_RANGES: usize = 32;
+
+/// How to answer a request's Range header.
+#[derive(Debug, PartialEq)]
+pub enum Ranges {
+    /// No usable Range header, serve the whole file.
+    Full,
+    /// None of the ranges overlap the file.
+    Unsatisfiable,
+    /// Serve these parts of the file, as (first byte, last byte) pairs.
+    Partial(Vec<(u64, u64)>),
+}
+
+/// Work out which parts of a file of `len` bytes the request asks for.
+///
+/// If-Range is honored: if the file changed since the client's partial copy, the whole file
+/// is served.
+pub fn requested_ranges(
+    headers: &HeaderMap,
+    len: u64,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> Ranges {
+    let range = match headers.get(RANGE).and_then(|h| h.to_str().ok()) {
+        Some(range) => range,
+        None => return Ranges::Full,
+    };
+
+    if let Some(if_range) = headers.get(IF_RANGE).and_then(|h| h.to_str().ok()) {
+        let if_range = if_range.trim();
+        let matches = if if_range.starts_with('"') {
+            Some(if_range) == etag
+        } else {
+            match (httpdate::parse_http_date(if_range), last_modified) {
+                (Ok(date), Some(last_modified)) => {
+                    httpdate::fmt_http_date(last_modified) == httpdate::fmt_http_date(date)
+                }
+                _ => false,
+            }
+        };
+        if !matches {
+            return Ranges::Full;
+        }
+    }
+
+    parse_range(range, len)
+}
+
+/// Parse a Range header, like `bytes=0-499, 1000-, -500`.
+///
+/// Malformed headers are ignored, as RFC 7233 allows.
+fn parse_range(range: &str, len: u64) -> Ranges {
+    let specs = match range.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return Ranges::Full,
+    };
+
+    let specs: Vec<&str> = specs
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect();
+    if specs.is_empty() {
+        return Ranges::Full;
+    }
+
+    let mut ranges = vec![];
+    for spec in specs {
+        let mut parts = spec.splitn(2, '-');
+        let first = parts.next().unwrap_or("").trim();
+        let last = match parts.next() {
+            Some(last) => last.trim(),
+            None => return Ranges::Full,
+        };
+
+        let range = if first.is_empty() {
+            // A suffix range: the last N bytes.
+            let suffix: u64 = match last.parse() {
+                Ok(suffix) => suffix,
+                Err(_) => return Ranges::Full,
+            };
+            if suffix == 0 || len == 0 {
+                None
+            } else {
+                Some((len.saturating_sub(suffix), len - 1))
+            }
+        } else {
+            let first: u64 = match first.parse() {
+                Ok(first) => first,
+                Err(_) => return Ranges::Full,
+            };
+            let last: u64 = if last.is_empty() {
+                u64::MAX
+            } else {
+                match last.parse() {
+                    Ok(last) => last,
+                    Err(_) => return Ranges::Full,
+                }
+            };
+            if last < first {
+                return Ranges::Full;
+            }
+            if first >= len {
+                None
+            } else {
+                Some((first, last.min(len - 1)))
+            }
+        };
+
+        if let Some(range) = range {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        Ranges::Full
+    } else if ranges.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        Ranges::Partial(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-499, 1000-, -500", 2000),
+            Ranges::Partial(vec![(0, 499), (1000, 1999), (1500, 1999)])
+        );
+        assert_eq!(
+            parse_range("bytes=0-9999", 100),
+            Ranges::Partial(vec![(0, 99)])
+        );
+        assert_eq!(
+            parse_range("bytes=-500", 100),
+            Ranges::Partial(vec![(0, 99)])
+        );
+    }
+
+    #[test]
+    fn ignores_malformed_ranges() {
+        for range in &[
+            "",
+            "bytes=",
+            "bytes=,",
+            "items=0-10",
+            "bytes=10",
+            "bytes=abc-10",
+            "bytes=0-abc",
+            "bytes=10-5",
+            "bytes=-",
+            "bytes=--5",
+        ] {
+            assert_eq!(parse_range(range, 100), Ranges::Full, "{:?}", range);
+        }
+    }
+
+    #[test]
+    fn ignores_overflowing_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-18446744073709551616", 100),
+            Ranges::Full
+        );
+        assert_eq!(
+            parse_range("bytes=-18446744073709551616", 100),
+            Ranges::Full
+        );
+        assert_eq!(
+            parse_range(&format!("bytes=0-{}", u64::MAX), 100),
+            Ranges::Partial(vec![(0, 99)])
+        );
+        assert_eq!(
+            parse_range(&format!("bytes=-{}", u64::MAX), 100),
+            Ranges::Partial(vec![(0, 99)])
+        );
+    }
+
+    #[test]
+    fn reports_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=100-", 100), Ranges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 100), Ranges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), Ranges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_too_many_ranges() {
+        let many = (0..=MAX_RANGES)
+            .map(|i| format!("{}-{}", i, i))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(parse_range(&format!("bytes={}", many), 100), Ranges::Full);
+    }
+
+    #[test]
+    fn honors_if_range() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, "bytes=0-9".parse().unwrap());
+        headers.insert(IF_RANGE, "\"abc\"".parse().unwrap());
+        assert_eq!(
+            requested_ranges(&headers, 100, Some("\"abc\""), None),
+            Ranges::Partial(vec![(0, 9)])
+        );
+        assert_eq!(
+            requested_ranges(&headers, 100, Some("\"def\""), None),
+            Ranges::Full
+        );
+    }
+}
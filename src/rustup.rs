@@ -1,16 +1,16 @@
 use crate::download::{
     append_to_path, download, download_with_sha256_file, move_if_exists,
-    move_if_exists_with_sha256, write_file_create_dir, DownloadError,
+    move_if_exists_with_sha256, write_file_create_dir, DownloadError, FailedDownload,
 };
-use crate::mirror::{MirrorError, MirrorSection, RustupSection};
+use crate::mirror::{MirrorError, MirrorSection, RustupSection, SyncReport};
 use crate::progress_bar::{progress_bar, ProgressBarMessage};
 use console::style;
 use reqwest::header::HeaderValue;
 use scoped_threadpool::Pool;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::{fs, io};
 
 // Note: These platforms should match https://github.com/rust-lang/rustup.rs#other-installation-methods
@@ -117,66 +117,52 @@ pub fn get_platforms_exe(target_platform: Option<&str>) -> Vec<String> {
     }
 }
 
-/// Synchronize one rustup-init file.
-pub fn sync_one_init(
+/// Get the source URL and local path of one rustup-init file.
+pub fn rustup_init_location(
     path: &Path,
     source: &str,
     platform: &str,
     archive_version: Option<&str>,
     is_exe: bool,
-    retries: usize,
-    user_agent: &HeaderValue,
-) -> Result<(), DownloadError> {
+) -> (String, PathBuf) {
+    let file_name = if is_exe {
+        "rustup-init.exe"
+    } else {
+        "rustup-init"
+    };
+
     if let Some(archive_version) = archive_version {
         // get from "/rustup/archive/{version}/{platform}/rustup-init"
-        let path = path
+        let local_path = path
             .join("rustup")
             .join("archive")
             .join(archive_version)
-            .join(platform);
+            .join(platform)
+            .join(file_name);
 
-        let local_path = if is_exe {
-            path.join("rustup-init.exe")
-        } else {
-            path.join("rustup-init")
-        };
-
-        let source_url = if is_exe {
-            format!(
-                "{}/rustup/archive/{}/{}/rustup-init.exe",
-                source, archive_version, platform
-            )
-        } else {
-            format!(
-                "{}/rustup/archive/{}/{}/rustup-init",
-                source, archive_version, platform
-            )
-        };
+        let source_url = format!(
+            "{}/rustup/archive/{}/{}/{}",
+            source, archive_version, platform, file_name
+        );
 
-        download_with_sha256_file(&source_url, &local_path, retries, false, user_agent)?;
+        (source_url, local_path)
     } else {
         // get from "/rustup/dist/{platform}/rustup-init"
-        let path = path.join("rustup").join("dist").join(platform);
-
-        let local_path = if is_exe {
-            path.join("rustup-init.exe")
-        } else {
-            path.join("rustup-init")
-        };
+        let local_path = path
+            .join("rustup")
+            .join("dist")
+            .join(platform)
+            .join(file_name);
 
-        let source_url = if is_exe {
-            format!("{}/rustup/dist/{}/rustup-init.exe", source, platform)
-        } else {
-            format!("{}/rustup/dist/{}/rustup-init", source, platform)
-        };
+        let source_url = format!("{}/rustup/dist/{}/{}", source, platform, file_name);
 
-        download_with_sha256_file(&source_url, &local_path, retries, false, user_agent)?;
+        (source_url, local_path)
     }
-
-    Ok(())
 }
 
 /// Synchronize all rustup-init files.
+///
+/// Returns the downloads that failed, if the rest of the sync could go on.
 pub fn sync_rustup_init(
     path: &Path,
     source: &str,
@@ -185,7 +171,7 @@ pub fn sync_rustup_init(
     threads: usize,
     retries: usize,
     user_agent: &HeaderValue,
-) -> Result<(), SyncError> {
+) -> Result<Vec<FailedDownload>, SyncError> {
     let mut all_platforms = vec![];
     all_platforms.append(&mut get_platforms(platform.as_deref()));
     all_platforms.append(&mut get_platforms_exe(platform.as_deref()));
@@ -214,26 +200,34 @@ pub fn sync_rustup_init(
 
     let count = all_platforms.len() * tags.len();
     let (pb_thread, sender) = progress_bar(Some(count), prefix);
-    let errors_occurred = AtomicUsize::new(0);
+    let failed_downloads = Mutex::new(vec![]);
 
     Pool::new(threads as u32).scoped(|scoped| {
-        let error_occurred = &errors_occurred;
+        let failed_downloads = &failed_downloads;
         for platform in &all_platforms {
             for tag in &tags {
                 let tag = tag.as_deref();
                 let s = sender.clone();
                 scoped.execute(move || {
                     let is_exe = PLATFORMS_EXE.contains(&&platform.as_str());
-                    if let Err(e) =
-                        sync_one_init(path, source, &platform, tag, is_exe, retries, user_agent)
-                    {
+                    let (source_url, local_path) =
+                        rustup_init_location(path, source, platform, tag, is_exe);
+                    if let Err(e) = download_with_sha256_file(
+                        &source_url,
+                        &local_path,
+                        retries,
+                        false,
+                        user_agent,
+                    ) {
                         s.send(ProgressBarMessage::Println(format!(
                             "Downloading {} failed: {:?}",
-                            path.display(),
-                            e
+                            source_url, e
                         )))
                         .expect("Channel send should not fail");
-                        error_occurred.fetch_add(1, Ordering::Release);
+                        failed_downloads
+                            .lock()
+                            .expect("Mutex lock should not fail")
+                            .push(FailedDownload::new(&source_url, &e));
                     }
                     s.send(ProgressBarMessage::Increment)
                         .expect("Channel send should not fail");
@@ -247,12 +241,9 @@ pub fn sync_rustup_init(
         .expect("Channel send should not fail");
     pb_thread.join().expect("Thread join should not fail");
 
-    let errors = errors_occurred.load(Ordering::Acquire);
-    if errors == 0 {
-        Ok(())
-    } else {
-        Err(SyncError::FailedDownloads(errors))
-    }
+    Ok(failed_downloads
+        .into_inner()
+        .expect("Mutex into_inner should not fail"))
 }
 
 #[derive(Deserialize, Debug)]
@@ -494,6 +485,7 @@ pub fn sync_rustup_channel(
     channel: &str,
     retries: usize,
     user_agent: &HeaderValue,
+    failed: &mut Vec<FailedDownload>,
 ) -> Result<(), SyncError> {
     // Download channel file
     let channel_url = format!("{}/dist/channel-rust-{}.toml", source, channel);
@@ -539,24 +531,27 @@ pub fn sync_rustup_channel(
     // Create progress bar
     let (pb_thread, sender) = progress_bar(Some(files.len()), prefix);
 
-    let errors_occurred = AtomicUsize::new(0);
+    let failed_downloads = Mutex::new(vec![]);
 
     // Download files
     Pool::new(threads as u32).scoped(|scoped| {
-        let error_occurred = &errors_occurred;
+        let failed_downloads = &failed_downloads;
         for (url, hash) in &files {
             let s = sender.clone();
             scoped.execute(move || {
                 if let Err(e) =
                     sync_one_rustup_target(&path, &source, &url, &hash, retries, user_agent)
                 {
+                    let source_url = format!("{}/{}", source, url);
                     s.send(ProgressBarMessage::Println(format!(
                         "Downloading {} failed: {:?}",
-                        path.display(),
-                        e
+                        source_url, e
                     )))
                     .expect("Channel send should not fail");
-                    error_occurred.fetch_add(1, Ordering::Release);
+                    failed_downloads
+                        .lock()
+                        .expect("Mutex lock should not fail")
+                        .push(FailedDownload::new(&source_url, &e));
                 }
                 s.send(ProgressBarMessage::Increment)
                     .expect("Channel send should not fail");
@@ -570,7 +565,12 @@ pub fn sync_rustup_channel(
         .expect("Channel send should not fail");
     pb_thread.join().expect("Thread join should not fail");
 
-    let errors = errors_occurred.load(Ordering::Acquire);
+    let failed_downloads = failed_downloads
+        .into_inner()
+        .expect("Mutex into_inner should not fail");
+    let errors = failed_downloads.len();
+    failed.extend(failed_downloads);
+
     if errors == 0 {
         // Write channel history file
         add_to_channel_history(path, channel, &date, &files)?;
@@ -582,18 +582,33 @@ pub fn sync_rustup_channel(
     }
 }
 
+/// Record a failed phase of the rustup sync in the sync report.
+fn report_failure(
+    report: &mut SyncReport,
+    phase: &str,
+    e: &SyncError,
+    failed: Vec<FailedDownload>,
+) {
+    let count = match e {
+        SyncError::FailedDownloads(count) => *count,
+        _ => 1,
+    };
+    report.add_failure("rustup", phase, count, format!("{:?}", e), failed);
+}
+
 /// Synchronize rustup.
 pub fn sync(
     path: &Path,
     mirror: &MirrorSection,
     rustup: &RustupSection,
     user_agent: &HeaderValue,
+    report: &mut SyncReport,
 ) -> Result<(), MirrorError> {
     eprintln!("{}", style("Syncing Rustup repositories...").bold());
 
     // Mirror rustup-init
     let prefix = format!("{} Syncing rustup-init files...", style("[1/5]").bold());
-    if let Err(e) = sync_rustup_init(
+    let result = sync_rustup_init(
         path,
         &rustup.source,
         &rustup.target_platform,
@@ -601,9 +616,16 @@ pub fn sync(
         rustup.download_threads,
         mirror.retries,
         user_agent,
-    ) {
+    );
+    let (e, failed) = match result {
+        Ok(failed) if failed.is_empty() => (None, failed),
+        Ok(failed) => (Some(SyncError::FailedDownloads(failed.len())), failed),
+        Err(e) => (Some(e), vec![]),
+    };
+    if let Some(e) = e {
         eprintln!("Downloading rustup init files failed: {:?}", e);
         eprintln!("You will need to sync again to finish this download.");
+        report_failure(report, "rustup-init", &e, failed);
     }
 
     let mut failures = false;
@@ -611,6 +633,7 @@ pub fn sync(
     // Mirror stable
     if rustup.keep_latest_stables != Some(0) {
         let prefix = format!("{} Syncing latest stable...    ", style("[2/5]").bold());
+        let mut failed = vec![];
         if let Err(e) = sync_rustup_channel(
             path,
             &rustup.source,
@@ -621,10 +644,12 @@ pub fn sync(
             "stable",
             mirror.retries,
             user_agent,
+            &mut failed,
         ) {
             failures = true;
             eprintln!("Downloading stable release failed: {:?}", e);
             eprintln!("You will need to sync again to finish this download.");
+            report_failure(report, "stable", &e, failed);
         }
     } else {
         eprintln!("{} Skipping syncing stable.", style("[2/5]").bold());
@@ -633,6 +658,7 @@ pub fn sync(
     // Mirror beta
     if rustup.keep_latest_betas != Some(0) {
         let prefix = format!("{} Syncing latest beta...      ", style("[3/5]").bold());
+        let mut failed = vec![];
         if let Err(e) = sync_rustup_channel(
             path,
             &rustup.source,
@@ -643,10 +669,12 @@ pub fn sync(
             "beta",
             mirror.retries,
             user_agent,
+            &mut failed,
         ) {
             failures = true;
             eprintln!("Downloading beta release failed: {:?}", e);
             eprintln!("You will need to sync again to finish this download.");
+            report_failure(report, "beta", &e, failed);
         }
     } else {
         eprintln!("{} Skipping syncing beta.", style("[3/5]").bold());
@@ -655,6 +683,7 @@ pub fn sync(
     // Mirror nightly
     if rustup.keep_latest_nightlies != Some(0) {
         let prefix = format!("{} Syncing latest nightly...   ", style("[4/5]").bold());
+        let mut failed = vec![];
         if let Err(e) = sync_rustup_channel(
             path,
             &rustup.source,
@@ -665,10 +694,12 @@ pub fn sync(
             "nightly",
             mirror.retries,
             user_agent,
+            &mut failed,
         ) {
             failures = true;
             eprintln!("Downloading nightly release failed: {:?}", e);
             eprintln!("You will need to sync again to finish this download.");
+            report_failure(report, "nightly", &e, failed);
         }
     } else {
         eprintln!("{} Skipping syncing nightly.", style("[4/5]").bold());
@@ -696,6 +727,7 @@ pub fn sync(
         ) {
             eprintln!("Cleaning old files failed: {:?}", e);
             eprintln!("You may need to sync again to clean these files.");
+            report_failure(report, "clean", &e, vec![]);
         }
     }
 
@@ -0,0 +1,143 @@
+use std::path::{Path, PathBuf};
+
+use url::percent_encoding::percent_decode;
+
+/// Check that a path segment is a plain file or directory name.
+///
+/// Empty segments, `.` and `..`, hidden files (like `.git`) and anything with a path
+/// separator are refused.
+pub fn is_plain_segment(segment: &str) -> bool {
+    !segment.is_empty() && !segment.starts_with('.') && !segment.contains(['/', '\\', '\0', ':'])
+}
+
+/// Split a URL path into percent-decoded segments, if they're all plain names.
+pub fn url_path_segments(url_path: &str) -> Option<Vec<String>> {
+    url_path
+        .trim_start_matches('/')
+        .split('/')
+        .map(|segment| {
+            let segment = percent_decode(segment.as_bytes()).decode_utf8().ok()?;
+            if is_plain_segment(&segment) {
+                Some(segment.into_owned())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Check that a crate name only uses the characters crates.io allows.
+pub fn is_valid_crate_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Check that a crate version only uses characters found in semver versions.
+pub fn is_valid_crate_version(version: &str) -> bool {
+    is_plain_segment(version)
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '+')
+}
+
+/// Resolve symlinks in a file's path, and check that it's still inside `dir`.
+///
+/// Returns None if the file doesn't exist, or if a symlink leads outside of `dir`.
+/// `dir` itself may be a symlink, so the mirror's directories can live on other disks.
+pub async fn resolve_inside(dir: &Path, file_path: &Path) -> Option<PathBuf> {
+    let dir = tokio::fs::canonicalize(dir).await.ok()?;
+    let file_path = tokio::fs::canonicalize(file_path).await.ok()?;
+    if file_path.starts_with(&dir) {
+        Some(file_path)
+    } else {
+        eprintln!(
+            "Refusing to serve {:?}, it's outside of {:?}",
+            file_path, dir
+        );
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_plain_url_paths() {
+        assert_eq!(
+            url_path_segments("/crates/serde/1.0.0/download"),
+            Some(vec![
+                "crates".to_string(),
+                "serde".to_string(),
+                "1.0.0".to_string(),
+                "download".to_string(),
+            ])
+        );
+        assert_eq!(url_path_segments("/a%20b"), Some(vec!["a b".to_string()]));
+    }
+
+    #[test]
+    fn refuses_traversal_in_url_paths() {
+        for url_path in &[
+            "/",
+            "/crates//serde",
+            "/crates/../config",
+            "/crates/%2e%2e/config",
+            "/crates/..%2fconfig",
+            "/crates/..%5cconfig",
+            "/crates/%2fetc",
+            "/crates/a%00b",
+            "/crates/c:",
+            "/.git/config",
+            "/crates/%ff",
+        ] {
+            assert_eq!(url_path_segments(url_path), None, "{:?}", url_path);
+        }
+    }
+
+    #[test]
+    fn checks_crate_names_and_versions() {
+        assert!(is_valid_crate_name("serde_json-2"));
+        assert!(!is_valid_crate_name(""));
+        assert!(!is_valid_crate_name("../serde"));
+        assert!(!is_valid_crate_name("serde json"));
+
+        assert!(is_valid_crate_version("1.0.0-beta.1+build"));
+        assert!(!is_valid_crate_version(""));
+        assert!(!is_valid_crate_version(".."));
+        assert!(!is_valid_crate_version("1.0/../.."));
+    }
+
+    #[tokio::test]
+    async fn resolves_files_inside_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+
+        assert!(resolve_inside(dir.path(), &file).await.is_some());
+        assert!(resolve_inside(dir.path(), &dir.path().join("missing"))
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_files_outside_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("dir");
+        std::fs::create_dir(&dir).unwrap();
+        let outside = root.path().join("outside");
+        std::fs::write(&outside, b"").unwrap();
+
+        assert!(resolve_inside(&dir, &dir.join("..").join("outside"))
+            .await
+            .is_none());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&outside, dir.join("link")).unwrap();
+            assert!(resolve_inside(&dir, &dir.join("link")).await.is_none());
+        }
+    }
+}
@@ -1,188 +1,1116 @@
 // substantial portion from `cargo-cacher:/src/main.rs`
 // https://github.com/ChrisMacNaughton/cargo-cacher
 
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::io::{self, SeekFrom};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use iron::prelude::*;
-use iron::status;
-use router::Router;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, ETAG, HOST, LAST_MODIFIED, LOCATION,
+};
+use hyper::http::uri::Authority;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::{mpsc, watch};
+use tokio_util::io::ReaderStream;
+use url::percent_encoding::percent_decode;
 
 use crate::{
-    middleware::cors::CorsMiddleware,
-    mirror::{MirrorError, ServeSection},
+    access_log::{AccessLog, AccessLogFormat, RequestInfo},
+    api::Api,
+    browse::{diff_text, file_text, listing_html, read_crate, CrateFile},
+    cache::{cache_control, is_not_modified, EtagCache, CACHE_IMMUTABLE},
+    crates::crate_exists,
+    landing::MirrorStatus,
+    listener::{accept_loop, BindAddr, Listener},
+    metrics::{measure_body, route_name, Metrics},
+    middleware::{
+        auth::Auth,
+        cors::{cors, is_preflight, preflight_response},
+    },
+    mirror::{check_registries, GitBackend, Mirror, MirrorError, ServeSection, StatsAccess},
+    publish::PrivateRegistry,
+    pull_through::{file_exists, PullThrough},
+    range::{requested_ranges, Ranges},
+    safe_path::{
+        is_plain_segment, is_valid_crate_name, is_valid_crate_version, resolve_inside,
+        url_path_segments,
+    },
+    stats::{CargoRequest, Stats},
 };
 
-#[derive(Clone, Debug)]
-pub struct CargoRequest {
-    /// crate name, ex: cargo-cacher
-    name: String,
-    /// major.minor.patch
-    version: String,
-    /// Cache hit?
-    hit: bool,
-    /// Filesize in bytes
-    size: i64,
+/// How often the download statistics are saved while serving.
+static STATS_SAVE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Everything a request handler needs to know about the mirror.
+struct ServeState {
+    path: PathBuf,
+    git_backend: GitBackend,
+    auth: Option<Auth>,
+    access_log: AccessLog,
+    stats: Stats,
+    stats_access: StatsAccess,
+    metrics: Metrics,
+    pull_through: Option<PullThrough>,
+    api: Api,
+    private_registry: Option<PrivateRegistry>,
+    /// Other mirrored registries, by name, with their directories.
+    registries: Vec<(String, PathBuf)>,
+    etags: EtagCache,
+    /// Top-level directories of the mirror that are served as plain files.
+    static_dirs: Vec<String>,
+    /// "https" if serving over TLS, "http" otherwise.
+    scheme: &'static str,
+    /// URL where the server can be accessed from, based on `base_url` in mirror.toml.
+    server_url: Option<String>,
 }
 
 pub fn serve(path: &Path) -> Result<(), MirrorError> {
-    let serve: ServeSection;
-    match crate::mirror::load_mirror_toml(path)?.serve {
-        Some(serve_section) => {
-            serve = serve_section;
+    let mirror = crate::mirror::load_mirror_toml(path)?;
+    let serve = match &mirror.serve {
+        Some(serve_section) => serve_section,
+        None => return Err(MirrorError::MissingServeSection),
+    };
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(worker_threads) = serve.worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
+    if let Some(blocking_threads) = serve.blocking_threads {
+        runtime.max_blocking_threads(blocking_threads);
+    }
+
+    let runtime = runtime.build()?;
+    let result = runtime.block_on(run_server(path, &mirror, serve));
+    // Dropping the runtime would wait for blocking tasks (like packs still being built for
+    // clients we stopped waiting for), so don't wait long for them.
+    runtime.shutdown_timeout(Duration::from_secs(1));
+    result
+}
+
+async fn run_server(path: &Path, mirror: &Mirror, serve: &ServeSection) -> Result<(), MirrorError> {
+    let static_dirs = serve
+        .static_dirs
+        .clone()
+        .unwrap_or_else(|| vec!["dist".to_string(), "rustup".to_string()]);
+    for dir in &static_dirs {
+        // The index and the crates have their own routes, and nothing else should be reachable.
+        if !is_plain_segment(dir) || dir == "crates.io-index" || dir == "crates" || dir == "private"
+        {
+            return Err(MirrorError::BadStaticDir(dir.clone()));
         }
-        _ => panic!("serve section of the Mirror.toml is missing."),
     }
 
-    // own path to use in request processing
-    let path = path.clone().to_owned();
-    // let path2 = &path;
+    let registries = mirror.registries.as_deref().unwrap_or_default();
+    check_registries(registries)?;
+    let registries = registries
+        .iter()
+        .map(|registry| (registry.name.clone(), registry.dir(path)))
+        .collect();
+
+    let private_registry = match &serve.publish {
+        Some(publish) => Some(PrivateRegistry::load(path, publish, serve.auth.is_some())?),
+        None => None,
+    };
+    let auth = match &serve.auth {
+        Some(auth) => {
+            let mut auth = Auth::load(path, auth)?;
+            // cargo sends the same token for every request to a registry, index included.
+            if let Some(private_registry) = &private_registry {
+                auth.accept_token_digests(private_registry.token_digests());
+            }
+            Some(auth)
+        }
+        None => None,
+    };
+
+    let state = Arc::new(ServeState {
+        // own path to use in request processing
+        path: path.to_owned(),
+        git_backend: serve.git_backend.unwrap_or(GitBackend::Native),
+        auth,
+        access_log: AccessLog::new(
+            serve.access_log.unwrap_or(AccessLogFormat::Combined),
+            serve
+                .access_log_path
+                .as_ref()
+                .map(|p| path.join(p))
+                .as_deref(),
+        )?,
+        stats: Stats::load(path),
+        stats_access: serve.stats_access.unwrap_or(StatsAccess::Local),
+        metrics: Metrics::default(),
+        pull_through: if serve.pull_through.unwrap_or(false) {
+            Some(PullThrough::new(mirror))
+        } else {
+            None
+        },
+        api: Api::new(path),
+        private_registry,
+        registries,
+        etags: EtagCache::default(),
+        static_dirs,
+        scheme: if serve.tls_cert.is_some() {
+            "https"
+        } else {
+            "http"
+        },
+        server_url: serve.base_url.as_deref().map(|base_url| {
+            let base_url = base_url.trim_end_matches('/');
+            base_url
+                .strip_suffix("/crates")
+                .unwrap_or(base_url)
+                .to_string()
+        }),
+    });
+    let keep_alive = serve.keep_alive.unwrap_or(true);
+
+    let warm_up_state = state.clone();
+    tokio::spawn(async move { warm_up_state.api.warm_up().await });
+
+    let tls = match (&serve.tls_cert, &serve.tls_key) {
+        (Some(cert), Some(key)) => Some(crate::tls::acceptor(&path.join(cert), &path.join(key))?),
+        (None, None) => None,
+        _ => {
+            return Err(MirrorError::Tls(
+                "tls_cert and tls_key must be set together".to_string(),
+            ))
+        }
+    };
+    if serve.http_redirect_port.is_some() && tls.is_none() {
+        return Err(MirrorError::Tls(
+            "http_redirect_port requires tls_cert and tls_key".to_string(),
+        ));
+    }
+
+    let bind = match &serve.bind {
+        Some(bind) => bind
+            .iter()
+            .map(|addr| BindAddr::parse(addr))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => vec![BindAddr::Tcp(SocketAddr::from(([0u16; 8], serve.port)))],
+    };
+
+    // Save the statistics now and then, so a crash doesn't lose them all.
+    let stats_state = state.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(STATS_SAVE_INTERVAL).await;
+            let state = stats_state.clone();
+            if let Ok(Err(e)) = tokio::task::spawn_blocking(move || state.stats.save()).await {
+                eprintln!("Could not save download statistics: {}", e);
+            }
+        }
+    });
+
+    // Connections watch `shutdown` to know when to finish up, and each one holds
+    // a clone of `done`, so we know they're all finished when it's closed.
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
 
     // web server to handle DL requests
-    let host = format!(":::{}", serve.port);
-    let router = router!(
+    let mut redirect_ips = vec![];
+    for addr in &bind {
+        let listener = Listener::bind(addr).await?;
+        println!(
+            "Listening on {} ({})",
+            addr,
+            if tls.is_some() { "https" } else { "http" }
+        );
+        let state = state.clone();
+        tokio::spawn(accept_loop(
+            listener,
+            tls.clone(),
+            move |remote_addr| {
+                let state = state.clone();
+                service_fn(move |req| handle(req, state.clone(), remote_addr))
+            },
+            keep_alive,
+            shutdown_rx.clone(),
+            done_tx.clone(),
+        ));
+
+        if let BindAddr::Tcp(addr) = addr {
+            if !redirect_ips.iter().any(|(ip, _)| *ip == addr.ip()) {
+                redirect_ips.push((addr.ip(), addr.port()));
+            }
+        }
+    }
+
+    if let Some(redirect_port) = serve.http_redirect_port {
+        for (ip, https_port) in redirect_ips {
+            let addr = BindAddr::Tcp(SocketAddr::new(ip, redirect_port));
+            let listener = Listener::bind(&addr).await?;
+            println!("Redirecting http://{} to HTTPS", addr);
+            tokio::spawn(accept_loop(
+                listener,
+                None,
+                move |_| {
+                    service_fn(move |req| async move {
+                        Ok::<_, Infallible>(redirect_to_https(&req, https_port))
+                    })
+                },
+                keep_alive,
+                shutdown_rx.clone(),
+                done_tx.clone(),
+            ));
+        }
+    }
+
+    shutdown_signal().await;
+    let _ = shutdown_tx.send(());
+    drop(done_tx);
+    let shutdown_timeout = Duration::from_secs(serve.shutdown_timeout.unwrap_or(30));
+    match tokio::time::timeout(shutdown_timeout, done_rx.recv()).await {
+        Ok(_) => println!("All requests finished, shutting down."),
+        Err(_) => println!("Requests still running after the shutdown timeout, shutting down."),
+    }
+
+    if let Err(e) = state.stats.save() {
+        eprintln!("Could not save download statistics: {}", e);
+    }
+
+    Ok(())
+}
+
+/// Send a client that connected over plain HTTP to the same URL over HTTPS.
+fn redirect_to_https(req: &Request<Body>, https_port: u16) -> Response<Body> {
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<Authority>().ok());
+    let host = match host {
+        Some(host) => host,
+        None => return text_response(StatusCode::BAD_REQUEST, "Missing Host header"),
+    };
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+
+    let location = if https_port == 443 {
+        format!("https://{}{}", host.host(), path)
+    } else {
+        format!("https://{}:{}{}", host.host(), https_port, path)
+    };
+    let mut res = text_response(StatusCode::MOVED_PERMANENTLY, "Moved to HTTPS");
+    if let Ok(location) = HeaderValue::from_str(&location) {
+        res.headers_mut().insert(LOCATION, location);
+    }
+    res
+}
+
+/// Resolves once the server is asked to stop, by Ctrl-C or SIGTERM.
+///
+/// The server then stops accepting connections, and waits for in-flight requests to finish.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("Installing SIGTERM handler should not fail");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+
+    println!("Shutting down, waiting for in-flight requests to finish...");
+}
+
+async fn handle(
+    req: Request<Body>,
+    state: Arc<ServeState>,
+    remote_addr: Option<SocketAddr>,
+) -> Result<Response<Body>, Infallible> {
+    let start = Instant::now();
+    let info = RequestInfo::new(&req, remote_addr);
+    let route_name = route_name(req.uri().path());
+
+    // CORS preflight requests never carry credentials, so they're answered before auth.
+    let (mut res, user) = if is_preflight(&req) {
+        (preflight_response(), None)
+    } else {
+        let authorized = match &state.auth {
+            Some(auth) => auth
+                .check(&req, remote_addr)
+                .await
+                .map_err(|f| auth.failure_response(f)),
+            None => Ok(None),
+        };
+        match authorized {
+            Ok(user) => (route(req, &state, remote_addr).await, user),
+            Err(res) => (res, None),
+        }
+    };
+    cors(&mut res);
+
+    state.metrics.record_request(route_name, res.status());
+    let metrics_state = state.clone();
+    let res = measure_body(res, move |bytes| {
+        metrics_state.metrics.record_bytes(route_name, bytes)
+    });
+    state
+        .access_log
+        .log(&info, user.as_deref(), &res, start.elapsed());
+    Ok(res)
+}
+
+async fn route(
+    req: Request<Body>,
+    state: &Arc<ServeState>,
+    remote_addr: Option<SocketAddr>,
+) -> Response<Body> {
+    let method = req.method().clone();
+    let url_path = req.uri().path().to_string();
+    let segments: Vec<&str> = url_path.trim_start_matches('/').split('/').collect();
+
+    match (&method, segments.as_slice()) {
         // old crates.io API?
-        download: get "api/v1/crates/:crate_name/:crate_version/download" => {
-            let path = path.clone();
-            move |request: &mut Request|
-                crates_download(request, &path)
-        },
+        (&Method::GET, ["api", "v1", "crates", crate_name, crate_version, "download"])
         // this one works
-        download2: get "crates/:crate_name/:crate_version/download" => {
-            let path = path.clone();
-            move |request: &mut Request|
-                crates_download(request, &path)
-        },
-        rustup_dist: get "dist/**" => {
-            let path = path.clone();
-            move |request: &mut Request|
-                simple_download(request, &path)
-        },
-        rustup_update: get "rustup/**" => {
-            let path = path.clone();
-            move |request: &mut Request|
-                simple_download(request, &path)
-        },
-        head: get "index/*" => {
-            let path = path.clone();
-            move |request: &mut Request|
-                crate::git::git(request, &path)
-        },
-        index: get "index/**/*" => {
-            let path = path.clone();
-            move |request: &mut Request|
-                crate::git::git(request, &path)
-        },
-        head: post "index/*" => {
-            let path = path.clone();
-            move |request: &mut Request|
-                crate::git::git(request, &path)
-        },
-        index: post "index/**/*" => {
-            let path = path.clone();
-            move |request: &mut Request|
-                crate::git::git(request, &path)
+        | (&Method::GET, ["crates", crate_name, crate_version, "download"]) => {
+            let res = crates_download(state, req.headers(), &state.path, crate_name, crate_version)
+                .await;
+            // Misses are only counted for crates in the index, so requests for made-up names
+            // can't grow the statistics.
+            let request = CargoRequest::new(crate_name, crate_version, &res);
+            if request.is_hit() || is_known_crate(state, crate_name).await {
+                state.stats.record(&request);
+            }
+            res
+        }
+        (&Method::GET, ["index", ..]) | (&Method::POST, ["index", ..]) => {
+            let start = Instant::now();
+            let repo_path = state.path.join("crates.io-index");
+            let res = git(req, &repo_path, "/index", state.git_backend, remote_addr).await;
+            let backend = match state.git_backend {
+                GitBackend::Native => "native",
+                GitBackend::HttpBackend => "http-backend",
+            };
+            let state = state.clone();
+            measure_body(res, move |_| {
+                state.metrics.record_git_latency(backend, start.elapsed())
+            })
+        }
+        (_, ["registries", name, rest @ ..]) => {
+            match state.registries.iter().find(|(n, _)| n == name) {
+                Some((name, dir)) => registry(req, state, name, dir, rest, &method, remote_addr).await,
+                None => text_response(StatusCode::NOT_FOUND, "Not found"),
+            }
+        }
+        (_, ["private", rest @ ..]) => match &state.private_registry {
+            Some(private_registry) => {
+                private(req, state, private_registry, rest, &method, remote_addr).await
+            }
+            None => text_response(StatusCode::NOT_FOUND, "Not found"),
         },
-        root: any "/" => log,
-        query: any "/*" => log,
+        // The read-only crates.io API. cargo uses config.json's "api" URL, which is base_url.
+        (&Method::GET, ["api", "v1", "crates", rest @ ..])
+        | (&Method::GET, ["crates", "api", "v1", "crates", rest @ ..]) => {
+            state.api.handle(rest, req.uri().query()).await
+        }
+        (&Method::GET, [""]) => landing(state.clone(), req.headers(), false).await,
+        (&Method::GET, ["mirror.json"]) => landing(state.clone(), req.headers(), true).await,
+        (&Method::GET, ["stats"]) => stats(&state.stats, state.stats_access, remote_addr),
+        (&Method::GET, ["metrics"]) => metrics(state.clone()).await,
+        (&Method::GET, ["browse", crate_name, "diff", old_version, new_version]) => {
+            browse_diff(state, crate_name, old_version, new_version).await
+        }
+        (&Method::GET, ["browse", crate_name, crate_version, rest @ ..]) => {
+            browse(state, crate_name, crate_version, rest).await
+        }
+        (&Method::GET, ["docs", crate_name, crate_version, rest @ ..]) => {
+            docs(state, req.headers(), crate_name, crate_version, rest, &url_path).await
+        }
+        (&Method::GET, [top_dir, ..]) if state.static_dirs.iter().any(|d| d == top_dir) => {
+            simple_download(state, req.headers(), &url_path).await
+        }
+        _ => text_response(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+/// Serve one of the other mirrored registries: its index, and its crates.
+async fn registry(
+    req: Request<Body>,
+    state: &ServeState,
+    name: &str,
+    registry_dir: &Path,
+    segments: &[&str],
+    method: &Method,
+    remote_addr: Option<SocketAddr>,
+) -> Response<Body> {
+    match (method, segments) {
+        (&Method::GET, ["index", ..]) | (&Method::POST, ["index", ..]) => {
+            let repo_path = registry_dir.join("crates.io-index");
+            let url_prefix = format!("/registries/{}/index", name);
+            git(req, &repo_path, &url_prefix, state.git_backend, remote_addr).await
+        }
+        (&Method::GET, ["crates", crate_name, crate_version, "download"]) => {
+            crates_download(
+                state,
+                req.headers(),
+                registry_dir,
+                crate_name,
+                crate_version,
+            )
+            .await
+        }
+        _ => text_response(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+/// Serve the private registry: its index, crate downloads, and publishing.
+async fn private(
+    req: Request<Body>,
+    state: &ServeState,
+    private_registry: &PrivateRegistry,
+    segments: &[&str],
+    method: &Method,
+    remote_addr: Option<SocketAddr>,
+) -> Response<Body> {
+    match (method, segments) {
+        (&Method::GET, ["index", ..]) | (&Method::POST, ["index", ..]) => {
+            let repo_path = private_registry.repo_path();
+            git(
+                req,
+                repo_path,
+                "/private/index",
+                state.git_backend,
+                remote_addr,
+            )
+            .await
+        }
+        (&Method::GET, ["api", "v1", "crates", crate_name, crate_version, "download"]) => {
+            let crates_dir = private_registry.crates_dir();
+            crates_download(state, req.headers(), &crates_dir, crate_name, crate_version).await
+        }
+        (_, ["api", "v1", "crates", rest @ ..]) => private_registry.handle(req, rest).await,
+        _ => text_response(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+/// Serve an index repository over git, from the URL path `url_prefix`.
+async fn git(
+    req: Request<Body>,
+    repo_path: &Path,
+    url_prefix: &str,
+    git_backend: GitBackend,
+    remote_addr: Option<SocketAddr>,
+) -> Response<Body> {
+    match git_backend {
+        GitBackend::Native => crate::upload_pack::git(req, repo_path).await,
+        GitBackend::HttpBackend => crate::git::git(req, repo_path, url_prefix, remote_addr).await,
+    }
+}
+
+/// Build a response with a plain text body.
+pub fn text_response(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    let mut res = Response::new(body.into());
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
     );
-    let mut chain = Chain::new(router);
-    chain.link_after(CorsMiddleware);
-    println!("Listening on {}", host);
-    // Iron::new(chain).http(host).unwrap();
-    Iron::new(chain).http(&host[..]).unwrap();
+    res
+}
 
-    Ok(())
+quick_error! {
+    #[derive(Debug)]
+    pub enum BodyError {
+        Hyper(err: hyper::Error) {
+            from()
+            display("{}", err)
+        }
+        TooLarge(limit: usize) {
+            display("Request body is larger than {} bytes", limit)
+        }
+    }
 }
 
-pub fn log(req: &mut Request) -> IronResult<Response> {
-    eprintln!("Whoops! {:?}", req);
-    Ok(Response::with((status::Ok, "Ok")))
-}
-
-fn crates_download(req: &mut Request, path: &Path) -> IronResult<Response> {
-    let ref crate_name = req
-        .extensions
-        .get::<Router>()
-        .unwrap()
-        .find("crate_name")
-        .unwrap();
-    let ref crate_version = req
-        .extensions
-        .get::<Router>()
-        .unwrap()
-        .find("crate_version")
-        .unwrap();
-    eprintln!("Downloading: {}:{}", crate_name, crate_version);
-    eprintln!("Raw request: {:?}", req);
-    let crate_path = path
-        .join("crates")
+/// Read a whole request body, giving up once it's larger than `limit` bytes.
+pub async fn read_body_limited(mut body: Body, limit: usize) -> Result<Vec<u8>, BodyError> {
+    let mut data = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > limit {
+            return Err(BodyError::TooLarge(limit));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// Guess a file's content type from its extension.
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") | Some("sha256") | Some("asc") | Some("json") => "text/plain; charset=utf-8",
+        Some("gz") => "application/gzip",
+        Some("xz") => "application/x-xz",
+        Some("exe") => "application/vnd.microsoft.portable-executable",
+        Some("html") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "application/javascript; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Stream a file as a response body, with caching headers.
+///
+/// If the request's conditional headers show the client's copy is still current,
+/// answers with 304 Not Modified instead.
+pub async fn file_response(
+    req_headers: &HeaderMap,
+    file_path: &Path,
+    cache_control: &'static str,
+    etags: &EtagCache,
+) -> io::Result<Response<Body>> {
+    let file = File::open(file_path).await?;
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "Not a file"));
+    }
+    let len = metadata.len();
+    let last_modified = metadata.modified().ok();
+    // Hashing a file the first time it's served can take a while.
+    let etag = tokio::task::block_in_place(|| etags.etag(file_path, &metadata));
+
+    let mut res = if is_not_modified(req_headers, etag.as_deref(), last_modified) {
+        let mut res = Response::new(Body::empty());
+        *res.status_mut() = StatusCode::NOT_MODIFIED;
+        res
+    } else {
+        let content_type = content_type(file_path);
+        match requested_ranges(req_headers, len, etag.as_deref(), last_modified) {
+            Ranges::Full => {
+                let mut res = Response::new(Body::wrap_stream(ReaderStream::new(file)));
+                let headers = res.headers_mut();
+                headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+                res
+            }
+            Ranges::Unsatisfiable => {
+                let mut res = Response::new(Body::empty());
+                *res.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+                res.headers_mut()
+                    .insert(CONTENT_RANGE, header_value(&format!("bytes */{}", len)));
+                res
+            }
+            Ranges::Partial(ranges) if ranges.len() == 1 => {
+                let (first, last) = ranges[0];
+                let mut file = file;
+                file.seek(SeekFrom::Start(first)).await?;
+                let part = file.take(last - first + 1);
+
+                let mut res = Response::new(Body::wrap_stream(ReaderStream::new(part)));
+                *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+                let headers = res.headers_mut();
+                headers.insert(CONTENT_LENGTH, HeaderValue::from(last - first + 1));
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+                headers.insert(
+                    CONTENT_RANGE,
+                    header_value(&format!("bytes {}-{}/{}", first, last, len)),
+                );
+                res
+            }
+            Ranges::Partial(ranges) => multipart_ranges_response(file, ranges, len, content_type),
+        }
+    };
+
+    let headers = res.headers_mut();
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+    if let Some(etag) = etag.and_then(|etag| HeaderValue::from_str(&etag).ok()) {
+        headers.insert(ETAG, etag);
+    }
+    if let Some(last_modified) = last_modified {
+        if let Ok(last_modified) = HeaderValue::from_str(&httpdate::fmt_http_date(last_modified)) {
+            headers.insert(LAST_MODIFIED, last_modified);
+        }
+    }
+    Ok(res)
+}
+
+/// Build a header value from a string that's known to be valid.
+fn header_value(value: &str) -> HeaderValue {
+    HeaderValue::from_str(value).expect("Header value should be valid")
+}
+
+/// Stream several parts of a file, as a multipart/byteranges response.
+fn multipart_ranges_response(
+    mut file: File,
+    ranges: Vec<(u64, u64)>,
+    len: u64,
+    content_type: &'static str,
+) -> Response<Body> {
+    let boundary = format!(
+        "panamax-{:x}",
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0)
+    );
+    let part_headers: Vec<String> = ranges
+        .iter()
+        .map(|(first, last)| {
+            format!(
+                "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                boundary, content_type, first, last, len
+            )
+        })
+        .collect();
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    let content_length = part_headers.iter().map(|h| h.len() as u64).sum::<u64>()
+        + ranges
+            .iter()
+            .map(|(first, last)| last - first + 1)
+            .sum::<u64>()
+        + closing.len() as u64;
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 65536];
+        for (part_header, (first, last)) in part_headers.into_iter().zip(ranges) {
+            if sender.send_data(part_header.into()).await.is_err() {
+                return;
+            }
+            if file.seek(SeekFrom::Start(first)).await.is_err() {
+                sender.abort();
+                return;
+            }
+            let mut part = (&mut file).take(last - first + 1);
+            loop {
+                let n = match part.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(_) => {
+                        sender.abort();
+                        return;
+                    }
+                };
+                if sender
+                    .send_data(Bytes::copy_from_slice(&buf[..n]))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+        let _ = sender.send_data(closing.into()).await;
+    });
+
+    let mut res = Response::new(body);
+    *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+    let headers = res.headers_mut();
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(content_length));
+    headers.insert(
+        CONTENT_TYPE,
+        header_value(&format!("multipart/byteranges; boundary={}", boundary)),
+    );
+    res
+}
+
+/// Serve the landing page, with the mirror's status and client configuration, as HTML or JSON.
+async fn landing(state: Arc<ServeState>, req_headers: &HeaderMap, json: bool) -> Response<Body> {
+    // Point the configuration snippets at the configured base_url. Without one, guess from the
+    // way the client reached the server.
+    let base_url = match &state.server_url {
+        Some(server_url) => server_url.clone(),
+        None => {
+            let host = req_headers
+                .get(HOST)
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("localhost");
+            format!("{}://{}", state.scheme, host)
+        }
+    };
+
+    let status =
+        tokio::task::spawn_blocking(move || MirrorStatus::load(&state.path, &base_url)).await;
+    let status = match status {
+        Ok(status) => status,
+        Err(_) => {
+            return text_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to load mirror status",
+            )
+        }
+    };
+
+    let (body, content_type) = if json {
+        match serde_json::to_vec_pretty(&status) {
+            Ok(body) => (body, "application/json"),
+            Err(_) => {
+                return text_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to serialize mirror status",
+                )
+            }
+        }
+    } else {
+        (status.to_html().into_bytes(), "text/html; charset=utf-8")
+    };
+    let mut res = Response::new(Body::from(body));
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    res
+}
+
+/// Serve the request metrics and sync status, in the Prometheus text format.
+async fn metrics(state: Arc<ServeState>) -> Response<Body> {
+    // Reading the sync journal and the index touches the disk, so keep it off the async threads.
+    let metrics = tokio::task::spawn_blocking(move || state.metrics.render(&state.path)).await;
+    match metrics {
+        Ok(metrics) => {
+            let mut res = Response::new(Body::from(metrics));
+            res.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+            );
+            res
+        }
+        Err(_) => text_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to render metrics",
+        ),
+    }
+}
+
+/// Check whether a crate is in the mirror's crates.io-index.
+async fn is_known_crate(state: &ServeState, crate_name: &str) -> bool {
+    if !is_valid_crate_name(crate_name) {
+        return false;
+    }
+    let path = state.path.clone();
+    let crate_name = crate_name.to_string();
+    tokio::task::spawn_blocking(move || crate_exists(&path, &crate_name))
+        .await
+        .is_ok_and(|exists| exists.unwrap_or(false))
+}
+
+/// Serve the download statistics, by default only to clients on the same machine.
+fn stats(stats: &Stats, access: StatsAccess, remote_addr: Option<SocketAddr>) -> Response<Body> {
+    // Unix domain socket clients are always local. Behind a reverse proxy on the same
+    // machine, every client looks local, so `stats_access` should be "public" or "off" there.
+    let is_local = remote_addr.is_none_or(|addr| match addr.ip() {
+        IpAddr::V4(ip) => ip.is_loopback(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.to_ipv4().is_some_and(|ip| ip.is_loopback()),
+    });
+    let allowed = match access {
+        StatsAccess::Local => is_local,
+        StatsAccess::Public => true,
+        StatsAccess::Off => false,
+    };
+    if !allowed {
+        return text_response(StatusCode::NOT_FOUND, "Not found");
+    }
+
+    match stats.to_json() {
+        Ok(json) => {
+            let mut res = Response::new(Body::from(json));
+            res.headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            res
+        }
+        Err(_) => text_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to serialize statistics",
+        ),
+    }
+}
+
+async fn crates_download(
+    state: &ServeState,
+    req_headers: &HeaderMap,
+    registry_dir: &Path,
+    crate_name: &str,
+    crate_version: &str,
+) -> Response<Body> {
+    let not_found = || {
+        text_response(
+            StatusCode::NOT_FOUND,
+            format!("Could not find crate ({}) in offline mirror.", crate_name),
+        )
+    };
+    let crate_path = match crate_file(state, registry_dir, crate_name, crate_version).await {
+        Some(crate_path) => crate_path,
+        None => return not_found(),
+    };
+    match file_response(req_headers, &crate_path, CACHE_IMMUTABLE, &state.etags).await {
+        Ok(res) => res,
+        Err(_) => {
+            eprintln!("Could not find crate in path: {:?}", crate_path);
+            not_found()
+        }
+    }
+}
+
+/// Find a crate file in a registry's `crates` directory.
+///
+/// With pull-through, crates.io crates missing from the mirror are fetched from upstream first.
+async fn crate_file(
+    state: &ServeState,
+    registry_dir: &Path,
+    crate_name: &str,
+    crate_version: &str,
+) -> Option<PathBuf> {
+    if !is_valid_crate_name(crate_name) || !is_valid_crate_version(crate_version) {
+        return None;
+    }
+
+    let crates_dir = registry_dir.join("crates");
+    let crate_path = crates_dir
         .join(crate_name)
         .join(crate_version)
         .join("download");
 
-    if crate_path.exists() {
-        // eprintln!("path {:?} exists!", crate_path);
-        Ok(Response::with((status::Ok, crate_path)))
-    } else {
+    let pull_through = state
+        .pull_through
+        .as_ref()
+        .filter(|_| registry_dir == state.path.as_path());
+    if let Some(pull_through) = pull_through {
+        if !file_exists(&crate_path).await {
+            if let Err(e) = pull_through
+                .fetch_crate(&state.path, crate_name, crate_version, &crate_path)
+                .await
+            {
+                eprintln!(
+                    "Could not fetch crate {} {} from upstream: {:?}",
+                    crate_name, crate_version, e
+                );
+            }
+        }
+    }
+
+    let resolved = resolve_inside(&crates_dir, &crate_path).await;
+    if resolved.is_none() {
         eprintln!("Could not find crate in path: {:?}", crate_path);
-        Ok(Response::with((
-            status::NotFound,
-            format!("Could not find crate ({}) in offline mirror.", crate_name),
-        )))
     }
+    resolved
 }
 
-fn simple_download(req: &mut Request, path: &Path) -> IronResult<Response> {
-    // let directory = req.url.path().first().unwrap();
-    // println!("req dir  => {}", directory);
-    eprintln!("Raw request: {:?}", req);
-    println!("req path => {:?}", req.url.path());
+/// Read the files in a mirrored crate.
+async fn read_crate_files(
+    state: &ServeState,
+    crate_name: &str,
+    crate_version: &str,
+) -> Option<BTreeMap<String, CrateFile>> {
+    let crate_path = crate_file(state, &state.path, crate_name, crate_version).await?;
+    match tokio::task::spawn_blocking(move || read_crate(&crate_path)).await {
+        Ok(Ok(files)) => Some(files),
+        Ok(Err(e)) => {
+            eprintln!(
+                "Could not read crate {} {}: {:?}",
+                crate_name, crate_version, e
+            );
+            None
+        }
+        Err(_) => None,
+    }
+}
 
-    let file_path = path.join(req.url.path().join("/"));
+/// List the files in a crate, or show one of them as plain text.
+async fn browse(
+    state: &ServeState,
+    crate_name: &str,
+    crate_version: &str,
+    rest: &[&str],
+) -> Response<Body> {
+    let files = match read_crate_files(state, crate_name, crate_version).await {
+        Some(files) => files,
+        None => {
+            return text_response(
+                StatusCode::NOT_FOUND,
+                format!("Could not find crate ({}) in offline mirror.", crate_name),
+            )
+        }
+    };
 
-    eprintln!("Downloading: {:?}", file_path);
+    if rest.iter().all(|segment| segment.is_empty()) {
+        let mut res = Response::new(Body::from(listing_html(crate_name, crate_version, &files)));
+        res.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        return res;
+    }
 
-    if file_path.exists() {
-        // eprintln!("path {:?} exists!", crate_path);
-        Ok(Response::with((status::Ok, file_path)))
-    } else {
-        eprintln!("Could not find file in path: {:?}", file_path);
-        Ok(Response::with((
-            status::NotFound,
+    // Paths inside the tarball never touch the file system, so hidden files are fine here.
+    let decoded: Option<Vec<String>> = rest
+        .iter()
+        .map(|segment| {
+            percent_decode(segment.as_bytes())
+                .decode_utf8()
+                .ok()
+                .map(|s| s.into_owned())
+        })
+        .collect();
+    match decoded.and_then(|segments| files.get(&segments.join("/"))) {
+        Some(file) => text_response(StatusCode::OK, file_text(file)),
+        None => text_response(StatusCode::NOT_FOUND, "No such file in this crate"),
+    }
+}
+
+/// Show the changes between two versions of a crate, as a unified diff.
+async fn browse_diff(
+    state: &ServeState,
+    crate_name: &str,
+    old_version: &str,
+    new_version: &str,
+) -> Response<Body> {
+    let old_files = read_crate_files(state, crate_name, old_version).await;
+    let new_files = read_crate_files(state, crate_name, new_version).await;
+    match (old_files, new_files) {
+        (Some(old_files), Some(new_files)) => {
+            let old_label = format!("{} {}", crate_name, old_version);
+            let new_label = format!("{} {}", crate_name, new_version);
+            let diff = tokio::task::block_in_place(|| {
+                diff_text(&old_label, &old_files, &new_label, &new_files)
+            });
+            text_response(StatusCode::OK, diff)
+        }
+        _ => text_response(
+            StatusCode::NOT_FOUND,
+            format!(
+                "Could not find both versions of crate ({}) in offline mirror.",
+                crate_name
+            ),
+        ),
+    }
+}
+
+/// Serve mirrored documentation, from `docs/<crate>/<version>/`.
+///
+/// `/docs/<crate>/<version>/` redirects to the crate's main page, and `latest` to the newest
+/// mirrored version.
+async fn docs(
+    state: &ServeState,
+    req_headers: &HeaderMap,
+    crate_name: &str,
+    crate_version: &str,
+    rest: &[&str],
+    url_path: &str,
+) -> Response<Body> {
+    if !is_valid_crate_name(crate_name) || !is_valid_crate_version(crate_version) {
+        return text_response(StatusCode::NOT_FOUND, "Not found");
+    }
+    let path = state.path.clone();
+    let name = crate_name.to_string();
+    let vers = crate_version.to_string();
+    // The rest of the path, still percent-encoded, for redirects from `latest`.
+    let rest_path = url_path.splitn(5, '/').nth(4).unwrap_or("").to_string();
+    let redirect = match rest {
+        [] | [""] => tokio::task::spawn_blocking(move || {
+            let vers = if vers == "latest" {
+                crate::docs::latest_docs_version(&path, &name)?
+            } else {
+                vers
+            };
+            let page = crate::docs::docs_index_page(&path, &name, &vers)?;
+            Some(format!(
+                "/docs/{}/{}/{}",
+                name,
+                vers,
+                page.to_string_lossy()
+            ))
+        })
+        .await
+        .ok()
+        .flatten(),
+        _ if crate_version == "latest" => tokio::task::spawn_blocking(move || {
+            let vers = crate::docs::latest_docs_version(&path, &name)?;
+            Some(format!("/docs/{}/{}/{}", name, vers, rest_path))
+        })
+        .await
+        .ok()
+        .flatten(),
+        _ => return simple_download(state, req_headers, url_path).await,
+    };
+
+    match redirect {
+        Some(location) => {
+            let mut res = Response::new(Body::empty());
+            *res.status_mut() = StatusCode::FOUND;
+            res.headers_mut().insert(LOCATION, header_value(&location));
+            res
+        }
+        None => text_response(
+            StatusCode::NOT_FOUND,
             format!(
-                "Could not find file ({}) in offline mirror.",
-                req.url.path().join("/")
+                "No documentation for {} {} in offline mirror.",
+                crate_name, crate_version
             ),
-        )))
-    }
-    //  else {
-    //     debug!("path {:?} doesn't exist!", path);
-
-    //     match fetch(
-    //         &path,
-    //         &config.upstream,
-    //         &config.index_path,
-    //         &crate_name,
-    //         &crate_version,
-    //     ) {
-    //         Ok(_) => {
-    //             let _ = stats.send(CargoRequest {
-    //                 name: crate_name.to_string(),
-    //                 version: crate_version.to_string(),
-    //                 hit: false,
-    //                 size: size(&path) as i64,
-    //             });
-    //             Ok(Response::with((status::Ok, path)))
-    //         }
-    //         Err(e) => {
-    //             error!("{:?}", e);
-    //             return Ok(Response::with((
-    //                 status::ServiceUnavailable,
-    //                 "Couldn't fetch from Crates.io",
-    //             )));
-    //         }
-    //     }
-    // }
-
-    // Ok(Response::with((status::Ok, "Ok")))
+        ),
+    }
+}
+
+/// Serve a file from one of the mirror's static directories, like `dist/` and `rustup/`.
+async fn simple_download(
+    state: &ServeState,
+    req_headers: &HeaderMap,
+    url_path: &str,
+) -> Response<Body> {
+    let not_found = || {
+        text_response(
+            StatusCode::NOT_FOUND,
+            format!("Could not find file ({}) in offline mirror.", url_path),
+        )
+    };
+    // Decoding and checking every segment keeps requests like `/dist/../mirror.toml` out.
+    let segments = match url_path_segments(url_path) {
+        Some(segments) => segments,
+        None => return not_found(),
+    };
+    let top_dir = state.path.join(&segments[0]);
+    let file_path = segments[1..]
+        .iter()
+        .fold(top_dir.clone(), |path, segment| path.join(segment));
+
+    if let Some(pull_through) = &state.pull_through {
+        let is_rustup_file = segments[0] == "dist" || segments[0] == "rustup";
+        if is_rustup_file && pull_through.needs_rustup_fetch(url_path, &file_path).await {
+            if let Err(e) = pull_through.fetch_rustup_file(url_path, &file_path).await {
+                eprintln!("Could not fetch {} from upstream: {:?}", url_path, e);
+            }
+        }
+    }
+
+    let file_path = match resolve_inside(&top_dir, &file_path).await {
+        Some(file_path) => file_path,
+        None => {
+            eprintln!("Could not find file in path: {:?}", file_path);
+            return not_found();
+        }
+    };
+    match file_response(
+        req_headers,
+        &file_path,
+        cache_control(url_path),
+        &state.etags,
+    )
+    .await
+    {
+        Ok(res) => res,
+        Err(_) => {
+            eprintln!("Could not find file in path: {:?}", file_path);
+            not_found()
+        }
+    }
 }
@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use hyper::header::CONTENT_LENGTH;
+use hyper::{Body, Response, StatusCode};
+use serde_derive::{Deserialize, Serialize};
+
+/// A crate download request, as recorded in the stats.
+#[derive(Clone, Debug)]
+pub struct CargoRequest {
+    /// crate name, ex: cargo-cacher
+    name: String,
+    /// major.minor.patch
+    version: String,
+    /// Cache hit?
+    hit: bool,
+    /// Filesize in bytes
+    size: i64,
+}
+
+impl CargoRequest {
+    /// Describe a crate download from the response it got.
+    pub fn new(name: &str, version: &str, res: &Response<Body>) -> CargoRequest {
+        let size = res
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| l.parse().ok())
+            .unwrap_or(0);
+        CargoRequest {
+            name: name.to_string(),
+            version: version.to_string(),
+            // A revalidated copy in the client's cache still counts as a use of the crate.
+            hit: res.status().is_success() || res.status() == StatusCode::NOT_MODIFIED,
+            size,
+        }
+    }
+
+    /// Whether the crate file was served.
+    pub fn is_hit(&self) -> bool {
+        self.hit
+    }
+}
+
+/// Download statistics for one crate.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CrateStats {
+    /// Number of successful downloads.
+    pub downloads: u64,
+    /// Number of downloads of versions that aren't in the mirror.
+    pub misses: u64,
+    /// Total bytes served.
+    pub bytes: u64,
+    /// Number of successful downloads, per version.
+    pub versions: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct StatsFile {
+    crates: BTreeMap<String, CrateStats>,
+}
+
+/// Collects per-crate download statistics while serving.
+///
+/// The statistics are kept in `mirror-stats.json`, so they survive restarts.
+pub struct Stats {
+    path: PathBuf,
+    stats: Mutex<StatsFile>,
+    /// Whether anything was recorded since the last save.
+    changed: AtomicBool,
+}
+
+impl Stats {
+    /// Load the statistics of previous runs from the mirror directory, if there are any.
+    pub fn load(mirror_path: &Path) -> Stats {
+        let path = mirror_path.join("mirror-stats.json");
+        let stats = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                eprintln!("Could not parse `{}`, starting over: {}", path.display(), e);
+                StatsFile::default()
+            }),
+            Err(_) => StatsFile::default(),
+        };
+        Stats {
+            path,
+            stats: Mutex::new(stats),
+            changed: AtomicBool::new(false),
+        }
+    }
+
+    /// Record a download. The caller checks the crate name is one worth keeping stats for.
+    pub fn record(&self, req: &CargoRequest) {
+        self.changed.store(true, Ordering::Relaxed);
+        let mut stats = self.stats.lock().unwrap();
+        let crate_stats = stats.crates.entry(req.name.clone()).or_default();
+        if req.hit {
+            crate_stats.downloads += 1;
+            crate_stats.bytes += req.size.max(0) as u64;
+            *crate_stats.versions.entry(req.version.clone()).or_default() += 1;
+        } else {
+            crate_stats.misses += 1;
+        }
+    }
+
+    /// The statistics as JSON, most downloaded crates first.
+    pub fn to_json(&self) -> Result<Vec<u8>, serde_json::Error> {
+        let stats = self.stats.lock().unwrap();
+        let mut crates: Vec<_> = stats.crates.iter().collect();
+        crates.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.downloads));
+        let crates: Vec<_> = crates
+            .into_iter()
+            .map(|(name, stats)| serde_json::json!({ "name": name, "stats": stats }))
+            .collect();
+        serde_json::to_vec_pretty(&serde_json::json!({ "crates": crates }))
+    }
+
+    /// Write the statistics to `mirror-stats.json`, if anything was recorded since the last save.
+    pub fn save(&self) -> io::Result<()> {
+        if !self.changed.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let content = serde_json::to_vec_pretty(&*self.stats.lock().unwrap())?;
+        let tmp_path = self.path.with_extension("json.part");
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn download(name: &str, version: &str, status: u16, size: usize) -> CargoRequest {
+        let res = Response::builder()
+            .status(status)
+            .header(CONTENT_LENGTH, size)
+            .body(Body::empty())
+            .unwrap();
+        CargoRequest::new(name, version, &res)
+    }
+
+    #[test]
+    fn aggregates_downloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let stats = Stats::load(dir.path());
+        stats.record(&download("serde", "1.0.0", 200, 100));
+        stats.record(&download("serde", "1.0.0", 304, 0));
+        stats.record(&download("serde", "1.0.1", 200, 50));
+        stats.record(&download("serde", "2.0.0", 404, 9));
+        stats.record(&download("rand", "0.8.0", 200, 10));
+
+        let json: serde_json::Value = serde_json::from_slice(&stats.to_json().unwrap()).unwrap();
+        let crates = json["crates"].as_array().unwrap();
+        // Most downloaded first.
+        assert_eq!(crates[0]["name"], "serde");
+        assert_eq!(crates[1]["name"], "rand");
+
+        let serde = &crates[0]["stats"];
+        assert_eq!(serde["downloads"], 3);
+        assert_eq!(serde["misses"], 1);
+        assert_eq!(serde["bytes"], 150);
+        assert_eq!(serde["versions"]["1.0.0"], 2);
+        assert_eq!(serde["versions"]["1.0.1"], 1);
+        assert!(serde["versions"].get("2.0.0").is_none());
+    }
+
+    #[test]
+    fn saves_and_reloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mirror-stats.json");
+
+        let stats = Stats::load(dir.path());
+        // Nothing recorded, so nothing is written.
+        stats.save().unwrap();
+        assert!(!path.exists());
+
+        stats.record(&download("serde", "1.0.0", 200, 100));
+        stats.save().unwrap();
+        assert!(path.exists());
+
+        let reloaded = Stats::load(dir.path());
+        reloaded.record(&download("serde", "1.0.0", 200, 100));
+        let json: serde_json::Value = serde_json::from_slice(&reloaded.to_json().unwrap()).unwrap();
+        assert_eq!(json["crates"][0]["stats"]["downloads"], 2);
+        assert_eq!(json["crates"][0]["stats"]["bytes"], 200);
+    }
+
+    #[test]
+    fn starts_over_on_unreadable_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("mirror-stats.json"), "not json").unwrap();
+        let stats = Stats::load(dir.path());
+        let json: serde_json::Value = serde_json::from_slice(&stats.to_json().unwrap()).unwrap();
+        assert_eq!(json["crates"].as_array().unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,64 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls_pemfile::Item;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::mirror::MirrorError;
+
+/// Build a TLS acceptor from a PEM certificate chain and a PEM private key.
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, MirrorError> {
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| MirrorError::Tls(format!("Invalid certificate or key: {}", e)))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn open_pem(path: &Path) -> Result<BufReader<File>, MirrorError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| MirrorError::Tls(format!("Could not open `{}`: {}", path.display(), e)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, MirrorError> {
+    let certs = rustls_pemfile::certs(&mut open_pem(path)?)
+        .map_err(|e| MirrorError::Tls(format!("Could not read `{}`: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(MirrorError::Tls(format!(
+            "No certificates found in `{}`",
+            path.display()
+        )));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Load the first private key in the file, in any of the formats rustls understands.
+fn load_key(path: &Path) -> Result<PrivateKey, MirrorError> {
+    let mut reader = open_pem(path)?;
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| MirrorError::Tls(format!("Could not read `{}`: {}", path.display(), e)))?;
+        match item {
+            Some(Item::RSAKey(key)) | Some(Item::PKCS8Key(key)) | Some(Item::ECKey(key)) => {
+                return Ok(PrivateKey(key))
+            }
+            Some(_) => {}
+            None => {
+                return Err(MirrorError::Tls(format!(
+                    "No private key found in `{}`",
+                    path.display()
+                )))
+            }
+        }
+    }
+}
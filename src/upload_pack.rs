@@ -0,0 +1,636 @@
+// In-process implementation of the server side of git's smart HTTP protocol.
+// Only fetching (upload-pack) is supported, which is all cargo needs from the index.
+// See https://git-scm.com/docs/http-protocol and https://git-scm.com/docs/pack-protocol
+
+use std::collections::HashSet;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use git2::{ObjectType, Oid, Repository};
+use hyper::body::{Bytes, Sender};
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::runtime::Handle;
+use tokio::task;
+
+use crate::serve::{read_body_limited, text_response, BodyError};
+
+/// Maximum data in one side-band-64k packet (65520 minus the length and band bytes).
+static SIDE_BAND_64K_MAX: usize = 65515;
+
+/// Maximum data in one side-band packet (1000 minus the length and band bytes).
+static SIDE_BAND_MAX: usize = 995;
+
+/// Largest upload-pack request accepted, before and after gzip decoding.
+/// Each have line is 50 bytes, so this allows for a long negotiation.
+static MAX_REQUEST_SIZE: usize = 10 * 1024 * 1024;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum UploadPackError {
+        Io(err: io::Error) {
+            from()
+        }
+        Git(err: git2::Error) {
+            from()
+        }
+        Body(err: BodyError) {
+            from()
+        }
+        TooLarge {}
+        Protocol(msg: String) {}
+    }
+}
+
+impl From<UploadPackError> for io::Error {
+    fn from(e: UploadPackError) -> io::Error {
+        match e {
+            UploadPackError::Io(e) => e,
+            e => io::Error::other(format!("{:?}", e)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SideBand {
+    None,
+    /// `side-band`, with packets of up to 1000 bytes.
+    Small,
+    /// `side-band-64k`, with packets of up to 65520 bytes.
+    Large,
+}
+
+/// A parsed upload-pack request from a client.
+#[derive(Debug)]
+pub struct UploadPackRequest {
+    wants: Vec<Oid>,
+    haves: Vec<Oid>,
+    done: bool,
+    side_band: SideBand,
+}
+
+/// Write one pkt-line.
+fn write_pkt_line(out: &mut dyn Write, data: &[u8]) -> io::Result<()> {
+    write!(out, "{:04x}", data.len() + 4)?;
+    out.write_all(data)
+}
+
+/// Write a flush-pkt.
+fn write_flush(out: &mut dyn Write) -> io::Result<()> {
+    out.write_all(b"0000")
+}
+
+/// Read all pkt-lines in a request body. Flush-pkts are returned as `None`.
+fn read_pkt_lines(body: &[u8]) -> Result<Vec<Option<&[u8]>>, UploadPackError> {
+    let mut lines = vec![];
+    let mut rest = body;
+
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(UploadPackError::Protocol("truncated pkt-line".to_string()));
+        }
+        let len = std::str::from_utf8(&rest[..4])
+            .ok()
+            .and_then(|l| usize::from_str_radix(l, 16).ok())
+            .ok_or_else(|| UploadPackError::Protocol("invalid pkt-line length".to_string()))?;
+
+        if len < 4 {
+            // flush-pkt (0000), or a protocol v2 delimiter, which we treat the same
+            lines.push(None);
+            rest = &rest[4..];
+        } else if len > rest.len() {
+            return Err(UploadPackError::Protocol("truncated pkt-line".to_string()));
+        } else {
+            lines.push(Some(&rest[4..len]));
+            rest = &rest[len..];
+        }
+    }
+
+    Ok(lines)
+}
+
+fn parse_oid(s: Option<&str>) -> Result<Oid, UploadPackError> {
+    s.and_then(|s| Oid::from_str(s).ok())
+        .ok_or_else(|| UploadPackError::Protocol("invalid object id".to_string()))
+}
+
+/// Parse the body of a POST to git-upload-pack.
+pub fn parse_request(body: &[u8]) -> Result<UploadPackRequest, UploadPackError> {
+    let mut request = UploadPackRequest {
+        wants: vec![],
+        haves: vec![],
+        done: false,
+        side_band: SideBand::None,
+    };
+
+    for line in read_pkt_lines(body)?.into_iter().flatten() {
+        let line = String::from_utf8_lossy(line);
+        let mut words = line.trim_end().split(' ');
+        match words.next() {
+            Some("want") => {
+                request.wants.push(parse_oid(words.next())?);
+                // Capabilities are only sent on the first want line.
+                for capability in words {
+                    match capability {
+                        "side-band-64k" => request.side_band = SideBand::Large,
+                        "side-band" if request.side_band == SideBand::None => {
+                            request.side_band = SideBand::Small
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Some("have") => request.haves.push(parse_oid(words.next())?),
+            Some("done") => request.done = true,
+            _ => {
+                return Err(UploadPackError::Protocol(format!(
+                    "unsupported request line: {}",
+                    line.trim_end()
+                )))
+            }
+        }
+    }
+
+    if request.wants.is_empty() {
+        return Err(UploadPackError::Protocol("no objects wanted".to_string()));
+    }
+
+    Ok(request)
+}
+
+/// The refs to advertise: HEAD first, then all branches and tags.
+fn advertised_refs(repo: &Repository) -> Result<Vec<(Oid, String)>, UploadPackError> {
+    let mut refs = vec![];
+    if let Ok(oid) = repo.refname_to_id("HEAD") {
+        refs.push((oid, "HEAD".to_string()));
+    }
+    for glob in &["refs/heads/*", "refs/tags/*"] {
+        for reference in repo.references_glob(glob)? {
+            let reference = reference?;
+            if let (Some(name), Ok(resolved)) = (reference.name(), reference.resolve()) {
+                if let Some(oid) = resolved.target() {
+                    refs.push((oid, name.to_string()));
+                }
+            }
+        }
+    }
+    Ok(refs)
+}
+
+/// Write the ref advertisement for `info/refs?service=git-upload-pack`.
+pub fn advertise_refs(repo_path: &Path, out: &mut dyn Write) -> Result<(), UploadPackError> {
+    let repo = Repository::open(repo_path)?;
+
+    write_pkt_line(out, b"# service=git-upload-pack\n")?;
+    write_flush(out)?;
+
+    let mut capabilities = format!(
+        "side-band side-band-64k ofs-delta no-progress agent=panamax/{}",
+        env!("CARGO_PKG_VERSION")
+    );
+    if let Ok(head) = repo.find_reference("HEAD") {
+        if let Some(target) = head.symbolic_target() {
+            capabilities = format!("{} symref=HEAD:{}", capabilities, target);
+        }
+    }
+
+    let mut refs = advertised_refs(&repo)?;
+
+    if refs.is_empty() {
+        // An empty repository still needs to send its capabilities.
+        refs.push((Oid::zero(), "capabilities^{}".to_string()));
+    }
+
+    for (i, (oid, name)) in refs.iter().enumerate() {
+        let line = if i == 0 {
+            format!("{} {}\0{}\n", oid, name, capabilities)
+        } else {
+            format!("{} {}\n", oid, name)
+        };
+        write_pkt_line(out, line.as_bytes())?;
+    }
+    write_flush(out)?;
+
+    Ok(())
+}
+
+/// Respond to an upload-pack request, writing the packfile if negotiation is done.
+///
+/// The packfile is streamed out as libgit2 builds it, rather than held in memory.
+pub fn upload_pack(
+    repo_path: &Path,
+    request: &UploadPackRequest,
+    out: &mut dyn Write,
+) -> Result<(), UploadPackError> {
+    let repo = Repository::open(repo_path)?;
+    let odb = repo.odb()?;
+
+    // Like git without uploadpack.allowAnySHA1InWant, only advertised refs can be wanted.
+    let advertised: HashSet<Oid> = advertised_refs(&repo)?
+        .into_iter()
+        .map(|(oid, _)| oid)
+        .collect();
+    if let Some(want) = request.wants.iter().find(|oid| !advertised.contains(oid)) {
+        let msg = format!("ERR upload-pack: not our ref {}\n", want);
+        write_pkt_line(out, msg.as_bytes())?;
+        return Err(UploadPackError::Protocol(format!("not our ref {}", want)));
+    }
+
+    // Without multi_ack, a single ACK for the first common commit is all the client expects.
+    // Haves that we don't have, or that aren't commits, are skipped.
+    let common: Vec<Oid> = request
+        .haves
+        .iter()
+        .cloned()
+        .filter(|oid| {
+            odb.read_header(*oid)
+                .is_ok_and(|(_, kind)| kind == ObjectType::Commit)
+        })
+        .collect();
+    match common.first() {
+        Some(oid) => write_pkt_line(out, format!("ACK {}\n", oid).as_bytes())?,
+        None => write_pkt_line(out, b"NAK\n")?,
+    }
+
+    if !request.done {
+        // The client will send another round of haves, or "done".
+        return Ok(());
+    }
+
+    let mut pack_started = false;
+    let res = write_pack(&repo, request, &common, out, &mut pack_started);
+    if let Err(ref e) = res {
+        // Let the client know why the pack was cut short, if we still can. Without side-band,
+        // an ERR line in the middle of the pack would only corrupt it.
+        let msg = format!("ERR {:?}\n", e);
+        match request.side_band {
+            SideBand::None if pack_started => {}
+            SideBand::None => write_pkt_line(out, msg.as_bytes())?,
+            _ => write_pkt_line(out, &[&[3u8][..], msg.as_bytes()].concat())?,
+        }
+    }
+    res
+}
+
+fn write_pack(
+    repo: &Repository,
+    request: &UploadPackRequest,
+    common: &[Oid],
+    out: &mut dyn Write,
+    pack_started: &mut bool,
+) -> Result<(), UploadPackError> {
+    let mut walk = repo.revwalk()?;
+    for want in &request.wants {
+        walk.push(*want)?;
+    }
+    for have in common {
+        walk.hide(*have)?;
+    }
+
+    let mut pack_builder = repo.packbuilder()?;
+    pack_builder.insert_walk(&mut walk)?;
+
+    let max_len = match request.side_band {
+        SideBand::None => usize::MAX,
+        SideBand::Small => SIDE_BAND_MAX,
+        SideBand::Large => SIDE_BAND_64K_MAX,
+    };
+
+    let mut write_error = None;
+    let res = pack_builder.foreach(|data| {
+        *pack_started = true;
+        let res = if request.side_band == SideBand::None {
+            out.write_all(data)
+        } else {
+            data.chunks(max_len).try_for_each(|chunk| {
+                write!(out, "{:04x}", chunk.len() + 5)?;
+                out.write_all(&[1])?;
+                out.write_all(chunk)
+            })
+        };
+        match res {
+            Ok(()) => true,
+            Err(e) => {
+                write_error = Some(e);
+                false
+            }
+        }
+    });
+
+    if let Some(e) = write_error {
+        return Err(e.into());
+    }
+    res?;
+
+    if request.side_band != SideBand::None {
+        write_flush(out)?;
+    }
+
+    Ok(())
+}
+
+/// Adapts a response body channel to `io::Write`, so a packfile can be streamed
+/// from a blocking thread as libgit2 builds it.
+struct ChannelWriter {
+    sender: Sender,
+    handle: Handle,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.handle
+            .block_on(self.sender.send_data(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client disconnected"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn git_response(content_type: &'static str, body: Body) -> Response<Body> {
+    let mut res = Response::new(body);
+    let headers = res.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    res
+}
+
+/// Read a request body, decompressing it if the client gzipped it.
+///
+/// Both the body and its decompressed contents are limited to `MAX_REQUEST_SIZE`.
+async fn read_body(req: Request<Body>) -> Result<Vec<u8>, UploadPackError> {
+    let gzipped = req
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.eq_ignore_ascii_case("gzip"))
+        .unwrap_or(false);
+
+    let data = match read_body_limited(req.into_body(), MAX_REQUEST_SIZE).await {
+        Ok(data) => data,
+        Err(BodyError::TooLarge(_)) => return Err(UploadPackError::TooLarge),
+        Err(e) => return Err(e.into()),
+    };
+
+    if gzipped {
+        gunzip_limited(&data, MAX_REQUEST_SIZE)
+    } else {
+        Ok(data)
+    }
+}
+
+/// Decompress gzipped data, failing if it decompresses to more than `limit` bytes.
+fn gunzip_limited(data: &[u8], limit: usize) -> Result<Vec<u8>, UploadPackError> {
+    let mut body = vec![];
+    GzDecoder::new(data)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut body)?;
+    if body.len() > limit {
+        return Err(UploadPackError::TooLarge);
+    }
+    Ok(body)
+}
+
+/// Serve an index repository over git's smart HTTP protocol, without the git command line.
+pub async fn git(req: Request<Body>, repo_path: &Path) -> Response<Body> {
+    let repo_path = repo_path.to_path_buf();
+    let url_path = req.uri().path().to_string();
+
+    if req.method() == Method::GET && url_path.ends_with("info/refs") {
+        if req.uri().query() != Some("service=git-upload-pack") {
+            return text_response(
+                StatusCode::FORBIDDEN,
+                "Only git-upload-pack over the smart HTTP protocol is supported.",
+            );
+        }
+
+        let advertisement = task::spawn_blocking(move || {
+            let mut advertisement = vec![];
+            advertise_refs(&repo_path, &mut advertisement).map(|_| advertisement)
+        })
+        .await
+        .map_err(|e| UploadPackError::Io(e.into()))
+        .and_then(|res| res);
+
+        match advertisement {
+            Ok(advertisement) => git_response(
+                "application/x-git-upload-pack-advertisement",
+                Body::from(advertisement),
+            ),
+            Err(e) => {
+                eprintln!("Advertising refs failed: {:?}", e);
+                text_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to read crates.io-index",
+                )
+            }
+        }
+    } else if req.method() == Method::POST && url_path.ends_with("git-upload-pack") {
+        let request = match read_body(req).await.and_then(|body| parse_request(&body)) {
+            Ok(r) => r,
+            Err(UploadPackError::TooLarge) => {
+                return text_response(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "The upload-pack request is too large.",
+                );
+            }
+            Err(e) => {
+                eprintln!("Invalid upload-pack request: {:?}", e);
+                return text_response(StatusCode::BAD_REQUEST, format!("{:?}", e));
+            }
+        };
+
+        let (sender, body) = Body::channel();
+        let handle = Handle::current();
+        task::spawn_blocking(move || {
+            let mut out = BufWriter::with_capacity(65536, ChannelWriter { sender, handle });
+            let res = upload_pack(&repo_path, &request, &mut out)
+                .and_then(|()| out.flush().map_err(UploadPackError::from));
+            if let Err(e) = res {
+                eprintln!("Sending pack failed: {:?}", e);
+            }
+        });
+
+        git_response("application/x-git-upload-pack-result", body)
+    } else {
+        text_response(
+            StatusCode::NOT_FOUND,
+            "Only git-upload-pack over the smart HTTP protocol is supported.",
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkt(data: &str) -> String {
+        format!("{:04x}{}", data.len() + 4, data)
+    }
+
+    /// A repository with two commits on master, returning the first and second commits.
+    fn test_repo(dir: &Path) -> (Repository, Oid, Oid) {
+        let repo = Repository::init(dir).unwrap();
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+        let mut parents = vec![];
+        for content in &["one", "two"] {
+            let blob = repo.blob(content.as_bytes()).unwrap();
+            let mut builder = repo.treebuilder(None).unwrap();
+            builder.insert("file", blob, 0o100644).unwrap();
+            let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+            let parent_commits: Vec<_> = parents
+                .iter()
+                .map(|oid| repo.find_commit(*oid).unwrap())
+                .collect();
+            let parent_refs: Vec<_> = parent_commits.iter().collect();
+            let oid = repo
+                .commit(
+                    Some("HEAD"),
+                    &signature,
+                    &signature,
+                    content,
+                    &tree,
+                    &parent_refs,
+                )
+                .unwrap();
+            parents = vec![oid];
+        }
+        let head = repo.head().unwrap().target().unwrap();
+        let first = repo.find_commit(head).unwrap().parent_id(0).unwrap();
+        (repo, first, head)
+    }
+
+    fn request(wants: Vec<Oid>, haves: Vec<Oid>, done: bool) -> UploadPackRequest {
+        UploadPackRequest {
+            wants,
+            haves,
+            done,
+            side_band: SideBand::None,
+        }
+    }
+
+    #[test]
+    fn reads_pkt_lines_and_flushes() {
+        let body = format!("{}0000{}", pkt("want abc\n"), pkt("done\n"));
+        let lines = read_pkt_lines(body.as_bytes()).unwrap();
+        assert_eq!(
+            lines,
+            vec![Some(&b"want abc\n"[..]), None, Some(&b"done\n"[..])]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_pkt_lines() {
+        // Too short for a length.
+        assert!(read_pkt_lines(b"00").is_err());
+        // Not hex.
+        assert!(read_pkt_lines(b"zzzzdata").is_err());
+        // Longer than the body.
+        assert!(read_pkt_lines(b"0010want").is_err());
+        assert!(read_pkt_lines(b"ffffwant").is_err());
+    }
+
+    #[test]
+    fn parses_request_with_capabilities() {
+        let want = "0123456789abcdef0123456789abcdef01234567";
+        let have = "89abcdef0123456789abcdef0123456789abcdef";
+        let body = format!(
+            "{}0000{}{}",
+            pkt(&format!(
+                "want {} side-band side-band-64k ofs-delta\n",
+                want
+            )),
+            pkt(&format!("have {}\n", have)),
+            pkt("done\n")
+        );
+        let request = parse_request(body.as_bytes()).unwrap();
+        assert_eq!(request.wants, vec![Oid::from_str(want).unwrap()]);
+        assert_eq!(request.haves, vec![Oid::from_str(have).unwrap()]);
+        assert!(request.done);
+        assert_eq!(request.side_band, SideBand::Large);
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        // No wants at all.
+        assert!(parse_request(b"0000").is_err());
+        // Invalid object ids.
+        assert!(parse_request(pkt("want nothex\n").as_bytes()).is_err());
+        assert!(parse_request(pkt("want\n").as_bytes()).is_err());
+        // Unknown commands.
+        let body = format!("{}{}", pkt("deepen 1\n"), pkt("done\n"));
+        assert!(parse_request(body.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn limits_gzipped_requests() {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::best());
+        encoder.write_all(&vec![b'0'; 100_000]).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        assert_eq!(gunzip_limited(&gzipped, 100_000).unwrap().len(), 100_000);
+        assert!(matches!(
+            gunzip_limited(&gzipped, 1000),
+            Err(UploadPackError::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn naks_unknown_haves_and_waits_for_done() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_repo, _, head) = test_repo(dir.path());
+        let unknown = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+
+        let mut out = vec![];
+        upload_pack(
+            dir.path(),
+            &request(vec![head], vec![unknown], false),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(out, pkt("NAK\n").as_bytes());
+    }
+
+    #[test]
+    fn acks_common_commits_and_skips_other_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repo, first, head) = test_repo(dir.path());
+        let tree = repo.find_commit(first).unwrap().tree_id();
+
+        let mut out = vec![];
+        let haves = vec![tree, first];
+        upload_pack(dir.path(), &request(vec![head], haves, true), &mut out).unwrap();
+        let ack = pkt(&format!("ACK {}\n", first));
+        assert!(out.starts_with(ack.as_bytes()));
+        assert_eq!(&out[ack.len()..ack.len() + 4], b"PACK");
+    }
+
+    #[test]
+    fn only_sends_a_pack_for_a_tree_have() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repo, first, head) = test_repo(dir.path());
+        let tree = repo.find_commit(first).unwrap().tree_id();
+
+        // A tree isn't a commit, so it's not common, and the whole history is sent.
+        let mut out = vec![];
+        upload_pack(dir.path(), &request(vec![head], vec![tree], true), &mut out).unwrap();
+        let nak = pkt("NAK\n");
+        assert!(out.starts_with(nak.as_bytes()));
+        assert_eq!(&out[nak.len()..nak.len() + 4], b"PACK");
+    }
+
+    #[test]
+    fn refuses_wants_that_were_not_advertised() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_repo, first, _) = test_repo(dir.path());
+
+        let mut out = vec![];
+        let res = upload_pack(dir.path(), &request(vec![first], vec![], true), &mut out);
+        assert!(res.is_err());
+        assert!(String::from_utf8_lossy(&out).contains("ERR upload-pack: not our ref"));
+        assert!(!out.windows(4).any(|w| w == b"PACK"));
+    }
+}